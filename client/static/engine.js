@@ -9,6 +9,9 @@ import { MsgHandler } from "./message.js";
 import * as utils from "./utils.js";
 import { Storage } from "./storage.js";
 
+// close code of a server exceeding the staleness bound, the reason is the url of the leader
+const STALE_CLOSE_CODE = 4307;
+
 export class Engine {
   id;
   userId;
@@ -116,12 +119,12 @@ export class Engine {
     this.msgerForm.addEventListener("submit", this.sendToServer.bind(this));
   }
 
-  connectWS(host, port) {
+  connectWS(host, port, path = "") {
     this.currentHost = host;
     this.currentPort = port;
 
     try {
-      this.socket = new WebSocket("ws://" + host + ":" + port);
+      this.socket = new WebSocket("ws://" + host + ":" + port + path);
     } catch (err) {
       console.log(err);
       return -1;
@@ -166,6 +169,14 @@ export class Engine {
       this.connectBtn.style.backgroundColor = "red";
       this.serverInfoDiv.style.color = "red";
       clearInterval(this.interval_handler);
+
+      // the server exceeds the staleness bound : reconnect to the leader in the reason
+      if (error.code == STALE_CLOSE_CODE && error.reason) {
+        let leader = new URL(error.reason);
+        console.log("redirect to " + error.reason);
+        this.connectWS(leader.hostname, leader.port, leader.pathname);
+        return;
+      }
       setTimeout(this.retry_connection.bind(this), 1000);
     };
     return 0;
@@ -269,10 +280,31 @@ export class Engine {
     console.log("send to server:" + JSON.stringify(json));
   }
 
+  // NB : every message rejected with the expired session is reported, so the session is
+  // restarted once, unless a new session is already pending.
+  restartSession(error) {
+    console.log("session expired : " + error.message);
+    if (this.msgHandler.startsSession()) return;
+
+    // the pending messages in the html are renamed with their new timeStamps
+    for (let [oldTimeStamp, newTimeStamp] of this.msgHandler.restartSession()) {
+      let child = document.getElementById(oldTimeStamp);
+      if (child) child.id = newTimeStamp;
+    }
+    this.retransmission();
+  }
+
   updateState(serverMsgs) {
     let serverMsg = JSON.parse(serverMsgs);
     let deletedFlag = false;
 
+    if (!Array.isArray(serverMsg)) {
+      if (serverMsg.error == "session_expired") {
+        this.restartSession(serverMsg);
+      }
+      return;
+    }
+
     // 1. Update committed index
     // this.committedIndex = serverMsg.committed_index+1;
 
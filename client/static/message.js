@@ -69,6 +69,30 @@ export class MsgHandler {
     return this.#numOfTimeout;
   }
 
+  // The session of the client expired on the server : the pending messages are numbered
+  // again from 1, which starts a new session, and sent again.
+  // returns the pairs [old timeStamp, new timeStamp]
+  restartSession() {
+    let renamed = [];
+    for (let i = 0; i < this.#msgQue.length; i++) {
+      renamed.push([this.#msgQue[i].timeStamp, i + 1]);
+      this.#msgQue[i].timeStamp = i + 1;
+      this.#msgSent[i] = false;
+    }
+    this.#timeStamp = this.#msgQue.length;
+    localStorage.setItem("timeStamp", this.#timeStamp);
+
+    this.#sendIndexToServer = 0;
+    this.#msgSize = 1;
+    this.#numOfTimeout = 0;
+    return renamed;
+  }
+
+  // true if the first pending message starts a new session
+  startsSession() {
+    return this.#msgQue.length > 0 && this.#msgQue[0].timeStamp === 1;
+  }
+
   resetNumOfTimeOut() {
     this.#numOfTimeout = 0;
   }
@@ -167,6 +191,10 @@ export class Msg {
     return this.#timeStamp;
   }
 
+  set timeStamp(timeStamp) {
+    this.#timeStamp = timeStamp;
+  }
+
   toJson() {
     return {
       id: this.#id,
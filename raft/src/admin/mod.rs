@@ -0,0 +1,249 @@
+// Admin service : status of a node and operator actions.
+
+use crate::raftchat_tonic::admin_server::Admin;
+use crate::raftchat_tonic::{NodeRole, PeerStatus, StatusArgs, StatusRes};
+use crate::raftchat_tonic::{StepDownArgs, StepDownRes};
+use crate::raftchat_tonic::{TimeoutNowArgs, TransferLeadershipArgs, TransferLeadershipRes};
+use crate::raftchat_tonic::{TriggerSnapshotArgs, TriggerSnapshotRes};
+use crate::{witness_has_no_history, MyRaftChat, Role};
+use log::{info, warn};
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::{Request, Response, Status};
+
+const TRANSFER_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+impl MyRaftChat {
+    // Leader only, with new entries rejected.
+    async fn transfer_to(self: &Arc<Self>, target: &'static str) -> Result<(), Status> {
+        let clock = &self.config.clock;
+        let deadline = clock.now() + Duration::from_millis(self.config.election_duration.0);
+        let (client, args) = loop {
+            {
+                let guard = self.state.lock();
+                let Role::Leader(s) = &guard.role else {
+                    return Err(Status::aborted("lost leadership during transfer"));
+                };
+                if s.match_length[target] >= guard.sm.wal().len() {
+                    let args = TimeoutNowArgs {
+                        term: guard.persistent_state.current_term(),
+                        leader_id: String::from(self.config.self_id),
+                        group_id: self.config.group_id,
+                    };
+                    break (guard.connections[target].clone(), args);
+                }
+            }
+            if clock.now() >= deadline {
+                return Err(Status::deadline_exceeded("target did not catch up"));
+            }
+            clock.sleep(TRANSFER_POLL_INTERVAL).await;
+        };
+
+        let term = args.term;
+        let res = self
+            .with_deadline(client.clone().timeout_now(Request::new(args)))
+            .await?
+            .into_inner();
+        if !res.success {
+            return Err(Status::aborted("target refused to start an election"));
+        }
+
+        let mut guard = self.state.lock();
+        if guard.persistent_state.current_term() == term {
+            if let Role::Leader(_) = guard.role {
+                self.reset_to_follower(&mut guard, None);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl Admin for Arc<MyRaftChat> {
+    async fn status(&self, _request: Request<StatusArgs>) -> Result<Response<StatusRes>, Status> {
+        let guard = self.state.lock();
+        let (role, leader_id) = match &guard.role {
+            Role::Leader(_) => (NodeRole::Leader, Some(self.config.self_id)),
+            Role::Follower(s) => (NodeRole::Follower, s.current_leader),
+            Role::Candidate(_) => (NodeRole::Candidate, None),
+        };
+        // NB : the peer health is in instants of the clock
+        let now = self.config.clock.now();
+        let peers = guard
+            .membership
+            .peers(self.config.self_id)
+            .into_iter()
+            .map(|peer| {
+                let (match_length, prev_length) = match &guard.role {
+                    Role::Leader(s) => (s.match_length[peer], s.prev_length[peer]),
+                    _ => (0, 0),
+                };
+                let health = guard.peer_health.get(peer).cloned().unwrap_or_default();
+                PeerStatus {
+                    peer_id: String::from(peer),
+                    match_length,
+                    prev_length,
+                    connection: health.connection(now).into(),
+                    failures: health.failures,
+                    last_error: health.last_error,
+                    retry_in_ms: health
+                        .retry_at
+                        .map_or(0, |at| at.saturating_duration_since(now).as_millis() as u64),
+                }
+            })
+            .collect();
+        Ok(Response::new(StatusRes {
+            node_id: String::from(self.config.self_id),
+            role: role.into(),
+            term: guard.persistent_state.current_term(),
+            leader_id: leader_id.map(String::from),
+            committed_length: guard.committed_length,
+            wal_length: guard.sm.wal().len(),
+            synced_length: guard.sm.wal().synced_len(),
+            snapshot_length: guard
+                .snapshot
+                .as_ref()
+                .map_or(0, |snapshot| snapshot.length),
+            peers,
+        }))
+    }
+
+    // Reject new entries until the target has the whole log of the leader, then ask it to
+    // start an election, and step down. New entries are accepted again if the transfer fails.
+    async fn transfer_leadership(
+        &self,
+        request: Request<TransferLeadershipArgs>,
+    ) -> Result<Response<TransferLeadershipRes>, Status> {
+        let args = request.into_inner();
+        let (target, term) = {
+            let mut guard = self.state.lock();
+            let term = guard.persistent_state.current_term();
+            let Role::Leader(s) = &guard.role else {
+                return Err(Status::failed_precondition("not the leader"));
+            };
+            let mut peers = guard.membership.peers(self.config.self_id);
+            if args.target.as_deref().is_some_and(|t| self.is_witness(t)) {
+                return Err(Status::invalid_argument("a witness cannot lead"));
+            }
+            peers.retain(|&peer| !self.is_witness(peer));
+            if s.transferring {
+                return Err(Status::failed_precondition("a transfer is in progress"));
+            }
+            let target = match &args.target {
+                Some(target) => peers
+                    .into_iter()
+                    .find(|&peer| peer == target)
+                    .ok_or_else(|| Status::invalid_argument("unknown peer"))?,
+                None => peers
+                    .into_iter()
+                    .max_by_key(|&peer| s.match_length[peer])
+                    .ok_or_else(|| Status::failed_precondition("no peer to transfer to"))?,
+            };
+            if let Role::Leader(s) = &mut guard.role {
+                s.transferring = true;
+            }
+            (target, term)
+        };
+        info!("transfer leadership to {}", target);
+
+        let res = self.transfer_to(target).await;
+        if let Err(e) = &res {
+            warn!("transfer leadership to {} failed : {}", target, e.message());
+            let mut guard = self.state.lock();
+            if guard.persistent_state.current_term() == term {
+                if let Role::Leader(s) = &mut guard.role {
+                    s.transferring = false;
+                }
+            }
+        }
+        res?;
+        Ok(Response::new(TransferLeadershipRes {
+            new_leader_id: String::from(target),
+        }))
+    }
+
+    // Write a snapshot of the committed log, unless the latest one is as long.
+    async fn trigger_snapshot(
+        &self,
+        _request: Request<TriggerSnapshotArgs>,
+    ) -> Result<Response<TriggerSnapshotRes>, Status> {
+        if self.is_witness(self.config.self_id) {
+            return Err(witness_has_no_history());
+        }
+        let snapshot_length = self
+            .write_snapshot()
+            .await
+            .map_err(|e| Status::internal(format!("failed to write the snapshot : {}", e)))?;
+        Ok(Response::new(TriggerSnapshotRes { snapshot_length }))
+    }
+
+    // The node becomes a follower of the same term, and another node will be elected after
+    // the election timeout.
+    async fn step_down(
+        &self,
+        _request: Request<StepDownArgs>,
+    ) -> Result<Response<StepDownRes>, Status> {
+        let mut guard = self.state.lock();
+        let Role::Leader(_) = guard.role else {
+            return Err(Status::failed_precondition("not the leader"));
+        };
+        info!("step down");
+        self.reset_to_follower(&mut guard, None);
+        Ok(Response::new(StepDownRes {
+            term: guard.persistent_state.current_term(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::VirtualClock;
+    use crate::raftchat_tonic::admin_server::Admin;
+    use crate::raftchat_tonic::TransferLeadershipArgs;
+    use crate::tests::{eventually, mk_config, node_ids, run, settle, tick_until, TestNode};
+    use std::time::Duration;
+    use tonic::{Code, Request};
+
+    // new entries are rejected while the target catches up, and accepted again if it fails
+    #[test]
+    fn case_transfer_rejects_entries() {
+        run(async {
+            let clock = VirtualClock::new(1);
+            let ids = node_ids(3);
+            let a = TestNode::start(mk_config(ids[0], &ids, &clock));
+            let mut config = mk_config(ids[1], &ids, &clock);
+            config.election_duration = (10_000, 20_000);
+            let _b = TestNode::start(config);
+            assert!(eventually(|| clock.pending() > 3).await);
+            assert!(tick_until(&clock, Duration::from_secs(1), || a.is_leader()).await);
+
+            let res = a.request("c", 1).await.await.unwrap();
+            assert!(res.unwrap().success);
+
+            // the target is down, so it never catches up
+            let raft = a.raft.clone();
+            let transfer = tokio::spawn(async move {
+                let args = TransferLeadershipArgs {
+                    target: Some(String::from(ids[2])),
+                    group_id: 0,
+                };
+                raft.transfer_leadership(Request::new(args)).await
+            });
+            settle().await;
+            let res = a.request("d", 1).await.await.unwrap();
+            assert_eq!(res.unwrap_err().code(), Code::Unavailable);
+
+            assert!(
+                tick_until(&clock, Duration::from_millis(200), || transfer
+                    .is_finished())
+                .await
+            );
+            let e = transfer.await.unwrap().unwrap_err();
+            assert_eq!(e.code(), Code::DeadlineExceeded);
+            assert!(a.is_leader());
+            let res = tokio::spawn(a.request("d", 1).await);
+            assert!(tick_until(&clock, Duration::from_millis(100), || res.is_finished()).await);
+            assert!(res.await.unwrap().unwrap().unwrap().success);
+        });
+    }
+}
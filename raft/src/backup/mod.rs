@@ -0,0 +1,87 @@
+// Backup archive of a chat history.
+// Layout : [MAGIC][number of entries: u64] followed by the entries, in the record format of
+// the file WAL, so that every entry is checksummed, and sealed if a keyring is given.
+
+use crate::raftchat_tonic::Entry;
+use crate::storage::cipher::Keyring;
+use crate::storage::file::{decode_records, encode_record};
+use atomic_write_file::AtomicWriteFile;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 16] = b"RAFTCHAT-BACKUP1";
+const HEADER_LEN: usize = MAGIC.len() + 8;
+
+pub fn write_archive(path: &Path, entries: &[Entry], keyring: Option<&Keyring>) -> io::Result<()> {
+    let mut file = AtomicWriteFile::options().open(path)?;
+    file.write_all(MAGIC)?;
+    file.write_all(&(entries.len() as u64).to_le_bytes())?;
+    for (index, entry) in entries.iter().enumerate() {
+        file.write_all(&encode_record(keyring, index as u64, entry))?;
+    }
+    file.commit()
+}
+
+pub fn read_archive(path: &Path, keyring: Option<&Keyring>) -> io::Result<Vec<Entry>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let buf = fs::read(path)?;
+    if buf.len() < HEADER_LEN || &buf[..MAGIC.len()] != MAGIC {
+        return Err(invalid("not a raftchat backup archive"));
+    }
+    let count = u64::from_le_bytes(buf[MAGIC.len()..HEADER_LEN].try_into().unwrap());
+    let (entries, _, valid_length) = decode_records(keyring, &buf[HEADER_LEN..])?;
+    if entries.len() as u64 != count || HEADER_LEN + valid_length != buf.len() {
+        return Err(invalid("truncated or corrupted backup archive"));
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_archive, write_archive};
+    use crate::raftchat_tonic::{Command, Entry};
+    use crate::storage::cipher::Keyring;
+
+    #[test]
+    fn case_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("backup");
+        let entries = vec![
+            Entry {
+                term: 1,
+                command: None,
+                membership: None,
+                time: 0,
+                session: None,
+            },
+            Entry {
+                term: 2,
+                command: Some(Command {
+                    client_id: "client1".to_string(),
+                    message_id: 1,
+                    data: vec![1, 2, 3],
+                    stripped: false,
+                }),
+                membership: None,
+                time: 0,
+                session: None,
+            },
+        ];
+        write_archive(&path, &entries, None).unwrap();
+        assert_eq!(read_archive(&path, None).unwrap(), entries);
+
+        let key = Keyring::parse(&"ab".repeat(32)).unwrap();
+        let other = Keyring::parse(&"cd".repeat(32)).unwrap();
+        write_archive(&path, &entries, Some(&key)).unwrap();
+        assert_eq!(read_archive(&path, Some(&key)).unwrap(), entries);
+        assert!(read_archive(&path, Some(&other)).is_err());
+        assert!(read_archive(&path, None).is_err());
+
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 1).unwrap();
+        drop(file);
+        assert!(read_archive(&path, Some(&key)).is_err());
+    }
+}
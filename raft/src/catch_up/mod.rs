@@ -0,0 +1,105 @@
+// Replication of the log in chunks.
+// - chunk : an AppendEntries carries the entries following prev_length, up to max_chunk_bytes
+//   (at least one entry). The progress of a peer is kept per chunk, so a transfer interrupted
+//   by a restart or a change of leader resumes from the log of the peer.
+// - throttle : chunks of entries already committed without the peer (catch-up) are limited to
+//   catch_up_rate bytes per second, so that a recovering node does not starve the replication
+//   to the other peers.
+// NB : a peer missing entries of the snapshot of the leader gets the snapshot instead, in
+// chunks throttled the same way (see snapshot).
+
+use crate::raftchat_tonic::Entry;
+use prost::Message;
+use std::time::{Duration, Instant};
+
+// number of entries of the chunk starting at entries[0], and its size in bytes
+pub fn chunk(entries: &[Entry], max_chunk_bytes: usize) -> (usize, usize) {
+    let mut bytes = 0;
+    for (i, entry) in entries.iter().enumerate() {
+        let len = entry.encoded_len();
+        if i > 0 && bytes + len > max_chunk_bytes {
+            return (i, bytes);
+        }
+        bytes += len;
+    }
+    (entries.len(), bytes)
+}
+
+// Token bucket of the catch-up traffic to one peer, holding at most one second of traffic.
+pub struct Throttle {
+    rate: u64, // bytes per second, 0 : unlimited
+    tokens: f64,
+    last: Instant,
+}
+
+impl Throttle {
+    pub fn new(rate: u64, now: Instant) -> Self {
+        Throttle {
+            rate,
+            tokens: rate as f64,
+            last: now,
+        }
+    }
+
+    // take bytes from the bucket, and return how long to wait before sending them
+    pub fn take(&mut self, bytes: usize, now: Instant) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chunk, Throttle};
+    use crate::raftchat_tonic::{Command, Entry};
+    use prost::Message;
+    use std::time::{Duration, Instant};
+
+    fn mk_entry(len: usize) -> Entry {
+        Entry {
+            term: 1,
+            command: Some(Command {
+                client_id: String::from("a"),
+                message_id: 1,
+                data: vec![0; len],
+                stripped: false,
+            }),
+            membership: None,
+            time: 0,
+            session: None,
+        }
+    }
+
+    #[test]
+    fn case_chunk() {
+        let entries: Vec<Entry> = (0..4).map(|_| mk_entry(100)).collect();
+        let len = entries[0].encoded_len();
+        assert_eq!(chunk(&entries, 2 * len), (2, 2 * len));
+        assert_eq!(chunk(&entries, 10 * len), (4, 4 * len));
+        // an entry larger than a chunk is sent alone
+        assert_eq!(chunk(&entries, 1), (1, len));
+        assert_eq!(chunk(&[], 1), (0, 0));
+    }
+
+    #[test]
+    fn case_throttle() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(1000, start);
+        assert_eq!(throttle.take(1000, start), Duration::ZERO);
+        assert_eq!(throttle.take(500, start), Duration::from_millis(500));
+        // the bucket refills with time
+        let later = start + Duration::from_secs(2);
+        assert_eq!(throttle.take(500, later), Duration::ZERO);
+        assert_eq!(Throttle::new(0, start).take(1 << 30, start), Duration::ZERO);
+    }
+}
@@ -0,0 +1,130 @@
+// Change feed : stream of committed entries for external consumers (analytics, archival).
+// A subscriber starts from a log index, or resumes right after the last event it received.
+// The resume token is "<next index>:<term of the last entry>", so that resuming against a log
+// with a different history (e.g. restored from a backup) is detected.
+
+use crate::raftchat_tonic::change_feed_server::ChangeFeed;
+use crate::raftchat_tonic::{ChangeEvent, SubscribeArgs};
+use crate::{witness_has_no_history, MyRaftChat};
+use log::info;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tonic::{Request, Response, Status};
+
+// max number of entries copied under the lock at once
+const BATCH_SIZE: usize = 256;
+const STREAM_BUFFER: usize = 64;
+
+pub fn resume_token(next_index: u64, term: u64) -> String {
+    format!("{}:{}", next_index, term)
+}
+
+// return (next index, term of the entry before it)
+pub fn parse_resume_token(token: &str) -> Option<(u64, u64)> {
+    let (index, term) = token.split_once(':')?;
+    Some((index.parse().ok()?, term.parse().ok()?))
+}
+
+#[tonic::async_trait]
+impl ChangeFeed for Arc<MyRaftChat> {
+    type SubscribeStream = Pin<Box<dyn Stream<Item = Result<ChangeEvent, Status>> + Send>>;
+
+    async fn subscribe(
+        &self,
+        request: Request<SubscribeArgs>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        if self.is_witness(self.config.self_id) {
+            return Err(witness_has_no_history());
+        }
+        let args = request.into_inner();
+        let from = {
+            let guard = self.state.lock();
+            let wal = guard.sm.wal();
+            let from = match &args.resume_token {
+                Some(token) => {
+                    let (index, term) = parse_resume_token(token)
+                        .ok_or_else(|| Status::invalid_argument("malformed resume token"))?;
+                    // NB : the entry before index is committed, once the token is issued
+                    if index == 0
+                        || index > guard.committed_length
+                        || (index > wal.first_index() && wal.last_term_for(index) != term)
+                    {
+                        return Err(Status::failed_precondition(
+                            "resume token does not match the log of this node",
+                        ));
+                    }
+                    index
+                }
+                None => args.from_index,
+            };
+            if from < wal.first_index() {
+                return Err(Status::out_of_range(format!(
+                    "index {} has been compacted, the log starts at {}",
+                    from,
+                    wal.first_index()
+                )));
+            }
+            from
+        };
+        info!("change feed subscribed from {}", from);
+
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        let raft_chat = self.clone();
+        tokio::spawn(async move {
+            let mut committed_rx = raft_chat.committed_length_tx.subscribe();
+            let mut next = from;
+            loop {
+                let entries = {
+                    let guard = raft_chat.state.lock();
+                    let end = guard.committed_length.min(next + BATCH_SIZE as u64);
+                    if next < end {
+                        guard.sm.wal().as_slice()[next as usize..end as usize].to_vec()
+                    } else {
+                        vec![]
+                    }
+                };
+                if entries.is_empty() {
+                    // wait for new commits, or the subscriber to leave
+                    tokio::select! {
+                        res = committed_rx.wait_for(|&l| l > next) => {
+                            if res.is_err() {
+                                return;
+                            }
+                        }
+                        _ = tx.closed() => return,
+                    }
+                    continue;
+                }
+                for entry in entries {
+                    let event = ChangeEvent {
+                        index: next,
+                        resume_token: resume_token(next + 1, entry.term),
+                        entry: Some(entry),
+                    };
+                    if tx.send(Ok(event)).await.is_err() {
+                        // unsubscribed
+                        return;
+                    }
+                    next += 1;
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_resume_token, resume_token};
+
+    #[test]
+    fn case_resume_token() {
+        assert_eq!(parse_resume_token(&resume_token(42, 3)), Some((42, 3)));
+        assert_eq!(parse_resume_token("42"), None);
+        assert_eq!(parse_resume_token("x:3"), None);
+    }
+}
@@ -0,0 +1,186 @@
+// Time and randomness of the raft protocol : election timeouts, heartbeats, backoffs and their
+// jitter, RPC deadlines, group commit delay, catch-up throttle, leader balancing, the instants of
+// the peer health and of the staleness, and the time of the entries, which expires the sessions.
+// They go through the clock of RaftConfig, so that tests can drive them by hand on a
+// VirtualClock.
+// Still on the real clock and the OS RNG :
+// - the connect timeout of the channels to the peers, applied inside tonic
+// - the retries of a node joining the cluster, before it has a raft group
+// - the nonces of the encryption at rest, which must not be predictable
+// - mock_raft
+
+use parking_lot::Mutex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::oneshot;
+use tokio::time;
+
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+pub trait Clock: Send + Sync + fmt::Debug {
+    fn now(&self) -> Instant;
+    // wall clock, for the time of the entries
+    fn system_time(&self) -> SystemTime;
+    fn sleep(&self, duration: Duration) -> Sleep;
+    // uniform in [0, 1)
+    fn random(&self) -> f64;
+}
+
+#[derive(Debug, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(time::sleep(duration))
+    }
+
+    fn random(&self) -> f64 {
+        rand::thread_rng().gen()
+    }
+}
+
+// Clock of the tests : time only moves by advance, and the randomness is seeded, so that a run
+// is reproducible.
+#[derive(Debug)]
+pub struct VirtualClock {
+    state: Mutex<VirtualState>,
+}
+
+#[derive(Debug)]
+struct VirtualState {
+    start: Instant,
+    elapsed: Duration,
+    timers: Vec<(Duration, oneshot::Sender<()>)>, // deadline since start
+    rng: StdRng,
+}
+
+impl VirtualClock {
+    pub fn new(seed: u64) -> Arc<VirtualClock> {
+        Arc::new(VirtualClock {
+            state: Mutex::new(VirtualState {
+                start: Instant::now(),
+                elapsed: Duration::ZERO,
+                timers: vec![],
+                rng: StdRng::seed_from_u64(seed),
+            }),
+        })
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.state.lock().elapsed
+    }
+
+    // number of sleeps not yet woken up
+    pub fn pending(&self) -> usize {
+        let mut guard = self.state.lock();
+        guard.timers.retain(|(_, tx)| !tx.is_closed());
+        guard.timers.len()
+    }
+
+    // Move the time forward, waking up the sleeps due in deadline order.
+    pub fn advance(&self, duration: Duration) {
+        let mut guard = self.state.lock();
+        guard.elapsed += duration;
+        let elapsed = guard.elapsed;
+        let (mut due, rest) = guard
+            .timers
+            .drain(..)
+            .partition::<Vec<_>, _>(|(deadline, _)| *deadline <= elapsed);
+        guard.timers = rest;
+        drop(guard);
+        due.sort_by_key(|(deadline, _)| *deadline);
+        for (_, tx) in due {
+            // NB : the sleep may be dropped already
+            let _ = tx.send(());
+        }
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        let guard = self.state.lock();
+        guard.start + guard.elapsed
+    }
+
+    // NB : starts at the epoch
+    fn system_time(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + self.state.lock().elapsed
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        if duration.is_zero() {
+            return Box::pin(async {});
+        }
+        let (tx, rx) = oneshot::channel();
+        let mut guard = self.state.lock();
+        let deadline = guard.elapsed + duration;
+        guard.timers.push((deadline, tx));
+        Box::pin(async move {
+            // NB : never woken up if the clock is dropped
+            if rx.await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        })
+    }
+
+    fn random(&self) -> f64 {
+        self.state.lock().rng.gen()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, VirtualClock};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn case_virtual_clock() {
+        let ms = Duration::from_millis;
+        let clock = VirtualClock::new(7);
+        let start = clock.now();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        for d in [300, 100, 200] {
+            let sleep = clock.sleep(ms(d));
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                sleep.await;
+                tx.send(d).unwrap();
+            });
+        }
+        assert_eq!(clock.pending(), 3);
+
+        clock.advance(ms(150));
+        assert_eq!(rx.recv().await, Some(100));
+        assert_eq!(clock.pending(), 2);
+        clock.advance(ms(150));
+        let mut woken = vec![rx.recv().await.unwrap(), rx.recv().await.unwrap()];
+        woken.sort();
+        assert_eq!(woken, vec![200, 300]);
+        assert_eq!(clock.pending(), 0);
+        assert_eq!(clock.now() - start, ms(300));
+        assert_eq!(clock.elapsed(), ms(300));
+        clock.sleep(Duration::ZERO).await;
+
+        // seeded
+        let other: Arc<dyn Clock> = VirtualClock::new(7);
+        let a: Vec<f64> = (0..4).map(|_| clock.random()).collect();
+        let b: Vec<f64> = (0..4).map(|_| other.random()).collect();
+        assert_eq!(a, b);
+        assert!(a.iter().all(|x| (0.0..1.0).contains(x)));
+    }
+}
@@ -0,0 +1,171 @@
+// Leader placement
+// - priority : a node of lower election priority waits longer before starting an election, and a
+//   leader hands over to a caught up member of higher priority.
+// - spreading : with many raft groups, every group prefers one of the members of the highest
+//   priority, the one at group_id % their number, so that leaders are spread across the nodes.
+
+use crate::raftchat_tonic::admin_server::Admin;
+use crate::raftchat_tonic::TransferLeadershipArgs;
+use crate::{MyRaftChat, Role};
+use log::{debug, info};
+use std::sync::Arc;
+use tonic::Request;
+
+impl MyRaftChat {
+    pub fn priority(&self, id: &str) -> u32 {
+        self.config.priorities.get(id).copied().unwrap_or(0)
+    }
+
+    // Extra election timeout of a node, one election timeout range per priority level below the
+    // highest one, so that nodes of higher priority start their elections first.
+    pub fn election_handicap(&self) -> u64 {
+        let top = self.config.priorities.values().copied().max().unwrap_or(0);
+        let (min, max) = self.config.election_duration;
+        (top - self.priority(self.config.self_id).min(top)) as u64 * (max - min)
+    }
+
+    // the member which should lead this group, if self is the leader and is not it
+    fn preferred_leader(&self, spread: bool) -> Option<&'static str> {
+        let guard = self.state.lock();
+        let Role::Leader(s) = &guard.role else {
+            return None;
+        };
+        let self_id = self.config.self_id;
+        let self_priority = self.priority(self_id);
+        // NB : a transfer waits for the target to have the whole log
+        let caught_up = |peer| s.match_length[peer] >= guard.committed_length;
+
+        let mut members = guard.membership.members().to_vec();
+        members.retain(|&m| !self.is_witness(m));
+        members.sort();
+        let top = members.iter().map(|&m| self.priority(m)).max()?;
+        if self_priority < top {
+            return members
+                .into_iter()
+                .filter(|&m| m != self_id && self.priority(m) > self_priority && caught_up(m))
+                .max_by_key(|&m| self.priority(m));
+        }
+        if !spread {
+            return None;
+        }
+        members.retain(|&m| self.priority(m) == top);
+        let preferred = members[(self.config.group_id % members.len() as u64) as usize];
+        (preferred != self_id && caught_up(preferred)).then_some(preferred)
+    }
+}
+
+// Periodically hand over the leadership of the groups led by a node other than the
+// preferred one.
+// NB : the groups of a process share the balance interval and clock of the first one
+pub fn balance_leaders(nodes: Vec<Arc<MyRaftChat>>) {
+    let Some(first) = nodes.first() else {
+        return;
+    };
+    let interval = first.config.balance_interval;
+    let clock = first.config.clock.clone();
+    let spread = nodes.len() > 1;
+    tokio::spawn(async move {
+        loop {
+            clock.sleep(interval).await;
+            for node in nodes.iter() {
+                let Some(target) = node.preferred_leader(spread) else {
+                    continue;
+                };
+                let args = TransferLeadershipArgs {
+                    target: Some(String::from(target)),
+                    group_id: node.config.group_id,
+                };
+                match node.transfer_leadership(Request::new(args)).await {
+                    Ok(_) => info!(
+                        "leadership of group {} handed over to {}",
+                        node.config.group_id, target
+                    ),
+                    Err(e) => debug!(
+                        "cannot hand over group {} to {} : {}",
+                        node.config.group_id,
+                        target,
+                        e.message()
+                    ),
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::VirtualClock;
+    use crate::tests::{eventually, mk_config, node_ids, run, tick_until, TestNode};
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    fn priorities(ids: &[&'static str], levels: &[u32]) -> HashMap<&'static str, u32> {
+        ids.iter().copied().zip(levels.iter().copied()).collect()
+    }
+
+    // one election timeout range per priority level below the highest one
+    #[test]
+    fn case_election_handicap() {
+        run(async {
+            let clock = VirtualClock::new(1);
+            let ids = ["a", "b", "c"];
+            let handicap = |id| {
+                let mut config = mk_config(id, &ids, &clock);
+                config.priorities = priorities(&ids, &[2, 1, 0]);
+                TestNode::start(config).raft.election_handicap()
+            };
+            assert_eq!(handicap("a"), 0);
+            assert_eq!(handicap("b"), 150);
+            assert_eq!(handicap("c"), 300);
+        });
+    }
+
+    // the node of the highest priority starts its election first, and wins it
+    #[test]
+    fn case_preferred_wins() {
+        run(async {
+            let clock = VirtualClock::new(1);
+            let ids = node_ids(3);
+            let nodes: Vec<TestNode> = ids
+                .iter()
+                .map(|&id| {
+                    let mut config = mk_config(id, &ids, &clock);
+                    config.priorities = priorities(&ids, &[0, 1, 0]);
+                    TestNode::start(config)
+                })
+                .collect();
+            assert!(eventually(|| clock.pending() > 5).await);
+            let leader = || nodes.iter().position(|node| node.is_leader());
+            assert!(tick_until(&clock, Duration::from_secs(1), || leader().is_some()).await);
+            assert_eq!(leader(), Some(1));
+            assert_eq!(nodes[1].term(), 1);
+        });
+    }
+
+    // a node of higher priority which starts late gets the leadership handed over
+    #[test]
+    fn case_preferred_gets_back() {
+        run(async {
+            let clock = VirtualClock::new(1);
+            let ids = node_ids(3);
+            let config = |id| {
+                let mut config = mk_config(id, &ids, &clock);
+                config.priorities = priorities(&ids, &[0, 0, 1]);
+                config.balance_interval = Duration::from_millis(100);
+                config
+            };
+            let a = TestNode::start(config(ids[0]));
+            let b = TestNode::start(config(ids[1]));
+            assert!(eventually(|| clock.pending() > 3).await);
+            assert!(
+                tick_until(&clock, Duration::from_secs(2), || a.is_leader()
+                    || b.is_leader())
+                .await
+            );
+
+            let c = TestNode::start(config(ids[2]));
+            assert!(tick_until(&clock, Duration::from_secs(2), || c.is_leader()).await);
+            assert!(!a.is_leader() && !b.is_leader());
+        });
+    }
+}
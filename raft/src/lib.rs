@@ -1,67 +1,129 @@
+pub mod admin;
+pub mod backup;
+pub mod catch_up;
+pub mod change_feed;
+pub mod clock;
+pub mod leadership;
+pub mod membership;
+pub mod metrics;
 pub mod mock_raft;
+pub mod multi_raft;
+pub mod peer_health;
 pub mod persistent_state;
 pub mod raftchat_tonic;
+pub mod rtt;
+pub mod snapshot;
+pub mod staleness;
 pub mod state_machine;
+pub mod storage;
+#[cfg(test)]
+mod tests;
+pub mod trace_context;
 pub mod wal;
+pub mod witness;
 
-use parking_lot::{Condvar, Mutex, MutexGuard};
-use rand::Rng;
-use std::cmp::{max, min};
-use std::collections::HashMap;
+use parking_lot::{Mutex, MutexGuard};
+use std::cmp::{max, min, Reverse};
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::net::SocketAddr;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::{mpsc, oneshot};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot, watch, Notify, Semaphore};
 use tokio::task;
-use tokio::time;
+use tokio_stream::wrappers::TcpListenerStream;
 
+use raftchat_tonic::admin_server::AdminServer;
+use raftchat_tonic::change_feed_server::ChangeFeedServer;
 use raftchat_tonic::raft_chat_client::RaftChatClient;
 use raftchat_tonic::raft_chat_server::{RaftChat, RaftChatServer};
+use raftchat_tonic::BackupArgs;
+use raftchat_tonic::{AddMemberArgs, AddMemberRes};
 use raftchat_tonic::{AppendEntriesArgs, AppendEntriesRes};
 use raftchat_tonic::{Command, Entry};
-use raftchat_tonic::{RequestVoteArgs, RequestVoteRes};
+use raftchat_tonic::{InstallSnapshotArgs, InstallSnapshotRes};
+use raftchat_tonic::{RequestVoteArgs, RequestVoteRes, Session};
+use raftchat_tonic::{TimeoutNowArgs, TimeoutNowRes};
 use raftchat_tonic::{UserRequestArgs, UserRequestRes};
 
 use tonic::transport::{Channel, Endpoint, Server};
-use tonic::{Request, Response, Status};
+use tonic::{Code, Request, Response, Status};
 
+use clock::Clock;
+use membership::Membership;
+use multi_raft::MultiRaft;
+use peer_health::PeerHealth;
 use persistent_state::PersistentState;
+use snapshot::{Incoming, Snapshot};
+use staleness::Staleness;
 use state_machine::{SMWrapper, UserMessageIdMap};
+use storage::cipher::Keyring;
+use storage::StorageBackend;
 use wal::WAL;
 
 use tokio_util::task::AbortOnDropHandle;
 
-use log::{debug, info};
+use log::{debug, error, info, warn};
+use std::future::{poll_fn, Future};
 use std::pin::Pin;
+use std::task::Poll;
+use tokio_stream::Stream;
+use tracing::{info_span, Instrument, Span};
 
 #[derive(Debug)]
 pub struct RaftConfig {
     pub serve_addr: SocketAddr,
+    pub group_id: u64,
     pub self_id: &'static str,
-    pub peers: Vec<&'static str>,      // except self
+    // bootstrap configuration, until a membership entry is in the log
+    // empty for a node joining the cluster
+    pub members: Vec<&'static str>,
     pub election_duration: (u64, u64), // lower~upper bound (ms)
+    // election priority of the nodes, 0 for the nodes not listed
+    pub priorities: HashMap<&'static str, u32>,
+    // members keeping only the log metadata, which vote but never lead
+    pub witnesses: HashSet<&'static str>,
     pub heartbeat_duration: Duration,
+    // election timeouts and heartbeats adapted to the RTT, within election_duration
+    pub adaptive_timeout: bool,
+    pub wal_sync_delay: Duration, // max delay for batching WAL writes into one fsync
+    // deduplication sessions of clients expire after this time without requests
+    pub session_timeout: Duration,
+    // max uncommitted entries on the leader, and max user requests in flight on a node
+    pub max_pending: usize,
+    pub max_chunk_bytes: usize, // max size of the entries of one AppendEntries
+    pub catch_up_rate: u64,     // bytes per second to a lagging peer, 0 : unlimited
+    pub rpc_timeout: Duration,  // deadline of the RPCs to the peers
+    // period of the hand over of the leadership to the preferred leader
+    pub balance_interval: Duration,
+    pub storage_backend: StorageBackend,
     pub persistent_state_path: &'static Path,
     pub wal_path: &'static Path,
+    // snapshot of the committed log, written on request, kept in memory only with the memory backend
+    pub snapshot_path: &'static Path,
+    // encryption of the WAL and the snapshots at rest, plain if None
+    pub keyring: Option<Arc<Keyring>>,
+    // timers and randomness of the protocol, clock::TokioClock but in tests
+    pub clock: Arc<dyn Clock>,
 }
 
-impl RaftConfig {
-    fn get_peer(&self, s: &str) -> Option<&'static str> {
-        if s == self.self_id {
-            Some(self.self_id)
-        } else {
-            self.peers.iter().find(|&&peer| peer == s).copied()
-        }
-    }
+// user request of the web server, with the channel of its result
+pub struct UserRequest {
+    pub args: UserRequestArgs,
+    pub span: Span,
+    pub result_tx: oneshot::Sender<Result<UserRequestRes, Status>>,
+}
 
-    fn cluster_size(&self) -> usize {
-        self.peers.len() + 1
-    }
+pub fn witness_has_no_history() -> Status {
+    Status::failed_precondition("a witness does not store the command payloads")
+}
 
-    fn quorum_size(&self) -> usize {
-        (self.cluster_size() / 2) + 1
-    }
+// retryable : the request is rejected before being proposed
+pub fn overloaded() -> Status {
+    Status::resource_exhausted("overloaded, retry later")
 }
 
 pub struct LeaderState {
@@ -71,6 +133,13 @@ pub struct LeaderState {
     match_length: HashMap<&'static str, u64>,
     // NB : alarm false to senders when dropping LeaderState
     commit_alarm: Vec<(u64, oneshot::Sender<bool>)>,
+    // span of the user request of each uncommitted entry, parent of its replication
+    entry_spans: HashMap<u64, Span>,
+    // a leadership transfer is in progress, new entries are rejected
+    transferring: bool,
+    // snapshot sent to each peer missing some of its entries, and the offset of the next chunk,
+    // None until the peer tells where to resume
+    snapshot_transfers: HashMap<&'static str, (Arc<Snapshot>, Option<u64>)>,
 }
 
 pub struct FollowerState {
@@ -86,36 +155,132 @@ pub struct CandidateState {
     timeout_handle: AbortOnDropHandle<()>,
 }
 
+#[allow(clippy::large_enum_variant)]
 pub enum Role {
     Leader(LeaderState),
     Follower(FollowerState),
     Candidate(CandidateState),
 }
 
+// next step of the replication to a peer
+#[allow(clippy::large_enum_variant)]
+enum PeerStep {
+    // nothing to send until the next proposal
+    Wait,
+    // the peer is unreachable, retry after the backoff
+    Retry(Duration),
+    Send {
+        client: RaftChatClient<Channel>,
+        args: AppendEntriesArgs,
+        span: Span,
+        // throttled, if the entries are only needed to catch up
+        catch_up_bytes: Option<usize>,
+    },
+    // a chunk of a snapshot, throttled as catch-up traffic
+    Install {
+        client: RaftChatClient<Channel>,
+        args: InstallSnapshotArgs,
+        span: Span,
+    },
+}
+
 pub struct RaftState {
     persistent_state: PersistentState, // current Term & voted For
     sm: SMWrapper<UserMessageIdMap>,   // log[]
     committed_length: u64,             // committed index in paper
+    leader_committed_length: u64, // committed length of the leader, as of the last append entries
+    // last time committed_length caught up with leader_committed_length, as a follower
+    fresh_at: Option<Instant>,
     role: Role,
+    membership: Membership,
+    // a peer future runs for each connection
     connections: HashMap<&'static str, RaftChatClient<Channel>>,
+    // smoothed RTT of the heartbeats to each peer, as a leader
+    peer_rtt: HashMap<&'static str, Duration>,
+    // connection state of each peer, from the RPCs sent to it
+    peer_health: HashMap<&'static str, PeerHealth>,
+    // the log has entries stripped for a witness, so this node cannot lead
+    log_stripped: bool,
+    // latest snapshot written or installed by this node
+    snapshot: Option<Arc<Snapshot>>,
+    // snapshot being received from the leaders
+    incoming_snapshot: Option<Incoming>,
 }
 
 pub struct MyRaftChat {
     config: RaftConfig,
     state: Mutex<RaftState>,
-    committed_length_cvar: Condvar,
-    propose_cvar: Condvar,
+    // bumped on every new entry or commit of the leader, to wake up the peer futures
+    propose_tx: watch::Sender<()>,
+    wal_sync_notify: Notify,
+    // one snapshot is written at a time
+    snapshot_lock: tokio::sync::Mutex<()>,
+    synced_length_tx: watch::Sender<u64>,
+    committed_length_tx: watch::Sender<u64>,
+    rpc_bound: AtomicBool,
+    // RTT estimate of the leader to this node (us), 0 if unknown
+    leader_rtt_us: AtomicU64,
+    // shared by the raft groups of this process
+    channels: Channels,
+}
+
+// one channel per peer node, multiplexing the RPCs of every raft group
+pub type Channels = Arc<Mutex<HashMap<&'static str, Channel>>>;
+
+// Snapshot of the state of a node, for health checks
+#[derive(Debug, Clone)]
+pub struct NodeStatus {
+    pub rpc_bound: bool,
+    pub is_leader: bool,
+    pub leader_id: Option<&'static str>,
+    pub term: u64,
+    pub committed_length: u64,
+    pub leader_committed_length: u64,
+    pub staleness: Staleness,
 }
 
 impl MyRaftChat {
+    pub fn status(&self) -> NodeStatus {
+        let staleness = self.staleness();
+        let guard = self.state.lock();
+        let (is_leader, leader_id, leader_committed_length) = match &guard.role {
+            Role::Leader(_) => (true, Some(self.config.self_id), guard.committed_length),
+            Role::Follower(s) => (false, s.current_leader, guard.leader_committed_length),
+            Role::Candidate(_) => (false, None, guard.leader_committed_length),
+        };
+        NodeStatus {
+            rpc_bound: self.rpc_bound.load(Ordering::Relaxed),
+            is_leader,
+            leader_id,
+            term: guard.persistent_state.current_term(),
+            committed_length: guard.committed_length,
+            leader_committed_length,
+            staleness,
+        }
+    }
+
     async fn timeout_future(self: Arc<Self>) {
-        let rand_duration = rand::thread_rng()
-            .gen_range(self.config.election_duration.0..self.config.election_duration.1);
+        if self.is_witness(self.config.self_id) {
+            return;
+        }
+        let rand_duration = self.election_timeout() + self.election_handicap();
 
-        time::sleep(std::time::Duration::from_millis(rand_duration)).await;
+        self.config
+            .clock
+            .sleep(Duration::from_millis(rand_duration))
+            .await;
         let mut guard = self.state.lock();
         // TODO : Add checking for term and role
 
+        // NB : a node joining the cluster waits for the leader
+        if !guard.membership.contains(self.config.self_id) {
+            return;
+        }
+        if guard.log_stripped {
+            warn!("the log has stripped entries, do not campaign");
+            return;
+        }
+
         guard.persistent_state.start_election(self.config.self_id);
         // NB : this will cancel itself
         self.reset_to_candidate(&mut guard);
@@ -123,18 +288,27 @@ impl MyRaftChat {
 
     async fn heartbeat_future(self: Arc<Self>) {
         loop {
-            time::sleep(self.config.heartbeat_duration).await;
+            let interval = self.heartbeat_interval(&self.state.lock());
+            self.config.clock.sleep(interval).await;
             let guard = self.state.lock();
             if let Role::Leader(s) = &guard.role {
-                for (peer, client) in guard.connections.clone() {
-                    let prev_length = s.prev_length[peer];
+                for (&peer, &prev_length) in s.prev_length.iter() {
+                    if !self.peer_ready(&guard, peer) {
+                        continue;
+                    }
+                    let client = guard.connections[peer].clone();
                     let args = AppendEntriesArgs {
                         term: guard.persistent_state.current_term(),
                         leader_id: String::from(self.config.self_id),
-                        prev_length: prev_length,
+                        prev_length,
                         prev_term: guard.sm.wal().last_term_for(prev_length),
                         entries: vec![],
                         committed_length: guard.committed_length,
+                        group_id: self.config.group_id,
+                        rtt_us: guard
+                            .peer_rtt
+                            .get(peer)
+                            .map_or(0, |rtt| rtt.as_micros() as u64),
                     };
                     debug!("{} send heartbeat to {}", self.config.self_id, peer);
                     task::spawn(self.clone().append_entries_future(peer, client, args));
@@ -145,57 +319,105 @@ impl MyRaftChat {
         }
     }
 
+    // Request the vote of every peer, until the election is won or lost, or the candidate
+    // times out (which aborts this future). A request failed on the connection is retried
+    // with a short backoff, a denied vote is final.
     async fn election_future(self: Arc<Self>) {
-        // NB : Repeatedly requesting vote for every peers is more robust,
-        // But we will request vote only once, just for ease of implementation.
-        let (req, connections) = {
+        let (req, connections, quorum_size) = {
             let guard = self.state.lock();
             let req = RequestVoteArgs {
                 candidate_id: String::from(self.config.self_id),
                 prev_length: guard.sm.wal().len(),
                 prev_term: guard.sm.wal().last_term(),
                 term: guard.persistent_state.current_term(),
+                group_id: self.config.group_id,
             };
-            let connections = guard.connections.clone();
-            (req, connections)
+            let connections: Vec<(&'static str, RaftChatClient<Channel>)> = guard
+                .membership
+                .peers(self.config.self_id)
+                .into_iter()
+                .map(|peer| (peer, guard.connections[peer].clone()))
+                .collect();
+            (req, connections, guard.membership.quorum_size())
         };
 
-        let (vote_tx, mut vote_rx) = mpsc::channel::<bool>(10);
+        // None : the peer failed the request otherwise than on the connection
+        let (vote_tx, mut vote_rx) = mpsc::channel::<Option<RequestVoteRes>>(10);
         let mut handles = vec![];
-        for (_peer, mut client) in connections {
+        let voters = connections.len() + 1;
+        for (peer, mut client) in connections {
             let req_cloned = req.clone();
             let vote_tx_cloned = vote_tx.clone();
+            let self_cloned = self.clone();
             handles.push(AbortOnDropHandle::new(task::spawn(async move {
-                match client.request_vote(Request::new(req_cloned)).await {
-                    Ok(res) => vote_tx_cloned.send(res.into_inner().vote_granted).await,
-                    Err(_) => vote_tx_cloned.send(false).await,
+                // NB : a vote is granted again to the same candidate within a term
+                for attempt in 1.. {
+                    let res = self_cloned
+                        .with_deadline(client.request_vote(Request::new(req_cloned.clone())))
+                        .await;
+                    self_cloned.record_rpc(&mut self_cloned.state.lock(), peer, &res);
+                    match res {
+                        Ok(res) => {
+                            let _ = vote_tx_cloned.send(Some(res.into_inner())).await;
+                            return;
+                        }
+                        Err(status) if peer_health::is_connection_error(&status) => {
+                            let delay = self_cloned.vote_retry_delay(attempt);
+                            self_cloned.config.clock.sleep(delay).await;
+                        }
+                        Err(_) => {
+                            let _ = vote_tx_cloned.send(None).await;
+                            return;
+                        }
+                    }
                 }
             })));
         }
 
+        drop(vote_tx);
+
         let mut vote_count: usize = 1; // Candidates vote for itself
+        let mut denied_count: usize = 0;
         let elected = loop {
-            // NB : This loop will get stuck if number of vote is not sufficient.
-            // We have timeout for election anyway.
-            if let Some(b) = vote_rx.recv().await {
-                if b {
-                    vote_count = vote_count + 1;
-                }
-            } else {
+            // NB : checked before receiving, so that a single node cluster elects itself
+            if vote_count >= quorum_size {
+                break true;
+            }
+            // NB : the candidate keeps its term until its timeout, or a higher term
+            if voters - denied_count < quorum_size {
+                info!("{} lost the election", self.config.self_id);
                 break false;
             }
-            if vote_count >= self.config.quorum_size() {
-                break true;
+            match vote_rx.recv().await {
+                Some(Some(res)) if res.term > req.term => {
+                    info!(
+                        "{} steps down for the term {}",
+                        self.config.self_id, res.term
+                    );
+                    let mut guard = self.state.lock();
+                    guard.persistent_state.update_term(res.term);
+                    if let Role::Candidate(_) = guard.role {
+                        // NB : this will cancel itself
+                        self.reset_to_follower(&mut guard, None);
+                    }
+                    return;
+                }
+                Some(Some(res)) if res.vote_granted => vote_count += 1,
+                Some(_) => denied_count += 1,
+                None => break false,
             }
         };
 
         if elected {
             info!("{} is elected as leader", self.config.self_id);
+            metrics::ELECTIONS_WON.inc();
 
             let mut guard = self.state.lock();
             self.reset_to_leader(&mut guard);
+            // NB : a single node cluster commits its whole log right away
+            self.advance_commit(&mut guard);
             drop(guard);
-            self.propose_cvar.notify_all();
+            self.propose_tx.send_replace(());
         }
     }
 
@@ -211,16 +433,27 @@ impl MyRaftChat {
         if entries_len > 0 {
             info!("send non empty append entries to {}", peer);
         }
-        if let Ok(res) = client.append_entries(Request::new(args)).await {
+        let timer = metrics::APPEND_ENTRIES_LATENCY
+            .with_label_values(&[peer])
+            .start_timer();
+        let mut request = Request::new(args);
+        trace_context::inject(&Span::current(), &mut request);
+        let res = self.with_deadline(client.append_entries(request)).await;
+        let rtt = Duration::from_secs_f64(timer.stop_and_record());
+        self.record_rpc(&mut self.state.lock(), peer, &res);
+        if let Ok(res) = res {
             let res = res.into_inner();
             if res.term == term {
                 let mut guard = self.state.lock();
+                // NB : only heartbeats, the others wait for a WAL sync of the peer
+                if entries_len == 0 {
+                    let srtt = rtt::smooth(guard.peer_rtt.get(peer).copied(), rtt);
+                    guard.peer_rtt.insert(peer, srtt);
+                }
                 if let (
                     true,
                     RaftState {
-                        sm,
                         role: Role::Leader(s),
-                        committed_length,
                         ..
                     },
                 ) = (guard.persistent_state.current_term() == term, &mut *guard)
@@ -236,29 +469,10 @@ impl MyRaftChat {
                         *l = new_l;
                         s.prev_length.insert(peer, new_l);
 
-                        // Update committed_length
-                        let mut v: Vec<std::cmp::Reverse<u64>> = s
-                            .match_length
-                            .values()
-                            .cloned()
-                            .map(|x| std::cmp::Reverse(x))
-                            .collect();
-                        v.sort();
-                        let l: u64 = v[self.config.quorum_size() - 2].0;
-                        if *committed_length < l {
-                            *committed_length = l;
-                            sm.take_snapshot(l);
-                            self.committed_length_cvar.notify_one();
-                            let v: Vec<(u64, oneshot::Sender<bool>)> =
-                                s.commit_alarm.drain(..).collect();
-                            for (i, ch) in v {
-                                if i < l {
-                                    info!("send commit alarm for {} < {}", i, l);
-                                    let _ = ch.send(true);
-                                } else {
-                                    s.commit_alarm.push((i, ch));
-                                }
-                            }
+                        self.advance_commit(&mut guard);
+                        // NB : the witnesses wait for the full followers
+                        if !self.is_witness(peer) {
+                            self.propose_tx.send_replace(());
                         }
                     } else {
                         if entries_len > 0 {
@@ -266,25 +480,288 @@ impl MyRaftChat {
                         }
                         assert!(prev_length > 0); // NB : If prev_length == 0 and res.term == term,
                                                   // then it is absurd to reject append entries rpc
-                        s.prev_length.insert(peer, prev_length - 1);
+                                                  // NB : the reply to an older probe, e.g. a heartbeat sent before a
+                                                  // snapshot was installed, is stale
+                        if s.prev_length.get(peer) == Some(&prev_length) {
+                            s.prev_length.insert(peer, prev_length - 1);
+                        }
                     }
                 }
             }
         }
     }
 
+    async fn install_snapshot_future(
+        self: Arc<Self>,
+        peer: &'static str,
+        mut client: RaftChatClient<Channel>,
+        args: InstallSnapshotArgs,
+    ) {
+        let term = args.term;
+        let res = self
+            .with_deadline(client.install_snapshot(Request::new(args)))
+            .await;
+        let mut guard = self.state.lock();
+        self.record_rpc(&mut guard, peer, &res);
+        let Ok(res) = res.map(Response::into_inner) else {
+            return;
+        };
+        if res.term != term || guard.persistent_state.current_term() != term {
+            return;
+        }
+        let Role::Leader(s) = &mut guard.role else {
+            return;
+        };
+        let Some((snapshot, offset)) = s.snapshot_transfers.get_mut(peer) else {
+            return;
+        };
+        if res.next_offset < snapshot.image.len() as u64 {
+            *offset = Some(res.next_offset);
+            return;
+        }
+        let length = snapshot.length;
+        info!("snapshot of {} entries installed on {}", length, peer);
+        s.snapshot_transfers.remove(peer);
+        s.prev_length.insert(peer, length);
+        let l = s.match_length.get_mut(peer).unwrap();
+        *l = max(*l, length);
+        self.advance_commit(&mut guard);
+    }
+
+    // Leader only.
+    // Longest log of the full followers, None without full followers.
+    fn full_match_length(&self, guard: &RaftState) -> Option<u64> {
+        let Role::Leader(s) = &guard.role else {
+            return None;
+        };
+        guard
+            .membership
+            .peers(self.config.self_id)
+            .into_iter()
+            .filter(|&peer| !self.is_witness(peer))
+            .map(|peer| s.match_length[peer])
+            .max()
+    }
+
+    // Length of the log of the leader which can be sent to peer.
+    // A witness gets no entry which no full follower has, so that the log of a witness is never
+    // ahead of every full follower, which could not be elected without its vote otherwise.
+    fn send_limit(&self, guard: &RaftState, peer: &str) -> u64 {
+        let len = guard.sm.wal().len();
+        if !self.is_witness(peer) {
+            return len;
+        }
+        self.full_match_length(guard).map_or(len, |l| l.min(len))
+    }
+
+    // Leader only.
+    // An entry is committed when it is on stable storage of a quorum, including the leader itself,
+    // and of a full follower if any : the payloads of a witness cannot replace a lost leader.
+    fn advance_commit(&self, guard: &mut MutexGuard<RaftState>) {
+        let full_match_length = self.full_match_length(guard);
+        let RaftState {
+            sm,
+            role: Role::Leader(s),
+            committed_length,
+            membership,
+            ..
+        } = &mut **guard
+        else {
+            return;
+        };
+
+        let mut v: Vec<Reverse<u64>> = membership
+            .members()
+            .iter()
+            .map(|&member| {
+                if member == self.config.self_id {
+                    sm.wal().synced_len()
+                } else {
+                    s.match_length[member]
+                }
+            })
+            .map(Reverse)
+            .collect();
+        v.sort();
+        let Some(&Reverse(l)) = v.get(membership.quorum_size() - 1) else {
+            return;
+        };
+        let l = full_match_length.map_or(l, |full| l.min(full));
+        if *committed_length < l {
+            *committed_length = l;
+            self.committed_length_tx.send_replace(l);
+            sm.take_snapshot(l);
+            let v: Vec<(u64, oneshot::Sender<bool>)> = s.commit_alarm.drain(..).collect();
+            for (i, ch) in v {
+                if i < l {
+                    info!("send commit alarm for {} < {}", i, l);
+                    let _ = ch.send(true);
+                } else {
+                    s.commit_alarm.push((i, ch));
+                }
+            }
+            s.entry_spans.retain(|&i, _| i >= l);
+        }
+    }
+
+    // Group commit.
+    // Entries appended to the WAL within wal_sync_delay are made durable by a single fsync,
+    // and every commit alarm (leader) or append entries response (follower) waiting for
+    // them is completed together.
+    async fn wal_sync_future(self: Arc<Self>) {
+        loop {
+            self.wal_sync_notify.notified().await;
+            if !self.config.wal_sync_delay.is_zero() {
+                self.config.clock.sleep(self.config.wal_sync_delay).await;
+            }
+
+            let (ticket, synced_length) = {
+                let guard = self.state.lock();
+                let Some(ticket) = guard.sm.wal().begin_sync() else {
+                    continue;
+                };
+                (ticket, guard.sm.wal().synced_len())
+            };
+            metrics::WAL_SYNC_BATCH.observe((ticket.length() - synced_length) as f64);
+            let res = task::spawn_blocking(move || {
+                let timer = metrics::WAL_SYNC_LATENCY.start_timer();
+                let res = ticket.sync();
+                timer.observe_duration();
+                res.map(|_| ticket)
+            })
+            .await
+            .unwrap();
+            // NB : the entries of a failed fsync may be lost, and no later fsync can tell,
+            // so the node stops instead of waiting for a sync which never completes
+            let ticket = match res {
+                Ok(ticket) => ticket,
+                Err(e) => {
+                    error!("Failed to sync WAL : {}", e);
+                    std::process::abort();
+                }
+            };
+
+            let mut guard = self.state.lock();
+            if guard.sm.finish_sync(&ticket) {
+                debug!("WAL synced up to {}", ticket.length());
+                self.synced_length_tx
+                    .send_replace(guard.sm.wal().synced_len());
+                self.advance_commit(&mut guard);
+            } else {
+                // the log was truncated meanwhile, retry with the current log
+                self.wal_sync_notify.notify_one();
+            }
+        }
+    }
+
+    // Wait until the first len entries of the log are on stable storage.
+    async fn wait_synced(&self, len: u64) {
+        let mut rx = self.synced_length_tx.subscribe();
+        let _ = rx.wait_for(|&synced| synced >= len).await;
+    }
+
     fn reset_to_leader(self: &Arc<Self>, guard: &mut MutexGuard<RaftState>) {
+        let peers = guard.membership.peers(self.config.self_id);
         guard.role = Role::Leader(LeaderState {
             heartbeat_handle: AbortOnDropHandle::new(task::spawn(self.clone().heartbeat_future())),
-            prev_length: self
-                .config
-                .peers
-                .iter()
-                .map(|&p| (p, guard.sm.wal().len()))
-                .collect(),
-            match_length: self.config.peers.iter().map(|&p| (p, 0)).collect(),
+            prev_length: peers.iter().map(|&p| (p, guard.sm.wal().len())).collect(),
+            match_length: peers.iter().map(|&p| (p, 0)).collect(),
             commit_alarm: Vec::new(),
+            entry_spans: HashMap::new(),
+            transferring: false,
+            snapshot_transfers: HashMap::new(),
+        });
+    }
+
+    // Connect to the members without connection and start their peer futures.
+    // Called whenever the membership may have changed.
+    fn connect_members(self: &Arc<Self>, guard: &mut MutexGuard<RaftState>) {
+        let len = guard.sm.wal().len();
+        for peer in guard.membership.peers(self.config.self_id) {
+            if let Role::Leader(s) = &mut guard.role {
+                s.prev_length.entry(peer).or_insert(len);
+                s.match_length.entry(peer).or_insert(0);
+            }
+            if guard.connections.contains_key(peer) {
+                continue;
+            }
+            let channel: Channel = self
+                .channels
+                .lock()
+                .entry(peer)
+                .or_insert_with(|| {
+                    Endpoint::from_static(peer)
+                        .connect_timeout(self.config.rpc_timeout)
+                        .connect_lazy()
+                })
+                .clone();
+            guard.connections.insert(peer, RaftChatClient::new(channel));
+            let self_cloned = self.clone();
+            task::spawn(self_cloned.peer_future(peer));
+        }
+    }
+
+    // Leader only.
+    // A change of membership must wait for the previous one to be committed, and for an entry
+    // of the current term to be committed (raft dissertation 4.1).
+    // return the index of the membership entry which contains the member,
+    //        or the reason to retry later
+    fn propose_member(
+        self: &Arc<Self>,
+        guard: &mut MutexGuard<RaftState>,
+        member: &str,
+    ) -> Result<u64, &'static str> {
+        let current_term = guard.persistent_state.current_term();
+        if guard.membership.contains(member) {
+            return Ok(guard.membership.latest_index().unwrap_or(0));
+        }
+        if let Role::Leader(LeaderState {
+            transferring: true, ..
+        }) = guard.role
+        {
+            return Err("a leadership transfer is in progress");
+        }
+        if !guard.membership.is_committed(guard.committed_length) {
+            return Err("another membership change is in progress");
+        }
+        if guard.sm.wal().last_term_for(guard.committed_length) != current_term {
+            if guard.sm.wal().last_term() != current_term {
+                let time = guard.sm.log_time(self.config.clock.system_time());
+                guard.sm.propose_entry(Entry {
+                    term: current_term,
+                    command: None,
+                    membership: None,
+                    time,
+                    session: None,
+                });
+                self.propose_tx.send_replace(());
+                self.wal_sync_notify.notify_one();
+            }
+            return Err("no entry of the current term is committed yet");
+        }
+
+        let mut members: Vec<String> = guard
+            .membership
+            .members()
+            .iter()
+            .map(|&m| String::from(m))
+            .collect();
+        members.push(String::from(member));
+        info!("add member {} : {}", member, members.join(","));
+        let time = guard.sm.log_time(self.config.clock.system_time());
+        let index = guard.sm.propose_entry(Entry {
+            term: current_term,
+            command: None,
+            membership: Some(raftchat_tonic::Membership { members }),
+            time,
+            session: None,
         });
+        let RaftState { sm, membership, .. } = &mut **guard;
+        membership.sync(sm.wal().as_slice(), index);
+        self.connect_members(guard);
+        self.propose_tx.send_replace(());
+        self.wal_sync_notify.notify_one();
+        Ok(index)
     }
 
     fn reset_to_candidate(self: &Arc<Self>, guard: &mut MutexGuard<RaftState>) {
@@ -301,70 +778,479 @@ impl MyRaftChat {
         current_leader: Option<&'static str>,
     ) {
         guard.role = Role::Follower(FollowerState {
-            current_leader: current_leader,
+            current_leader,
             timeout_handle: AbortOnDropHandle::new(task::spawn(self.clone().timeout_future())),
         });
     }
 
+    // handling user request
+    // - follower => forward to leader and return result.
+    // - candidate => retuurn false
+    // - leader => propose new entry, wait until committed and return result.
+    async fn handle_user_request(
+        &self,
+        mut request: Request<UserRequestArgs>,
+    ) -> Result<Response<UserRequestRes>, Status> {
+        let mut client;
+        let future: Pin<
+            Box<dyn Send + std::future::Future<Output = Result<Response<UserRequestRes>, Status>>>,
+        > = {
+            let mut guard = self.state.lock();
+
+            match &mut *guard {
+                RaftState {
+                    role: Role::Leader(leader_state),
+                    sm,
+                    persistent_state,
+                    committed_length,
+                    ..
+                } => {
+                    let args = request.into_inner();
+
+                    // NB : the target of a transfer must catch up with a log which stops growing
+                    if leader_state.transferring {
+                        return Err(Status::unavailable(
+                            "leadership transfer in progress, retry later",
+                        ));
+                    }
+
+                    // 0. backpressure : bound the uncommitted entries and their alarms
+                    let max_pending = self.config.max_pending;
+                    if sm.wal().len() - *committed_length >= max_pending as u64
+                        || leader_state.commit_alarm.len() >= max_pending
+                    {
+                        metrics::OVERLOADED_REQUESTS.inc();
+                        return Err(overloaded());
+                    }
+
+                    // 1. blocking
+                    // the first message of a client registers its session, and a retry of
+                    // the last request of a session gets the result of the first attempt
+                    let time = sm.log_time(self.config.clock.system_time());
+                    let retry = match sm.state().last_request(&args.client_id, time) {
+                        Some(last) if last.message_id > 0 && last.message_id == args.message_id => {
+                            if last.index < *committed_length {
+                                return Ok(Response::new(UserRequestRes {
+                                    success: true,
+                                    index: last.index,
+                                }));
+                            }
+                            // in flight : wait for the commit of the first attempt
+                            Some(last.index)
+                        }
+                        Some(last) if last.message_id + 1 == args.message_id => None,
+                        Some(_) => {
+                            return Ok(Response::new(UserRequestRes {
+                                success: false,
+                                index: 0,
+                            }));
+                        }
+                        None if args.message_id == 1 => {
+                            // NB : the session entry takes one more slot
+                            if sm.wal().len() + 1 - *committed_length >= max_pending as u64 {
+                                metrics::OVERLOADED_REQUESTS.inc();
+                                return Err(overloaded());
+                            }
+                            sm.propose_entry(Entry {
+                                term: persistent_state.current_term(),
+                                command: None,
+                                membership: None,
+                                time,
+                                session: Some(Session {
+                                    client_id: args.client_id.clone(),
+                                    timeout_ms: self.config.session_timeout.as_millis() as u64,
+                                }),
+                            });
+                            None
+                        }
+                        None => {
+                            return Err(Status::failed_precondition(format!(
+                                "session of client {} expired or unknown, \
+                                 start a new session from message id 1",
+                                args.client_id
+                            )));
+                        }
+                    };
+
+                    // 2. append log in wal
+                    let proposed_idx = match retry {
+                        Some(index) => index,
+                        None => sm.propose_entry(Entry {
+                            term: persistent_state.current_term(),
+                            command: Some(Command {
+                                client_id: args.client_id,
+                                message_id: args.message_id,
+                                data: args.data,
+                                stripped: false,
+                            }),
+                            membership: None,
+                            time,
+                            session: None,
+                        }),
+                    };
+
+                    // 3. append channel raft state
+                    let (tx, rx) = oneshot::channel();
+                    leader_state.commit_alarm.push((proposed_idx, tx));
+                    leader_state
+                        .entry_spans
+                        .entry(proposed_idx)
+                        .or_insert_with(Span::current);
+                    drop(guard);
+
+                    // 4. call commit func - notify peer futures and WAL sync
+                    self.propose_tx.send_replace(());
+                    self.wal_sync_notify.notify_one();
+
+                    // 5. waiting commit
+                    let timer = metrics::COMMIT_LATENCY.start_timer();
+                    Box::pin(async move {
+                        match rx.await {
+                            Ok(true) => {
+                                timer.observe_duration();
+                                Ok(Response::new(UserRequestRes {
+                                    success: true,
+                                    index: proposed_idx,
+                                }))
+                            }
+                            Ok(false) | Err(_) => Ok(Response::new(UserRequestRes {
+                                success: false,
+                                index: 0,
+                            })),
+                        }
+                    })
+                }
+                RaftState {
+                    role: Role::Follower(follower_state),
+                    ..
+                } => {
+                    if let Some(leader_id) = follower_state.current_leader {
+                        client = guard
+                            .connections
+                            .get(leader_id)
+                            .expect("Get connection failed : follower don't know who's the leader")
+                            .clone();
+
+                        drop(guard);
+
+                        trace_context::inject(&Span::current(), &mut request);
+                        Box::pin(async move { client.user_request(request).await })
+                    } else {
+                        return Ok(Response::new(UserRequestRes {
+                            success: false,
+                            index: 0,
+                        }));
+                    }
+                }
+                RaftState {
+                    role: Role::Candidate(_),
+                    ..
+                } => {
+                    return Ok(Response::new(UserRequestRes {
+                        success: false,
+                        index: 0,
+                    }));
+                }
+            }
+        };
+
+        future.await
+    }
+
     // receive request from web server
-    pub fn user_request_thread(self: Arc<Self>, mut req_rx: mpsc::Receiver<UserRequestArgs>) {
-        while let Some(args) = req_rx.blocking_recv() {
+    // the span of each request is the parent of its trace in raft
+    pub async fn user_request_future(self: Arc<Self>, mut req_rx: mpsc::Receiver<UserRequest>) {
+        // requests in flight on this node, waiting for their commit
+        let in_flight = Arc::new(Semaphore::new(self.config.max_pending));
+        while let Some(UserRequest {
+            args,
+            span,
+            result_tx,
+        }) = req_rx.recv().await
+        {
+            let Ok(permit) = in_flight.clone().try_acquire_owned() else {
+                metrics::OVERLOADED_REQUESTS.inc();
+                let _ = result_tx.send(Err(overloaded()));
+                continue;
+            };
             let self_cloned = self.clone();
-            task::spawn(async move { self_cloned.user_request(Request::new(args)).await });
+            let mut request = Box::pin(
+                async move {
+                    let res = self_cloned
+                        .user_request(Request::new(args))
+                        .await
+                        .map(Response::into_inner);
+                    drop(permit);
+                    match &res {
+                        Ok(res) => {
+                            debug!("user request success={} index={}", res.success, res.index)
+                        }
+                        Err(status) if status.code() == Code::ResourceExhausted => {
+                            debug!("user request rejected : {}", status.message())
+                        }
+                        Err(status) => warn!("user request rejected : {}", status.message()),
+                    }
+                    let _ = result_tx.send(res);
+                }
+                .instrument(span),
+            );
+            // NB : the entry is proposed at the first poll, so the messages of a client are
+            // proposed in the order they were sent
+            if poll_fn(|cx| Poll::Ready(request.as_mut().poll(cx)))
+                .await
+                .is_pending()
+            {
+                task::spawn(request);
+            }
         }
     }
 
     // publish committed log to web server
-    pub fn publisher_thread(self: Arc<Self>, log_tx: mpsc::Sender<Entry>) {
+    pub async fn publisher_future(self: Arc<Self>, log_tx: mpsc::Sender<Entry>) {
+        if self.is_witness(self.config.self_id) {
+            return;
+        }
+        let mut committed_rx = self.committed_length_tx.subscribe();
         let mut sent_length: usize = 0;
-        let mut guard = self.state.lock();
         loop {
-            let committed_length = guard.committed_length as usize;
-            if sent_length < committed_length {
-                let entries = guard.sm.wal().as_slice()[sent_length..committed_length].to_vec();
-                drop(guard);
-                for entry in entries {
-                    log_tx.blocking_send(entry).unwrap();
+            let entries = {
+                let guard = self.state.lock();
+                guard.sm.wal().as_slice()[sent_length..guard.committed_length as usize].to_vec()
+            };
+            if entries.is_empty() {
+                let _ = committed_rx.wait_for(|&l| l as usize > sent_length).await;
+                continue;
+            }
+            for entry in entries {
+                let span = info_span!(
+                    "publish",
+                    index = sent_length,
+                    client_id = tracing::field::Empty,
+                    message_id = tracing::field::Empty
+                );
+                if let Some(cmd) = &entry.command {
+                    span.record("client_id", cmd.client_id.as_str());
+                    span.record("message_id", cmd.message_id);
                 }
-                sent_length = committed_length;
-                guard = self.state.lock();
-            } else {
-                self.committed_length_cvar.wait(&mut guard);
+                log_tx.send(entry).instrument(span).await.unwrap();
+                sent_length += 1;
             }
         }
     }
 
-    pub fn peer_thread(self: Arc<Self>, peer: &'static str) {
-        let mut guard: parking_lot::lock_api::MutexGuard<'_, parking_lot::RawMutex, RaftState> =
-            self.state.lock();
-        'LOOP: loop {
-            if let Role::Leader(s) = &guard.role {
-                if s.match_length[peer] < guard.sm.wal().len() {
-                    let client = guard.connections[peer].clone();
-                    let prev_length = s.prev_length[peer];
-                    let entries_len = if prev_length < guard.sm.wal().len() {
-                        1
-                    } else {
-                        0
-                    };
-                    let args = AppendEntriesArgs {
-                        term: guard.persistent_state.current_term(),
-                        leader_id: String::from(self.config.self_id),
-                        prev_length: prev_length,
-                        prev_term: guard.sm.wal().last_term_for(prev_length),
-                        entries: guard.sm.wal().as_slice()
-                            [prev_length as usize..prev_length as usize + entries_len]
-                            .to_vec(),
-                        committed_length: guard.committed_length,
+    // Write a snapshot of the committed log, and return its length.
+    pub async fn write_snapshot(self: &Arc<Self>) -> io::Result<u64> {
+        let _lock = self.snapshot_lock.lock().await;
+        let entries = {
+            let guard = self.state.lock();
+            match &guard.snapshot {
+                Some(snapshot) if snapshot.length >= guard.committed_length => {
+                    return Ok(snapshot.length);
+                }
+                _ => guard.sm.wal().as_slice()[..guard.committed_length as usize].to_vec(),
+            }
+        };
+        let snapshot = task::spawn_blocking(move || Snapshot::new(&entries))
+            .await
+            .unwrap();
+        let length = snapshot.length;
+        self.save_snapshot(snapshot).await?;
+        info!("snapshot written at {}", length);
+        Ok(length)
+    }
+
+    // Save the snapshot as the latest one of this node.
+    // NB : called with the snapshot lock
+    async fn save_snapshot(&self, snapshot: Snapshot) -> io::Result<()> {
+        let path = self.config.snapshot_path;
+        let memory = self.config.storage_backend == StorageBackend::Memory;
+        let keyring = self.config.keyring.clone();
+        let snapshot = task::spawn_blocking(move || {
+            if !memory {
+                snapshot.save(path, keyring.as_deref())?;
+            }
+            Ok::<_, io::Error>(snapshot)
+        })
+        .await
+        .unwrap()?;
+        self.state.lock().snapshot = Some(Arc::new(snapshot));
+        Ok(())
+    }
+
+    // Leader only.
+    // the next append entries or snapshot chunk to send to the peer
+    fn peer_step(&self, guard: &mut RaftState, peer: &'static str) -> PeerStep {
+        let clock = &self.config.clock;
+        let limit = self.send_limit(guard, peer);
+        // NB : a probe of the log of the peer failed within the snapshot, which sends the
+        // missing entries at once instead of probing one entry per round trip. A witness only
+        // takes the metadata of the entries.
+        if let (Role::Leader(s), Some(snapshot)) = (&mut guard.role, &guard.snapshot) {
+            let probing = |&l: &u64| s.match_length[peer] < l && l < snapshot.length;
+            if s.prev_length.get(peer).is_some_and(probing) && !self.is_witness(peer) {
+                s.snapshot_transfers
+                    .entry(peer)
+                    .or_insert_with(|| (snapshot.clone(), None));
+            }
+        }
+        let guard = &*guard;
+        let Role::Leader(s) = &guard.role else {
+            return PeerStep::Wait;
+        };
+        if s.match_length.get(peer).is_none_or(|&l| l >= limit) {
+            return PeerStep::Wait;
+        }
+        if let Some(retry_at) = guard
+            .peer_health
+            .get(peer)
+            .and_then(|health| health.retry_at)
+            .filter(|&retry_at| retry_at > clock.now())
+        {
+            return PeerStep::Retry(retry_at.saturating_duration_since(clock.now()));
+        }
+        let client = guard.connections[peer].clone();
+        if let Some((snapshot, offset)) = s.snapshot_transfers.get(peer) {
+            // NB : an empty chunk at 0 asks the peer where to resume
+            let data = offset.map_or(vec![], |offset| {
+                snapshot.chunk(offset, self.config.max_chunk_bytes).to_vec()
+            });
+            let offset = offset.unwrap_or(0);
+            let args = InstallSnapshotArgs {
+                term: guard.persistent_state.current_term(),
+                leader_id: String::from(self.config.self_id),
+                group_id: self.config.group_id,
+                length: snapshot.length,
+                last_term: snapshot.last_term,
+                size: snapshot.image.len() as u64,
+                snapshot_checksum: snapshot.checksum,
+                offset,
+                checksum: crc32fast::hash(&data),
+                data,
+            };
+            let span = info_span!("install_snapshot", peer, offset);
+            return PeerStep::Install { client, args, span };
+        }
+        let prev_length = s.prev_length[peer];
+        let rest =
+            &guard.sm.wal().as_slice()[prev_length as usize..limit.max(prev_length) as usize];
+        let (entries_len, bytes) = catch_up::chunk(rest, self.config.max_chunk_bytes);
+        // NB : entries committed without the peer are not needed by live commits
+        let catching_up = prev_length + (entries_len as u64) <= guard.committed_length;
+        let entries = &rest[..entries_len];
+        let entries = if self.is_witness(peer) {
+            entries.iter().map(witness::metadata_only).collect()
+        } else {
+            entries.to_vec()
+        };
+        let args = AppendEntriesArgs {
+            term: guard.persistent_state.current_term(),
+            leader_id: String::from(self.config.self_id),
+            prev_length,
+            prev_term: guard.sm.wal().last_term_for(prev_length),
+            entries,
+            committed_length: guard.committed_length,
+            group_id: self.config.group_id,
+            rtt_us: guard
+                .peer_rtt
+                .get(peer)
+                .map_or(0, |rtt| rtt.as_micros() as u64),
+        };
+        let span = info_span!(
+            parent: s.entry_spans.get(&prev_length).and_then(|span| span.id()),
+            "replicate",
+            peer,
+            prev_length,
+            entries = entries_len
+        );
+        PeerStep::Send {
+            client,
+            args,
+            span,
+            catch_up_bytes: catching_up.then_some(bytes),
+        }
+    }
+
+    pub async fn peer_future(self: Arc<Self>, peer: &'static str) {
+        let clock = self.config.clock.clone();
+        let mut throttle = catch_up::Throttle::new(self.config.catch_up_rate, clock.now());
+        let mut propose_rx = self.propose_tx.subscribe();
+        loop {
+            // NB : marked as seen before the check, so a proposal during an RPC is not missed
+            propose_rx.borrow_and_update();
+            let step = self.peer_step(&mut self.state.lock(), peer);
+            match step {
+                PeerStep::Wait => {
+                    let _ = propose_rx.changed().await;
+                }
+                PeerStep::Retry(delay) => clock.sleep(delay).await,
+                PeerStep::Send {
+                    client,
+                    args,
+                    span,
+                    catch_up_bytes,
+                } => {
+                    let delay = match catch_up_bytes {
+                        Some(bytes) => {
+                            metrics::CATCH_UP_BYTES
+                                .with_label_values(&[peer])
+                                .inc_by(bytes as u64);
+                            throttle.take(bytes, clock.now())
+                        }
+                        None => Duration::ZERO,
                     };
-                    drop(guard);
-                    tokio::runtime::Handle::current()
-                        .block_on(self.clone().append_entries_future(peer, client, args));
-                    guard = self.state.lock();
-                    continue 'LOOP;
+                    async {
+                        clock.sleep(delay).await;
+                        self.clone().append_entries_future(peer, client, args).await
+                    }
+                    .instrument(span)
+                    .await;
+                }
+                PeerStep::Install { client, args, span } => {
+                    let bytes = args.data.len();
+                    metrics::CATCH_UP_BYTES
+                        .with_label_values(&[peer])
+                        .inc_by(bytes as u64);
+                    let delay = throttle.take(bytes, clock.now());
+                    async {
+                        clock.sleep(delay).await;
+                        self.clone()
+                            .install_snapshot_future(peer, client, args)
+                            .await
+                    }
+                    .instrument(span)
+                    .await;
                 }
             }
-            self.propose_cvar.wait(&mut guard);
+        }
+    }
+
+    // Follower only.
+    // NB : a truncation lowers the synced length, and the entries written over the truncated
+    // ones must not be acked before they are synced again
+    fn lower_synced_length(&self, guard: &RaftState) {
+        let synced_length = guard.sm.wal().synced_len();
+        self.synced_length_tx.send_if_modified(|synced| {
+            let lowered = synced_length < *synced;
+            *synced = (*synced).min(synced_length);
+            lowered
+        });
+    }
+
+    // Follower only.
+    // Apply the membership entries appended from prev_length, and find the leader among the
+    // members.
+    fn sync_membership(
+        self: &Arc<Self>,
+        guard: &mut MutexGuard<RaftState>,
+        leader_id: &str,
+        prev_length: u64,
+    ) {
+        let RaftState { sm, membership, .. } = &mut **guard;
+        membership.sync(sm.wal().as_slice(), prev_length);
+        self.connect_members(guard);
+        let leader_id = guard.membership.member(leader_id);
+        if let Role::Follower(s) = &mut guard.role {
+            s.current_leader = leader_id;
         }
     }
 }
@@ -375,47 +1261,159 @@ impl RaftChat for Arc<MyRaftChat> {
         &self,
         request: Request<AppendEntriesArgs>,
     ) -> Result<Response<AppendEntriesRes>, Status> {
+        let span = info_span!(
+            "append_entries",
+            prev_length = request.get_ref().prev_length,
+            entries = request.get_ref().entries.len()
+        );
+        trace_context::set_remote_parent(&span, &request);
         let args: AppendEntriesArgs = request.into_inner();
-        if args.entries.len() > 0 {
+        if !args.entries.is_empty() {
             info!("non empty append entries received");
         }
-        let mut guard = self.state.lock();
-        let (current_term, ok) = guard.persistent_state.update_term(args.term);
-        if !ok {
-            return Ok(Response::new(AppendEntriesRes {
-                term: current_term,
-                success: false,
-            }));
-        }
-        let Some(leader_id) = self.config.get_peer(&args.leader_id) else {
-            unimplemented!();
+        let (current_term, compatible_length) = {
+            let _enter = span.enter();
+            let mut guard = self.state.lock();
+            let (current_term, ok) = guard.persistent_state.update_term(args.term);
+            if !ok {
+                return Ok(Response::new(AppendEntriesRes {
+                    term: current_term,
+                    success: false,
+                }));
+            }
+            // NB : a joining node knows the leader from the membership entries it is sent
+            let leader_id = guard.membership.member(&args.leader_id);
+            if args.rtt_us > 0 {
+                self.leader_rtt_us.store(args.rtt_us, Ordering::Relaxed);
+            }
+            self.reset_to_follower(&mut guard, leader_id);
+            guard.leader_committed_length = args.committed_length;
+
+            match guard
+                .sm
+                .append_entries(args.prev_length, args.prev_term, &args.entries)
+            {
+                None => {
+                    return Ok(Response::new(AppendEntriesRes {
+                        term: current_term,
+                        success: false,
+                    }))
+                }
+                Some(compatible_length) => {
+                    self.lower_synced_length(&guard);
+                    if !args.entries.is_empty() {
+                        // NB : the leader takes this node for a witness
+                        if args.entries.iter().any(witness::is_stripped) {
+                            guard.log_stripped = true;
+                        }
+                        self.sync_membership(&mut guard, &args.leader_id, args.prev_length);
+                    }
+                    let l = max(
+                        guard.committed_length,
+                        min(args.committed_length, compatible_length),
+                    );
+                    guard.committed_length = l;
+                    if l >= args.committed_length {
+                        guard.fresh_at = Some(self.config.clock.now());
+                    }
+                    self.committed_length_tx.send_replace(l);
+                    guard.sm.take_snapshot(l);
+                    (current_term, compatible_length)
+                }
+            }
         };
-        self.reset_to_follower(&mut guard, Some(leader_id));
 
-        match guard
-            .sm
-            .append_entries(args.prev_length, args.prev_term, &args.entries)
-        {
-            None => Ok(Response::new(AppendEntriesRes {
-                term: current_term,
-                success: false,
-            })),
-            Some(compatible_length) => {
-                let l = max(
-                    guard.committed_length,
-                    min(args.committed_length, compatible_length),
-                );
-                guard.committed_length = l;
-                guard.sm.take_snapshot(l);
-                drop(guard);
-                self.committed_length_cvar.notify_one();
+        // NB : success must not be replied before the entries are on stable storage
+        self.wal_sync_notify.notify_one();
+        self.wait_synced(compatible_length).instrument(span).await;
+
+        Ok(Response::new(AppendEntriesRes {
+            term: current_term,
+            success: true,
+        }))
+    }
+
+    // Receive a chunk of the snapshot of the leader. Once complete, the entries of the snapshot
+    // are appended from the start of the log, as with append entries : they are committed, so
+    // only the uncommitted entries which conflict with them are truncated.
+    async fn install_snapshot(
+        &self,
+        request: Request<InstallSnapshotArgs>,
+    ) -> Result<Response<InstallSnapshotRes>, Status> {
+        let args = request.into_inner();
+        if self.is_witness(self.config.self_id) {
+            return Err(witness_has_no_history());
+        }
+        let (current_term, incoming) = {
+            let mut guard = self.state.lock();
+            let (current_term, ok) = guard.persistent_state.update_term(args.term);
+            if !ok {
+                return Ok(Response::new(InstallSnapshotRes {
+                    term: current_term,
+                    next_offset: 0,
+                }));
+            }
+            let leader_id = guard.membership.member(&args.leader_id);
+            self.reset_to_follower(&mut guard, leader_id);
+            let mut incoming = match guard.incoming_snapshot.take() {
+                Some(incoming) if incoming.matches(&args) => incoming,
+                _ => Incoming::new(&args),
+            };
+            let next_offset = incoming.receive(args.offset, &args.data, args.checksum);
+            if !incoming.is_complete() {
+                guard.incoming_snapshot = Some(incoming);
+                return Ok(Response::new(InstallSnapshotRes {
+                    term: current_term,
+                    next_offset,
+                }));
+            }
+            (current_term, incoming)
+        };
 
-                Ok(Response::new(AppendEntriesRes {
+        let (snapshot, entries) = match task::spawn_blocking(|| incoming.finish()).await.unwrap() {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("Drop the snapshot sent by {} : {}", args.leader_id, e);
+                return Ok(Response::new(InstallSnapshotRes {
                     term: current_term,
-                    success: true,
-                }))
+                    next_offset: 0,
+                }));
+            }
+        };
+        let length = snapshot.length;
+        let compatible_length = {
+            let mut guard = self.state.lock();
+            let compatible_length = guard
+                .sm
+                .append_entries(0, 0, &entries)
+                .expect("entries appended from 0");
+            self.lower_synced_length(&guard);
+            self.sync_membership(&mut guard, &args.leader_id, 0);
+            let l = max(guard.committed_length, length);
+            guard.committed_length = l;
+            self.committed_length_tx.send_replace(l);
+            guard.sm.take_snapshot(l);
+            compatible_length
+        };
+        info!("snapshot of {} entries installed", length);
+        self.wal_sync_notify.notify_one();
+
+        {
+            let _lock = self.snapshot_lock.lock().await;
+            let newer = (self.state.lock().snapshot.as_ref()).is_some_and(|s| s.length >= length);
+            if !newer {
+                if let Err(e) = self.save_snapshot(snapshot).await {
+                    warn!("Failed to save the installed snapshot : {}", e);
+                }
             }
         }
+
+        // NB : the leader takes the entries for replicated once acked
+        self.wait_synced(compatible_length).await;
+        Ok(Response::new(InstallSnapshotRes {
+            term: current_term,
+            next_offset: args.size,
+        }))
     }
 
     async fn request_vote(
@@ -434,14 +1432,14 @@ impl RaftChat for Arc<MyRaftChat> {
             }));
         }
         self.reset_to_follower(&mut guard, None);
-        let Some(candidate_id) = self.config.get_peer(&args.candidate_id) else {
-            unimplemented!();
-        };
         let ok = guard
             .sm
             .wal()
             .fresher_or_eq(args.prev_term, args.prev_length)
-            && guard.persistent_state.try_vote(candidate_id);
+            && guard
+                .membership
+                .member(&args.candidate_id)
+                .is_some_and(|candidate_id| guard.persistent_state.try_vote(candidate_id));
         if !ok {
             return Ok(Response::new(RequestVoteRes {
                 term: current_term,
@@ -454,157 +1452,288 @@ impl RaftChat for Arc<MyRaftChat> {
         }))
     }
 
-    // handling user request
-    // - follower => forward to leader and return result.
-    // - candidate => retuurn false
-    // - leader => propose new entry, wait until committed and return result.
-    async fn user_request(
-        &self,
-        request: Request<UserRequestArgs>,
-    ) -> Result<Response<UserRequestRes>, Status> {
-        let mut client;
-        let future: Pin<
-            Box<dyn Send + std::future::Future<Output = Result<Response<UserRequestRes>, Status>>>,
-        > = {
-            let mut guard = self.state.lock();
+    type BackupStream = Pin<Box<dyn Stream<Item = Result<Entry, Status>> + Send>>;
 
-            match &mut *guard {
-                RaftState {
-                    role: Role::Leader(leader_state),
-                    sm,
-                    persistent_state,
-                    ..
-                } => {
-                    let args = request.into_inner();
-
-                    // 1. blocking
-                    match sm.state().get(&args.client_id) {
-                        Some(message_id) => {
-                            if message_id + 1 != args.message_id {
-                                return Ok(Response::new(UserRequestRes { success: false }));
-                            }
-                        }
-                        None => {
-                            if args.message_id != 1 {
-                                return Ok(Response::new(UserRequestRes { success: false }));
-                            }
-                        }
-                    };
-
-                    // 2. append log in wal
-                    let proposed_idx = sm.propose_entry(Entry {
-                        term: persistent_state.current_term(),
-                        command: Some(Command {
-                            client_id: args.client_id,
-                            message_id: args.message_id,
-                            data: args.data,
-                        }),
-                    });
-
-                    // 3. append channel raft state
-                    let (tx, rx) = oneshot::channel();
-                    leader_state.commit_alarm.push((proposed_idx, tx));
-                    drop(guard);
-
-                    // 4. call commit func - notify peer threads
-                    self.propose_cvar.notify_all();
-
-                    // 5. waiting commit
-                    Box::pin(async {
-                        match rx.await {
-                            Ok(true) => return Ok(Response::new(UserRequestRes { success: true })),
-                            Ok(false) => {
-                                return Ok(Response::new(UserRequestRes { success: false }))
-                            }
-                            Err(_) => return Ok(Response::new(UserRequestRes { success: false })),
-                        }
-                    })
-                }
-                RaftState {
-                    role: Role::Follower(follower_state),
-                    ..
-                } => {
-                    if let Some(leader_id) = follower_state.current_leader {
-                        client = guard
-                            .connections
-                            .get(leader_id)
-                            .expect("Get connection failed : follower don't know who's the leader")
-                            .clone();
+    // Stream every committed entry, as of the time of the request.
+    // The committed prefix of the log never changes, so it is a consistent snapshot of the
+    // chat history and can be streamed without holding the lock.
+    async fn backup(
+        &self,
+        _request: Request<BackupArgs>,
+    ) -> Result<Response<Self::BackupStream>, Status> {
+        if self.is_witness(self.config.self_id) {
+            return Err(witness_has_no_history());
+        }
+        let entries = {
+            let guard = self.state.lock();
+            guard.sm.wal().as_slice()[..guard.committed_length as usize].to_vec()
+        };
+        info!("backup {} committed entries", entries.len());
+        Ok(Response::new(Box::pin(tokio_stream::iter(
+            entries.into_iter().map(Ok),
+        ))))
+    }
 
-                        drop(guard);
+    // Start an election at once, on request of the current leader (leadership transfer).
+    async fn timeout_now(
+        &self,
+        request: Request<TimeoutNowArgs>,
+    ) -> Result<Response<TimeoutNowRes>, Status> {
+        let args = request.into_inner();
+        let mut guard = self.state.lock();
+        if args.term != guard.persistent_state.current_term()
+            || !matches!(guard.role, Role::Follower(_))
+            || self.is_witness(self.config.self_id)
+            || guard.log_stripped
+        {
+            return Ok(Response::new(TimeoutNowRes { success: false }));
+        }
+        info!("election requested by {}", args.leader_id);
+        guard.persistent_state.start_election(self.config.self_id);
+        self.reset_to_candidate(&mut guard);
+        Ok(Response::new(TimeoutNowRes { success: true }))
+    }
 
-                        Box::pin(async {
-                            let res = client.user_request(request).await;
-                            res
-                        })
-                    } else {
-                        return Ok(Response::new(UserRequestRes { success: false }));
-                    }
+    // Add a node to the cluster, and reply when the membership entry is committed.
+    async fn add_member(
+        &self,
+        request: Request<AddMemberArgs>,
+    ) -> Result<Response<AddMemberRes>, Status> {
+        let args = request.into_inner();
+        let (rx, members) = {
+            let mut guard = self.state.lock();
+            match &guard.role {
+                Role::Leader(_) => {}
+                Role::Follower(s) => {
+                    return Ok(Response::new(AddMemberRes {
+                        success: false,
+                        leader_id: s.current_leader.map(String::from),
+                        members: vec![],
+                    }))
                 }
-                RaftState {
-                    role: Role::Candidate(_),
-                    ..
-                } => {
-                    return Ok(Response::new(UserRequestRes { success: false }));
+                Role::Candidate(_) => {
+                    return Ok(Response::new(AddMemberRes {
+                        success: false,
+                        leader_id: None,
+                        members: vec![],
+                    }))
                 }
             }
+            let index = self
+                .propose_member(&mut guard, &args.member_id)
+                .map_err(Status::unavailable)?;
+            let members: Vec<String> = guard
+                .membership
+                .members()
+                .iter()
+                .map(|&m| String::from(m))
+                .collect();
+            if guard.membership.is_committed(guard.committed_length) {
+                return Ok(Response::new(AddMemberRes {
+                    success: true,
+                    leader_id: Some(String::from(self.config.self_id)),
+                    members,
+                }));
+            }
+            let (tx, rx) = oneshot::channel();
+            if let Role::Leader(s) = &mut guard.role {
+                s.commit_alarm.push((index, tx));
+            }
+            (rx, members)
         };
 
-        future.await
+        match rx.await {
+            Ok(true) => Ok(Response::new(AddMemberRes {
+                success: true,
+                leader_id: Some(String::from(self.config.self_id)),
+                members,
+            })),
+            _ => Err(Status::aborted(
+                "lost leadership before the change is committed",
+            )),
+        }
+    }
+
+    async fn user_request(
+        &self,
+        request: Request<UserRequestArgs>,
+    ) -> Result<Response<UserRequestRes>, Status> {
+        let args = request.get_ref();
+        let span = info_span!(
+            "user_request",
+            client_id = %args.client_id,
+            message_id = args.message_id
+        );
+        trace_context::set_remote_parent(&span, &request);
+        self.handle_user_request(request).instrument(span).await
     }
 }
 
-pub fn run_raft(
+// The snapshot saved by the node, if it is a prefix of its log.
+fn load_snapshot(config: &RaftConfig, log: &[Entry]) -> Option<Snapshot> {
+    if config.storage_backend == StorageBackend::Memory {
+        return None;
+    }
+    let keyring = config.keyring.as_deref();
+    match Snapshot::load(config.snapshot_path, keyring) {
+        Ok(None) => None,
+        Ok(Some((snapshot, entries, stale))) if log.starts_with(&entries) => {
+            if stale {
+                info!(
+                    "re-encrypt the snapshot {:?} with the current key",
+                    config.snapshot_path
+                );
+                if let Err(e) = snapshot.save(config.snapshot_path, keyring) {
+                    warn!("Failed to re-encrypt the snapshot : {}", e);
+                }
+            }
+            Some(snapshot)
+        }
+        // NB : the log has every entry, and the next snapshot replaces this one
+        Ok(Some(_)) => {
+            warn!(
+                "Ignore the snapshot {:?} : not a prefix of the log",
+                config.snapshot_path
+            );
+            None
+        }
+        Err(e) => {
+            warn!("Ignore the snapshot {:?} : {}", config.snapshot_path, e);
+            None
+        }
+    }
+}
+
+// Start a raft group, without RPC server.
+fn start_group(
     config: RaftConfig,
+    channels: Channels,
     log_tx: mpsc::Sender<Entry>,
-    req_rx: mpsc::Receiver<UserRequestArgs>,
-) {
-    let serve_addr = config.serve_addr;
-    let persistent_state_path = config.persistent_state_path;
-    let wal_path = config.wal_path;
+    req_rx: mpsc::Receiver<UserRequest>,
+) -> Arc<MyRaftChat> {
+    let persistent_state = PersistentState::new(
+        storage::open_stable_store(config.storage_backend, config.persistent_state_path)
+            .expect("Failed to open persistent state"),
+    );
+    let wal = WAL::new(
+        storage::open_log_store(
+            config.storage_backend,
+            config.wal_path,
+            config.keyring.clone(),
+        )
+        .expect("Failed to open WAL"),
+    );
+    let synced_length = wal.synced_len();
+    let witness = config.witnesses.contains(config.self_id);
+    if let Err(e) = witness::check_log(witness, wal.as_slice()) {
+        panic!("Witness flag of {} changed : {}", config.self_id, e);
+    }
+    let log_stripped = wal.as_slice().iter().any(witness::is_stripped);
+    let snapshot = load_snapshot(&config, wal.as_slice()).map(Arc::new);
+    let membership = Membership::new(&config.members, wal.as_slice());
     let raft_chat = Arc::new(MyRaftChat {
-        config: config,
+        config,
         state: Mutex::new(RaftState {
-            persistent_state: PersistentState::new(persistent_state_path),
-            sm: SMWrapper::new(WAL::new(wal_path)),
+            persistent_state,
+            sm: SMWrapper::new(wal),
             committed_length: 0,
+            leader_committed_length: 0,
+            fresh_at: None,
             role: Role::Follower(FollowerState {
                 current_leader: None,
                 timeout_handle: AbortOnDropHandle::new(task::spawn(async {})),
             }),
+            membership,
             connections: HashMap::new(),
+            peer_rtt: HashMap::new(),
+            peer_health: HashMap::new(),
+            log_stripped,
+            snapshot,
+            incoming_snapshot: None,
         }),
-        committed_length_cvar: Condvar::new(),
-        propose_cvar: Condvar::new(),
+        propose_tx: watch::Sender::new(()),
+        wal_sync_notify: Notify::new(),
+        snapshot_lock: tokio::sync::Mutex::new(()),
+        synced_length_tx: watch::Sender::new(synced_length),
+        committed_length_tx: watch::Sender::new(0),
+        rpc_bound: AtomicBool::new(false),
+        leader_rtt_us: AtomicU64::new(0),
+        channels,
     });
 
-    for addr in raft_chat.config.peers.iter() {
-        let channel: Channel = Endpoint::from_static(addr).connect_lazy();
-        raft_chat
-            .state
-            .lock()
-            .connections
-            .insert(*addr, RaftChatClient::new(channel));
+    {
+        let mut guard = raft_chat.state.lock();
+        raft_chat.connect_members(&mut guard);
+        guard.role = Role::Follower(FollowerState {
+            current_leader: None,
+            timeout_handle: AbortOnDropHandle::new(task::spawn(raft_chat.clone().timeout_future())),
+        });
     }
 
-    raft_chat.state.lock().role = Role::Follower(FollowerState {
-        current_leader: None,
-        timeout_handle: AbortOnDropHandle::new(task::spawn(raft_chat.clone().timeout_future())),
-    });
+    task::spawn(raft_chat.clone().wal_sync_future());
 
-    let raft_chat_cloned = raft_chat.clone();
-    task::spawn_blocking(move || raft_chat_cloned.user_request_thread(req_rx));
+    task::spawn(raft_chat.clone().user_request_future(req_rx));
+    task::spawn(raft_chat.clone().publisher_future(log_tx));
 
-    let raft_chat_cloned = raft_chat.clone();
-    task::spawn_blocking(move || raft_chat_cloned.publisher_thread(log_tx));
+    raft_chat
+}
 
-    for peer in raft_chat.config.peers.iter().copied() {
-        let raft_chat_cloned: Arc<MyRaftChat> = raft_chat.clone();
-        task::spawn_blocking(move || raft_chat_cloned.peer_thread(peer));
-    }
+// config, channel of committed entries and channel of user requests of a raft group
+pub type RaftGroup = (RaftConfig, mpsc::Sender<Entry>, mpsc::Receiver<UserRequest>);
+
+// Run many raft groups in this process.
+// They share one RPC server, bound to the serve_addr of the first group, and the connections
+// to peer nodes.
+pub fn run_raft_groups(groups: Vec<RaftGroup>) -> Vec<Arc<MyRaftChat>> {
+    let serve_addr = groups.first().expect("no raft group").0.serve_addr;
+    let channels: Channels = Arc::new(Mutex::new(HashMap::new()));
+    let nodes: Vec<Arc<MyRaftChat>> = groups
+        .into_iter()
+        .map(|(config, log_tx, req_rx)| start_group(config, channels.clone(), log_tx, req_rx))
+        .collect();
 
+    let listener = match std::net::TcpListener::bind(serve_addr)
+        .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+        .and_then(TcpListener::from_std)
+    {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind raft rpc server to {} : {}", serve_addr, e);
+            return nodes;
+        }
+    };
+    let set_rpc_bound = |nodes: &[Arc<MyRaftChat>], bound: bool| {
+        for node in nodes {
+            node.rpc_bound.store(bound, Ordering::Relaxed);
+        }
+    };
+    set_rpc_bound(&nodes, true);
+
+    let router = Arc::new(MultiRaft::new(&nodes));
     let rpc_future = Server::builder()
-        .add_service(RaftChatServer::new(raft_chat))
-        .serve(serve_addr);
-    task::spawn(rpc_future);
+        .add_service(AdminServer::new(router.clone()))
+        .add_service(ChangeFeedServer::new(router.clone()))
+        .add_service(RaftChatServer::new(router))
+        .serve_with_incoming(TcpListenerStream::new(listener));
+    let nodes_cloned = nodes.clone();
+    task::spawn(async move {
+        if let Err(e) = rpc_future.await {
+            error!("raft rpc server stopped : {}", e);
+        }
+        set_rpc_bound(&nodes_cloned, false);
+    });
+
+    leadership::balance_leaders(nodes.clone());
+
+    nodes
+}
+
+pub fn run_raft(
+    config: RaftConfig,
+    log_tx: mpsc::Sender<Entry>,
+    req_rx: mpsc::Receiver<UserRequest>,
+) -> Arc<MyRaftChat> {
+    run_raft_groups(vec![(config, log_tx, req_rx)])
+        .pop()
+        .unwrap()
 }
@@ -0,0 +1,219 @@
+// Cluster membership
+// The configuration of the cluster is the one of the latest membership entry in the log,
+// committed or not, and the bootstrap one if there is none.
+// Members are added one at a time (single server changes), so that the majorities of the old
+// and the new configuration always overlap.
+
+use crate::raftchat_tonic::raft_chat_client::RaftChatClient;
+use crate::raftchat_tonic::{AddMemberArgs, Entry};
+use atomic_write_file::AtomicWriteFile;
+use log::{info, warn};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+use tokio::time;
+use tonic::Request;
+
+const JOIN_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Membership {
+    bootstrap: Vec<&'static str>,
+    // (index, members) of every membership entry in the log
+    changes: Vec<(u64, Vec<&'static str>)>,
+    // node ids live as long as the process, like the ones in RaftConfig
+    // NB : only the ids of the membership entries are interned, never the ones of an RPC
+    interned: HashSet<&'static str>,
+}
+
+impl Membership {
+    pub fn new(bootstrap: &[&'static str], entries: &[Entry]) -> Membership {
+        let mut membership = Membership {
+            bootstrap: bootstrap.to_vec(),
+            changes: Vec::new(),
+            interned: bootstrap.iter().copied().collect(),
+        };
+        membership.sync(entries, 0);
+        membership
+    }
+
+    fn intern(&mut self, id: &str) -> &'static str {
+        match self.interned.get(id) {
+            Some(&id) => id,
+            None => {
+                let id: &'static str = String::from(id).leak();
+                self.interned.insert(id);
+                id
+            }
+        }
+    }
+
+    // Entries of the log from index from on have changed.
+    pub fn sync(&mut self, entries: &[Entry], from: u64) {
+        self.changes.retain(|&(index, _)| index < from);
+        for (index, entry) in entries.iter().enumerate().skip(from as usize) {
+            if let Some(membership) = &entry.membership {
+                let members = membership
+                    .members
+                    .iter()
+                    .map(|member| self.intern(member))
+                    .collect();
+                self.changes.push((index as u64, members));
+            }
+        }
+    }
+
+    pub fn members(&self) -> &[&'static str] {
+        match self.changes.last() {
+            Some((_, members)) => members,
+            None => &self.bootstrap,
+        }
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.members().contains(&id)
+    }
+
+    // the id of a member, without allocating for an unknown one
+    pub fn member(&self, id: &str) -> Option<&'static str> {
+        self.members().iter().copied().find(|&member| member == id)
+    }
+
+    // members except self
+    pub fn peers(&self, self_id: &str) -> Vec<&'static str> {
+        self.members()
+            .iter()
+            .copied()
+            .filter(|&member| member != self_id)
+            .collect()
+    }
+
+    pub fn quorum_size(&self) -> usize {
+        (self.members().len() / 2) + 1
+    }
+
+    // index of the latest membership entry
+    pub fn latest_index(&self) -> Option<u64> {
+        self.changes.last().map(|&(index, _)| index)
+    }
+
+    pub fn is_committed(&self, committed_length: u64) -> bool {
+        self.latest_index()
+            .is_none_or(|index| index < committed_length)
+    }
+}
+
+// Identity of a node created by `server init` or `server join`.
+// The first line is the node id, and the second line is the bootstrap members.
+pub struct Identity {
+    pub node_id: String,
+    pub members: Vec<String>,
+}
+
+pub fn load_identity(path: &Path) -> io::Result<Option<Identity>> {
+    let s = match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut lines = s.lines();
+    let node_id = match lines.next() {
+        Some(id) if !id.is_empty() => String::from(id),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "malformed identity",
+            ))
+        }
+    };
+    let members = match lines.next() {
+        None | Some("") => vec![],
+        Some(members) => members.split(',').map(String::from).collect(),
+    };
+    Ok(Some(Identity { node_id, members }))
+}
+
+pub fn save_identity(path: &Path, identity: &Identity) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = AtomicWriteFile::options().open(path)?;
+    writeln!(file, "{}", identity.node_id)?;
+    writeln!(file, "{}", identity.members.join(","))?;
+    file.commit()
+}
+
+// Ask the raft group to add self_id, following the leader hints, until it succeeds.
+// addr is the RPC address of any member.
+pub async fn join(self_id: &'static str, group_id: u64, addr: String) {
+    let mut addr = addr;
+    loop {
+        let res = match RaftChatClient::connect(addr.clone()).await {
+            Ok(mut client) => client
+                .add_member(Request::new(AddMemberArgs {
+                    member_id: String::from(self_id),
+                    group_id,
+                }))
+                .await
+                .map(|res| res.into_inner())
+                .map_err(|status| status.message().to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match res {
+            Ok(res) if res.success => {
+                info!("joined raft group {} : {}", group_id, res.members.join(","));
+                return;
+            }
+            Ok(res) => match res.leader_id {
+                Some(leader_id) => {
+                    info!("{} is not the leader, retry with {}", addr, leader_id);
+                    addr = leader_id;
+                    continue;
+                }
+                None => warn!("{} does not know the leader", addr),
+            },
+            Err(e) => warn!("failed to join through {} : {}", addr, e),
+        }
+        time::sleep(JOIN_RETRY_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Membership;
+    use crate::raftchat_tonic::{Entry, Membership as MembershipEntry};
+
+    fn mk_entry(members: Option<&[&str]>) -> Entry {
+        Entry {
+            term: 1,
+            command: None,
+            membership: members.map(|members| MembershipEntry {
+                members: members.iter().map(|&m| String::from(m)).collect(),
+            }),
+            time: 0,
+            session: None,
+        }
+    }
+
+    #[test]
+    fn case_latest_entry() {
+        let mut log = vec![mk_entry(None), mk_entry(Some(&["a", "b"])), mk_entry(None)];
+        let mut membership = Membership::new(&["a"], &log);
+        assert_eq!(membership.members(), &["a", "b"]);
+        assert_eq!(membership.peers("a"), vec!["b"]);
+        assert_eq!(membership.quorum_size(), 2);
+        assert!(!membership.is_committed(1));
+        assert!(membership.is_committed(2));
+
+        log.push(mk_entry(Some(&["a", "b", "c"])));
+        membership.sync(&log, 3);
+        assert_eq!(membership.members(), &["a", "b", "c"]);
+
+        // truncated by a new leader
+        log.truncate(1);
+        membership.sync(&log, 1);
+        assert_eq!(membership.members(), &["a"]);
+        assert_eq!(membership.latest_index(), None);
+    }
+}
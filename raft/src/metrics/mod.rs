@@ -0,0 +1,92 @@
+// Prometheus metrics of the raft node.
+// They are registered in the default registry, which the web server exports.
+
+use prometheus::{register_histogram, register_histogram_vec, register_int_counter};
+use prometheus::{register_int_counter_vec, register_int_gauge, IntCounterVec};
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use prometheus::{Histogram, HistogramVec, IntCounter, IntGauge};
+use std::sync::LazyLock;
+
+pub static ELECTIONS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("raft_elections_total", "Number of elections started").unwrap()
+});
+
+pub static ELECTIONS_WON: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("raft_elections_won_total", "Number of elections won").unwrap()
+});
+
+pub static TERM_CHANGES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("raft_term_changes_total", "Number of term changes").unwrap()
+});
+
+pub static OVERLOADED_REQUESTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "raft_overloaded_requests_total",
+        "Number of user requests rejected by backpressure"
+    )
+    .unwrap()
+});
+
+pub static CATCH_UP_BYTES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "raft_catch_up_bytes_total",
+        "Bytes of entries and snapshot chunks sent by the leader to a lagging peer",
+        &["peer"]
+    )
+    .unwrap()
+});
+
+pub static PEER_UP: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "raft_peer_up",
+        "Whether the last RPC to the peer succeeded",
+        &["peer"]
+    )
+    .unwrap()
+});
+
+pub static PEER_RPC_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "raft_peer_rpc_failures_total",
+        "Number of RPCs to the peer failed on the connection or deadline",
+        &["peer"]
+    )
+    .unwrap()
+});
+
+pub static CURRENT_TERM: LazyLock<IntGauge> =
+    LazyLock::new(|| register_int_gauge!("raft_current_term", "Current term").unwrap());
+
+pub static APPEND_ENTRIES_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "raft_append_entries_duration_seconds",
+        "Round trip time of AppendEntries RPCs sent by the leader",
+        &["peer"]
+    )
+    .unwrap()
+});
+
+pub static COMMIT_LATENCY: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "raft_commit_duration_seconds",
+        "Time from proposing a user request on the leader to its commit"
+    )
+    .unwrap()
+});
+
+pub static WAL_SYNC_LATENCY: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "raft_wal_sync_duration_seconds",
+        "Time spent in one WAL sync"
+    )
+    .unwrap()
+});
+
+pub static WAL_SYNC_BATCH: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "raft_wal_sync_batch_entries",
+        "Number of entries made durable by one WAL sync",
+        vec![1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0]
+    )
+    .unwrap()
+});
@@ -1,13 +1,14 @@
-use crate::raftchat_tonic::{Command, Entry, UserRequestArgs};
-use crate::RaftConfig;
+use crate::raftchat_tonic::{Command, Entry, UserRequestRes};
+use crate::{RaftConfig, UserRequest};
 use std::time::Duration;
 use tokio::sync::mpsc;
 use tokio::time::sleep;
 
 struct RaftNode {
+    #[allow(dead_code)]
     config: RaftConfig,
     log_tx: mpsc::Sender<Entry>,
-    req_rx: mpsc::Receiver<UserRequestArgs>,
+    req_rx: mpsc::Receiver<UserRequest>,
     test_flag: bool,
     client_timestamp_map: std::collections::HashMap<String, u64>,
 }
@@ -15,12 +16,12 @@ struct RaftNode {
 pub fn run_mock_raft(
     config: RaftConfig,
     log_tx: mpsc::Sender<Entry>,
-    req_rx: mpsc::Receiver<UserRequestArgs>,
+    req_rx: mpsc::Receiver<UserRequest>,
 ) {
     let mut raft_node = RaftNode {
-        config: config,
-        log_tx: log_tx,
-        req_rx: req_rx,
+        config,
+        log_tx,
+        req_rx,
         test_flag: true,
         client_timestamp_map: std::collections::HashMap::new(),
     };
@@ -45,12 +46,20 @@ impl RaftNode {
 
         // [NOTE] This is a dummy implementation
         // echo back the data
-        while let Some(data) = self.req_rx.recv().await {
+        while let Some(UserRequest {
+            args: data,
+            result_tx,
+            ..
+        }) = self.req_rx.recv().await
+        {
             // no op test
             if idx == 5 {
                 let value = Entry {
                     term: 0,
                     command: None,
+                    membership: None,
+                    time: 0,
+                    session: None,
                 };
 
                 self.log_tx.send(value).await.unwrap();
@@ -84,10 +93,18 @@ impl RaftNode {
                     client_id: data.client_id,
                     message_id: data.message_id,
                     data: data.data,
+                    stripped: false,
                 }),
+                membership: None,
+                time: 0,
+                session: None,
             };
 
             self.log_tx.send(value).await.unwrap();
+            let _ = result_tx.send(Ok(UserRequestRes {
+                success: true,
+                index: idx as u64,
+            }));
             idx += 1;
         }
 
@@ -0,0 +1,206 @@
+// Multi-Raft : many independent raft groups in one process.
+// Every RPC carries the id of its group, and the router dispatches it to that group.
+// The groups share the RPC server and the connections to peer nodes.
+
+use crate::raftchat_tonic::admin_server::Admin;
+use crate::raftchat_tonic::change_feed_server::ChangeFeed;
+use crate::raftchat_tonic::raft_chat_server::RaftChat;
+use crate::raftchat_tonic::{AddMemberArgs, AddMemberRes, AppendEntriesArgs, AppendEntriesRes};
+use crate::raftchat_tonic::{BackupArgs, RequestVoteArgs, RequestVoteRes};
+use crate::raftchat_tonic::{InstallSnapshotArgs, InstallSnapshotRes};
+use crate::raftchat_tonic::{StatusArgs, StatusRes, StepDownArgs, StepDownRes};
+use crate::raftchat_tonic::{SubscribeArgs, TimeoutNowArgs, TimeoutNowRes};
+use crate::raftchat_tonic::{TransferLeadershipArgs, TransferLeadershipRes};
+use crate::raftchat_tonic::{TriggerSnapshotArgs, TriggerSnapshotRes};
+use crate::raftchat_tonic::{UserRequestArgs, UserRequestRes};
+use crate::MyRaftChat;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tonic::{Request, Response, Status};
+
+pub struct MultiRaft {
+    groups: HashMap<u64, Arc<MyRaftChat>>,
+}
+
+impl MultiRaft {
+    pub fn new(nodes: &[Arc<MyRaftChat>]) -> MultiRaft {
+        let mut groups = HashMap::new();
+        for node in nodes {
+            let prev = groups.insert(node.config.group_id, node.clone());
+            assert!(
+                prev.is_none(),
+                "duplicated raft group {}",
+                node.config.group_id
+            );
+        }
+        MultiRaft { groups }
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn group(&self, group_id: u64) -> Result<&Arc<MyRaftChat>, Status> {
+        self.groups
+            .get(&group_id)
+            .ok_or_else(|| Status::not_found(format!("unknown raft group {}", group_id)))
+    }
+}
+
+#[tonic::async_trait]
+impl RaftChat for Arc<MultiRaft> {
+    type BackupStream = <Arc<MyRaftChat> as RaftChat>::BackupStream;
+
+    async fn append_entries(
+        &self,
+        request: Request<AppendEntriesArgs>,
+    ) -> Result<Response<AppendEntriesRes>, Status> {
+        self.group(request.get_ref().group_id)?
+            .append_entries(request)
+            .await
+    }
+
+    async fn request_vote(
+        &self,
+        request: Request<RequestVoteArgs>,
+    ) -> Result<Response<RequestVoteRes>, Status> {
+        self.group(request.get_ref().group_id)?
+            .request_vote(request)
+            .await
+    }
+
+    async fn user_request(
+        &self,
+        request: Request<UserRequestArgs>,
+    ) -> Result<Response<UserRequestRes>, Status> {
+        self.group(request.get_ref().group_id)?
+            .user_request(request)
+            .await
+    }
+
+    async fn backup(
+        &self,
+        request: Request<BackupArgs>,
+    ) -> Result<Response<Self::BackupStream>, Status> {
+        self.group(request.get_ref().group_id)?
+            .backup(request)
+            .await
+    }
+
+    async fn timeout_now(
+        &self,
+        request: Request<TimeoutNowArgs>,
+    ) -> Result<Response<TimeoutNowRes>, Status> {
+        self.group(request.get_ref().group_id)?
+            .timeout_now(request)
+            .await
+    }
+
+    async fn add_member(
+        &self,
+        request: Request<AddMemberArgs>,
+    ) -> Result<Response<AddMemberRes>, Status> {
+        self.group(request.get_ref().group_id)?
+            .add_member(request)
+            .await
+    }
+
+    async fn install_snapshot(
+        &self,
+        request: Request<InstallSnapshotArgs>,
+    ) -> Result<Response<InstallSnapshotRes>, Status> {
+        self.group(request.get_ref().group_id)?
+            .install_snapshot(request)
+            .await
+    }
+}
+
+#[tonic::async_trait]
+impl Admin for Arc<MultiRaft> {
+    async fn status(&self, request: Request<StatusArgs>) -> Result<Response<StatusRes>, Status> {
+        self.group(request.get_ref().group_id)?
+            .status(request)
+            .await
+    }
+
+    async fn transfer_leadership(
+        &self,
+        request: Request<TransferLeadershipArgs>,
+    ) -> Result<Response<TransferLeadershipRes>, Status> {
+        self.group(request.get_ref().group_id)?
+            .transfer_leadership(request)
+            .await
+    }
+
+    async fn trigger_snapshot(
+        &self,
+        request: Request<TriggerSnapshotArgs>,
+    ) -> Result<Response<TriggerSnapshotRes>, Status> {
+        self.group(request.get_ref().group_id)?
+            .trigger_snapshot(request)
+            .await
+    }
+
+    async fn step_down(
+        &self,
+        request: Request<StepDownArgs>,
+    ) -> Result<Response<StepDownRes>, Status> {
+        self.group(request.get_ref().group_id)?
+            .step_down(request)
+            .await
+    }
+}
+
+#[tonic::async_trait]
+impl ChangeFeed for Arc<MultiRaft> {
+    type SubscribeStream = <Arc<MyRaftChat> as ChangeFeed>::SubscribeStream;
+
+    async fn subscribe(
+        &self,
+        request: Request<SubscribeArgs>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        self.group(request.get_ref().group_id)?
+            .subscribe(request)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MultiRaft;
+    use crate::clock::VirtualClock;
+    use crate::raftchat_tonic::admin_server::Admin;
+    use crate::raftchat_tonic::raft_chat_server::RaftChat;
+    use crate::raftchat_tonic::{RequestVoteArgs, StatusArgs};
+    use crate::tests::{mk_config, run, TestNode};
+    use std::sync::Arc;
+    use tonic::{Code, Request};
+
+    #[test]
+    fn case_route() {
+        run(async {
+            let clock = VirtualClock::new(1);
+            let a = TestNode::start(mk_config("a", &["a"], &clock));
+            let mut config = mk_config("b", &["b"], &clock);
+            config.group_id = 1;
+            let b = TestNode::start(config);
+            let router = Arc::new(MultiRaft::new(&[a.raft.clone(), b.raft.clone()]));
+
+            let status = |group_id| router.status(Request::new(StatusArgs { group_id }));
+            assert_eq!(status(0).await.unwrap().into_inner().node_id, "a");
+            assert_eq!(status(1).await.unwrap().into_inner().node_id, "b");
+            let e = status(2).await.unwrap_err();
+            assert_eq!(e.code(), Code::NotFound);
+            assert_eq!(e.message(), "unknown raft group 2");
+
+            let vote = RequestVoteArgs {
+                candidate_id: String::from("c"),
+                prev_length: 0,
+                prev_term: 0,
+                term: 1,
+                group_id: 2,
+            };
+            let e = router.request_vote(Request::new(vote)).await.unwrap_err();
+            assert_eq!(e.code(), Code::NotFound);
+            // NB : a message of an unknown group changes no group
+            assert_eq!((a.term(), b.term()), (0, 0));
+        });
+    }
+}
@@ -0,0 +1,156 @@
+// Health of the connections to the peers, tracked from the RPCs sent to them.
+// - up : the last RPC succeeded
+// - down : the last RPC failed on the connection (refused, reset, deadline), and the next one
+//   reconnects
+// - backoff : after a failure, no RPC is sent to the peer until the retry time, so that a dead
+//   peer is not hammered. The delay doubles with every consecutive failure from BACKOFF_BASE up
+//   to half of the election timeout, so that a peer back up hears from the leader before it
+//   starts an election. Half of it is random, so that the nodes do not reconnect in lockstep.
+// Every RPC to a peer has the deadline rpc_timeout, which is also the connect timeout.
+
+use crate::raftchat_tonic::PeerConnection;
+use crate::{metrics, MyRaftChat, RaftState};
+use log::{info, warn};
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tonic::{Code, Status};
+
+const BACKOFF_BASE: Duration = Duration::from_millis(50);
+const BACKOFF_MAX: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Default)]
+pub struct PeerHealth {
+    pub failures: u32, // consecutive
+    pub retry_at: Option<Instant>,
+    pub last_error: Option<String>,
+}
+
+impl PeerHealth {
+    pub fn connection(&self, now: Instant) -> PeerConnection {
+        match self.retry_at {
+            _ if self.failures == 0 => PeerConnection::Up,
+            Some(retry_at) if now < retry_at => PeerConnection::Backoff,
+            _ => PeerConnection::Down,
+        }
+    }
+
+    // whether an RPC may be sent to the peer
+    pub fn ready(&self, now: Instant) -> bool {
+        self.retry_at.is_none_or(|retry_at| now >= retry_at)
+    }
+}
+
+// delay before the retry after the given number of consecutive failures, jitter in [0, 1]
+pub fn backoff(failures: u32, max: Duration, jitter: f64) -> Duration {
+    let delay = BACKOFF_BASE
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(max);
+    delay / 2 + delay.mul_f64(jitter / 2.0)
+}
+
+// failures of the connection, as opposed to errors returned by the peer
+pub fn is_connection_error(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable | Code::DeadlineExceeded | Code::Cancelled | Code::Unknown
+    )
+}
+
+impl MyRaftChat {
+    // RPC to a peer, failing with deadline_exceeded after rpc_timeout
+    pub async fn with_deadline<T>(
+        &self,
+        rpc: impl Future<Output = Result<T, Status>>,
+    ) -> Result<T, Status> {
+        tokio::select! {
+            res = rpc => res,
+            _ = self.config.clock.sleep(self.config.rpc_timeout) => {
+                Err(Status::deadline_exceeded("rpc deadline exceeded"))
+            }
+        }
+    }
+
+    fn backoff_max(&self) -> Duration {
+        Duration::from_millis(self.config.election_duration.0 / 2).min(BACKOFF_MAX)
+    }
+
+    // delay before requesting again a vote failed on the connection, short enough for a few
+    // attempts within an election timeout
+    pub fn vote_retry_delay(&self, attempt: u32) -> Duration {
+        let max = Duration::from_millis(self.config.election_duration.0 / 8);
+        backoff(attempt, max, self.config.clock.random())
+    }
+
+    pub fn peer_ready(&self, state: &RaftState, peer: &str) -> bool {
+        state
+            .peer_health
+            .get(peer)
+            .is_none_or(|health| health.ready(self.config.clock.now()))
+    }
+
+    // Update the health of peer with the result of an RPC sent to it.
+    pub fn record_rpc<T>(
+        &self,
+        state: &mut RaftState,
+        peer: &'static str,
+        res: &Result<T, Status>,
+    ) {
+        let health = state.peer_health.entry(peer).or_default();
+        match res {
+            Err(status) if is_connection_error(status) => {
+                health.failures += 1;
+                let delay = backoff(
+                    health.failures,
+                    self.backoff_max(),
+                    self.config.clock.random(),
+                );
+                health.retry_at = Some(self.config.clock.now() + delay);
+                health.last_error = Some(String::from(status.message()));
+                if health.failures == 1 {
+                    warn!("connection to {} is down : {}", peer, status.message());
+                }
+                metrics::PEER_UP.with_label_values(&[peer]).set(0);
+                metrics::PEER_RPC_FAILURES.with_label_values(&[peer]).inc();
+            }
+            _ => {
+                if health.failures > 0 {
+                    info!(
+                        "connection to {} is up after {} failures",
+                        peer, health.failures
+                    );
+                }
+                *health = PeerHealth::default();
+                metrics::PEER_UP.with_label_values(&[peer]).set(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backoff, PeerHealth};
+    use crate::raftchat_tonic::PeerConnection;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn case_backoff() {
+        let ms = Duration::from_millis;
+        let max = ms(1000);
+        assert_eq!(backoff(1, max, 0.0), ms(25));
+        assert_eq!(backoff(1, max, 1.0), ms(50));
+        assert_eq!(backoff(3, max, 1.0), ms(200));
+        // capped
+        assert_eq!(backoff(10, max, 0.0), ms(500));
+        assert_eq!(backoff(u32::MAX, max, 1.0), max);
+
+        let now = Instant::now();
+        let mut health = PeerHealth::default();
+        assert_eq!(health.connection(now), PeerConnection::Up);
+        health.failures = 1;
+        health.retry_at = Some(now + ms(10));
+        assert!(!health.ready(now));
+        assert_eq!(health.connection(now), PeerConnection::Backoff);
+        assert!(health.ready(now + ms(10)));
+        assert_eq!(health.connection(now + ms(10)), PeerConnection::Down);
+    }
+}
@@ -1,23 +1,35 @@
 // persistent state
 
-use atomic_write_file::AtomicWriteFile;
-use std::path::Path;
+use crate::metrics;
+use crate::storage::memory::MemStableStore;
+use crate::storage::StableStore;
 
 pub struct PersistentState {
     // These data must be stored on persistent storage
     current_term: u64,
     voted_for: Option<&'static str>,
+    store: Box<dyn StableStore>,
 }
 
 impl PersistentState {
-    pub fn new(path: &Path) -> PersistentState {
-        // TODO : initialize with data from path
+    pub fn new(mut store: Box<dyn StableStore>) -> PersistentState {
+        let (current_term, voted_for) = store
+            .load()
+            .expect("Failed to load persistent state")
+            .unwrap_or((0, None));
+        metrics::CURRENT_TERM.set(current_term as i64);
         PersistentState {
-            current_term: 0,
-            voted_for: None,
+            current_term,
+            // NB : node ids live as long as the process, like the ones in RaftConfig
+            voted_for: voted_for.map(|id| &*id.leak()),
+            store,
         }
     }
 
+    pub fn in_memory() -> PersistentState {
+        PersistentState::new(Box::new(MemStableStore::new()))
+    }
+
     pub fn current_term(&self) -> u64 {
         self.current_term
     }
@@ -26,13 +38,25 @@ impl PersistentState {
         self.voted_for
     }
 
-    // Dummy implementation
+    fn set_term(&mut self, term: u64) {
+        self.current_term = term;
+        metrics::TERM_CHANGES.inc();
+        metrics::CURRENT_TERM.set(term as i64);
+    }
+
+    fn save(&mut self) {
+        self.store
+            .save(self.current_term, self.voted_for)
+            .expect("Failed to save persistent state");
+    }
+
     pub fn start_election(&mut self, self_id: &'static str) {
-        self.current_term = self.current_term + 1;
+        self.set_term(self.current_term + 1);
         self.voted_for = Some(self_id);
+        metrics::ELECTIONS.inc();
+        self.save();
     }
 
-    // Dummy implementation.
     // return (current_term, ok)
     //   current_term : term number after update
     //   ok : true if the given term was not outdated
@@ -40,23 +64,55 @@ impl PersistentState {
         if new_term < self.current_term {
             (self.current_term, false)
         } else {
-            // Warning : this two updates must be committed simultaneously
-            self.current_term = new_term;
-            self.voted_for = None;
+            // NB : the vote is kept within the same term, otherwise a node could vote twice
+            if new_term > self.current_term {
+                self.set_term(new_term);
+                self.voted_for = None;
+                self.save();
+            }
             (self.current_term, true)
         }
     }
 
-    // Dummy implementation
     // return ok
     //   ok : true if candidate received a vote
     pub fn try_vote(&mut self, candidate: &'static str) -> bool {
         match &self.voted_for {
             None => {
                 self.voted_for = Some(candidate);
+                self.save();
                 true
             }
             Some(recipient) => *recipient == candidate,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::PersistentState;
+    use crate::storage::memory::MemStableStore;
+
+    #[test]
+    fn case_vote_once_per_term() {
+        let mut state = PersistentState::in_memory();
+        assert_eq!(state.update_term(1), (1, true));
+        assert!(state.try_vote("a"));
+        assert_eq!(state.update_term(1), (1, true));
+        assert!(!state.try_vote("b"));
+        assert_eq!(state.update_term(2), (2, true));
+        assert!(state.try_vote("b"));
+    }
+
+    #[test]
+    fn case_reload() {
+        let store = MemStableStore::new();
+        let mut state = PersistentState::new(Box::new(store.clone()));
+        state.start_election("a");
+        drop(state);
+
+        let state = PersistentState::new(Box::new(store));
+        assert_eq!(state.current_term(), 1);
+        assert_eq!(state.voted_for(), Some("a"));
+    }
+}
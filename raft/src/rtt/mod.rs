@@ -0,0 +1,87 @@
+// Election timeouts adapted to the round trip time (adaptive_timeout mode).
+// - the leader measures the RTT of its heartbeats to each peer, smoothed as the SRTT of TCP,
+//   and sends the estimate to the peer in its append entries
+// - the election timeout of a node is RTT_FACTOR times the RTT to its leader, randomized by up
+//   to half of it, within the configured election_duration
+// - the leader sends its heartbeats HEARTBEATS_PER_TIMEOUT times per smallest election timeout
+//   of its peers, at most every heartbeat_duration
+// Without RTT estimate, e.g. before hearing from a leader, the timeout is in election_duration.
+
+use crate::{MyRaftChat, RaftState};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+const RTT_FACTOR: u32 = 10;
+const HEARTBEATS_PER_TIMEOUT: u32 = 3;
+
+// smoothed RTT after a new sample, with gain 1/8
+pub fn smooth(srtt: Option<Duration>, sample: Duration) -> Duration {
+    match srtt {
+        Some(srtt) => (srtt * 7 + sample) / 8,
+        None => sample,
+    }
+}
+
+// (lower, upper) bound of the election timeout (ms), for an RTT estimate
+pub fn election_range(rtt: Option<Duration>, bounds: (u64, u64)) -> (u64, u64) {
+    let Some(rtt) = rtt else {
+        return bounds;
+    };
+    // NB : leave room for the randomization
+    let top = (bounds.1 * 2 / 3).max(bounds.0);
+    let lower = ((rtt * RTT_FACTOR).as_millis() as u64).clamp(bounds.0, top);
+    (lower, (lower + lower / 2).min(bounds.1).max(lower + 1))
+}
+
+impl MyRaftChat {
+    // election timeout (ms) of this node, without handicap
+    pub fn election_timeout(&self) -> u64 {
+        let (lower, upper) = if self.config.adaptive_timeout {
+            let rtt_us = self.leader_rtt_us.load(Ordering::Relaxed);
+            let rtt = (rtt_us > 0).then(|| Duration::from_micros(rtt_us));
+            election_range(rtt, self.config.election_duration)
+        } else {
+            self.config.election_duration
+        };
+        lower + ((upper - lower) as f64 * self.config.clock.random()) as u64
+    }
+
+    // Leader only.
+    pub fn heartbeat_interval(&self, state: &RaftState) -> Duration {
+        if !self.config.adaptive_timeout {
+            return self.config.heartbeat_duration;
+        }
+        state
+            .membership
+            .peers(self.config.self_id)
+            .into_iter()
+            .map(|peer| {
+                let (lower, _) = election_range(
+                    state.peer_rtt.get(peer).copied(),
+                    self.config.election_duration,
+                );
+                Duration::from_millis(lower) / HEARTBEATS_PER_TIMEOUT
+            })
+            .fold(self.config.heartbeat_duration, Duration::min)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{election_range, smooth};
+    use std::time::Duration;
+
+    #[test]
+    fn case_election_range() {
+        let ms = Duration::from_millis;
+        // clamped to the bounds
+        assert_eq!(election_range(Some(ms(1)), (150, 3000)), (150, 225));
+        assert_eq!(election_range(Some(ms(50)), (150, 3000)), (500, 750));
+        assert_eq!(election_range(Some(ms(1000)), (150, 3000)), (2000, 3000));
+        assert_eq!(election_range(Some(ms(1000)), (150, 160)), (150, 160));
+        assert_eq!(election_range(None, (150, 3000)), (150, 3000));
+
+        assert_eq!(smooth(None, ms(8)), ms(8));
+        assert_eq!(smooth(Some(ms(8)), ms(16)), ms(9));
+    }
+}
@@ -0,0 +1,304 @@
+// Snapshot of a raft group.
+// The state of the chat is its whole history, so the snapshot at length L is the committed
+// prefix of L entries of the log, with the term of its last entry.
+// Layout : [MAGIC][length: u64][last term: u64] followed by the entries, in the record format of
+// the file WAL, so that every entry is checksummed. In the file, the records are sealed if a
+// keyring is given (see cipher), while the image in memory, which is sent to the peers, is plain.
+// NB : the log is never compacted, so the snapshot of a node is a prefix of its log.
+// Transfer : the leader sends its snapshot to a peer missing some of its entries, in chunks of
+// the image with the crc32 of each. The peer keeps the chunks received, so a transfer
+// interrupted by a lost connection or a change of leader resumes at the same offset, as long
+// as the new leader has the same snapshot. The chunks are kept in memory only, and a restarted
+// peer starts over.
+
+use crate::raftchat_tonic::{Entry, InstallSnapshotArgs};
+use crate::storage::cipher::Keyring;
+use crate::storage::file::{decode_records, decode_records_stale, encode_record};
+use atomic_write_file::AtomicWriteFile;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 16] = b"RAFTCHAT-SNAPSH1";
+const HEADER_LEN: usize = MAGIC.len() + 16;
+
+#[derive(Debug, PartialEq)]
+pub struct Snapshot {
+    pub length: u64,
+    pub last_term: u64,
+    // encoding of the snapshot, as laid out above
+    pub image: Vec<u8>,
+    pub checksum: u32, // crc32 of the image
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// the snapshot of entries, laid out as above
+fn encode(keyring: Option<&Keyring>, entries: &[Entry]) -> Vec<u8> {
+    let length = entries.len() as u64;
+    let last_term = entries.last().map_or(0, |entry| entry.term);
+    let mut image = Vec::with_capacity(HEADER_LEN);
+    image.extend_from_slice(MAGIC);
+    image.extend_from_slice(&length.to_le_bytes());
+    image.extend_from_slice(&last_term.to_le_bytes());
+    for (index, entry) in entries.iter().enumerate() {
+        image.extend_from_slice(&encode_record(keyring, index as u64, entry));
+    }
+    image
+}
+
+// return (length, last term, entries, whether some record must be sealed again by the current key)
+fn decode(keyring: Option<&Keyring>, image: &[u8]) -> io::Result<(u64, u64, Vec<Entry>, bool)> {
+    if image.len() < HEADER_LEN || &image[..MAGIC.len()] != MAGIC {
+        return Err(invalid("not a raftchat snapshot"));
+    }
+    let length = u64::from_le_bytes(image[MAGIC.len()..MAGIC.len() + 8].try_into().unwrap());
+    let last_term = u64::from_le_bytes(image[MAGIC.len() + 8..HEADER_LEN].try_into().unwrap());
+    let (entries, _, valid_length, stale) = decode_records_stale(keyring, &image[HEADER_LEN..])?;
+    if entries.len() as u64 != length
+        || HEADER_LEN + valid_length != image.len()
+        || entries.last().map_or(0, |entry| entry.term) != last_term
+    {
+        return Err(invalid("truncated or corrupted snapshot"));
+    }
+    Ok((length, last_term, entries, stale))
+}
+
+impl Snapshot {
+    pub fn new(entries: &[Entry]) -> Snapshot {
+        let image = encode(None, entries);
+        Snapshot {
+            length: entries.len() as u64,
+            last_term: entries.last().map_or(0, |entry| entry.term),
+            checksum: crc32fast::hash(&image),
+            image,
+        }
+    }
+
+    // the snapshot of a plain image, and its entries
+    pub fn decode(image: Vec<u8>) -> io::Result<(Snapshot, Vec<Entry>)> {
+        let (length, last_term, entries, _) = decode(None, &image)?;
+        let snapshot = Snapshot {
+            length,
+            last_term,
+            checksum: crc32fast::hash(&image),
+            image,
+        };
+        Ok((snapshot, entries))
+    }
+
+    // NB : the records are sealed by the current key of keyring
+    pub fn save(&self, path: &Path, keyring: Option<&Keyring>) -> io::Result<()> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let sealed;
+        let image = match keyring {
+            None => &self.image,
+            Some(keyring) => {
+                let (entries, _, _) = decode_records(None, &self.image[HEADER_LEN..])?;
+                sealed = encode(Some(keyring), &entries);
+                &sealed
+            }
+        };
+        let mut file = AtomicWriteFile::options().open(path)?;
+        file.write_all(image)?;
+        file.commit()
+    }
+
+    // None if no snapshot was saved at path
+    // return the snapshot, its entries, and whether the file must be saved again to seal every
+    // record by the current key
+    pub fn load(
+        path: &Path,
+        keyring: Option<&Keyring>,
+    ) -> io::Result<Option<(Snapshot, Vec<Entry>, bool)>> {
+        let file = match fs::read(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let (_, _, entries, stale) = decode(keyring, &file)?;
+        Ok(Some((Snapshot::new(&entries), entries, stale)))
+    }
+
+    // the chunk of the image at offset, of at most max_chunk_bytes
+    pub fn chunk(&self, offset: u64, max_chunk_bytes: usize) -> &[u8] {
+        let start = (offset as usize).min(self.image.len());
+        let end = start.saturating_add(max_chunk_bytes).min(self.image.len());
+        &self.image[start..end]
+    }
+}
+
+// Snapshot being received in chunks.
+pub struct Incoming {
+    length: u64,
+    last_term: u64,
+    size: u64,
+    checksum: u32,
+    image: Vec<u8>,
+}
+
+impl Incoming {
+    pub fn new(args: &InstallSnapshotArgs) -> Incoming {
+        Incoming {
+            length: args.length,
+            last_term: args.last_term,
+            size: args.size,
+            checksum: args.snapshot_checksum,
+            image: vec![],
+        }
+    }
+
+    // args is a chunk of this snapshot, whichever leader sends it
+    pub fn matches(&self, args: &InstallSnapshotArgs) -> bool {
+        (self.length, self.last_term, self.size, self.checksum)
+            == (
+                args.length,
+                args.last_term,
+                args.size,
+                args.snapshot_checksum,
+            )
+    }
+
+    // bytes received so far, the offset of the next chunk
+    pub fn offset(&self) -> u64 {
+        self.image.len() as u64
+    }
+
+    // Append the chunk if it is the next one and intact, and return the offset of the next chunk.
+    // NB : the leader resends from the returned offset
+    pub fn receive(&mut self, offset: u64, data: &[u8], checksum: u32) -> u64 {
+        if offset == self.offset()
+            && crc32fast::hash(data) == checksum
+            && self.offset() + data.len() as u64 <= self.size
+        {
+            self.image.extend_from_slice(data);
+        }
+        self.offset()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.offset() == self.size
+    }
+
+    // the received snapshot and its entries, if it is the announced one
+    pub fn finish(self) -> io::Result<(Snapshot, Vec<Entry>)> {
+        if crc32fast::hash(&self.image) != self.checksum {
+            return Err(invalid("checksum mismatch of the snapshot"));
+        }
+        let (snapshot, entries) = Snapshot::decode(self.image)?;
+        if (snapshot.length, snapshot.last_term) != (self.length, self.last_term) {
+            return Err(invalid("not the announced snapshot"));
+        }
+        Ok((snapshot, entries))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Incoming, Snapshot};
+    use crate::raftchat_tonic::{Command, Entry, InstallSnapshotArgs};
+    use crate::storage::cipher::Keyring;
+    use std::fs;
+
+    fn mk_entry(term: u64, message_id: u64) -> Entry {
+        Entry {
+            term,
+            command: Some(Command {
+                client_id: String::from("a"),
+                message_id,
+                data: b"hello".to_vec(),
+                stripped: false,
+            }),
+            membership: None,
+            time: 0,
+            session: None,
+        }
+    }
+
+    #[test]
+    fn case_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snapshot");
+        assert_eq!(Snapshot::load(&path, None).unwrap(), None);
+
+        let entries = vec![mk_entry(1, 1), mk_entry(1, 2), mk_entry(3, 3)];
+        let snapshot = Snapshot::new(&entries);
+        assert_eq!((snapshot.length, snapshot.last_term), (3, 3));
+        snapshot.save(&path, None).unwrap();
+        let loaded = Snapshot::load(&path, None).unwrap().unwrap();
+        assert_eq!(loaded, (snapshot, entries, false));
+        let snapshot = loaded.0;
+
+        let mut image = snapshot.image.clone();
+        image.pop();
+        assert!(Snapshot::decode(image).is_err());
+        let mut image = snapshot.image.clone();
+        image[16] = 2; // length
+        assert!(Snapshot::decode(image).is_err());
+        assert!(Snapshot::decode(b"RAFTCHAT-BACKUP1".to_vec()).is_err());
+    }
+
+    // the file of a snapshot has no plaintext with a key, and is sealed again after a rotation
+    #[test]
+    fn case_sealed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snapshot");
+        let key = Keyring::parse(&"ab".repeat(32)).unwrap();
+        let rotated = Keyring::parse(&format!("{}\n{}", "cd".repeat(32), "ab".repeat(32))).unwrap();
+        let entries = vec![mk_entry(1, 1), mk_entry(2, 2)];
+        let snapshot = Snapshot::new(&entries);
+
+        snapshot.save(&path, Some(&key)).unwrap();
+        let file = fs::read(&path).unwrap();
+        assert!(!file.windows(5).any(|w| w == b"hello"));
+        assert_ne!(file, snapshot.image);
+        let loaded = Snapshot::load(&path, Some(&key)).unwrap().unwrap();
+        assert_eq!(loaded, (Snapshot::new(&entries), entries.clone(), false));
+        assert!(Snapshot::load(&path, None).is_err());
+
+        let (_, _, stale) = Snapshot::load(&path, Some(&rotated)).unwrap().unwrap();
+        assert!(stale);
+        snapshot.save(&path, None).unwrap();
+        let (_, _, stale) = Snapshot::load(&path, Some(&key)).unwrap().unwrap();
+        assert!(stale);
+    }
+
+    // chunks out of order or corrupted are dropped, and the transfer resumes at the last good one
+    #[test]
+    fn case_incoming() {
+        let entries: Vec<Entry> = (1..=20).map(|i| mk_entry(1, i)).collect();
+        let snapshot = Snapshot::new(&entries);
+        let args = InstallSnapshotArgs {
+            length: snapshot.length,
+            last_term: snapshot.last_term,
+            size: snapshot.image.len() as u64,
+            snapshot_checksum: snapshot.checksum,
+            ..Default::default()
+        };
+        let mut incoming = Incoming::new(&args);
+        let chunk = |offset| snapshot.chunk(offset, 100);
+        assert_eq!(
+            incoming.receive(0, chunk(0), crc32fast::hash(chunk(0))),
+            100
+        );
+        assert_eq!(
+            incoming.receive(200, chunk(200), crc32fast::hash(chunk(200))),
+            100
+        );
+        assert_eq!(incoming.receive(100, chunk(100), 0), 100);
+        assert!(incoming.matches(&args));
+        assert!(!incoming.matches(&InstallSnapshotArgs { size: 1, ..args }));
+
+        let mut offset = incoming.offset();
+        while !incoming.is_complete() {
+            offset = incoming.receive(offset, chunk(offset), crc32fast::hash(chunk(offset)));
+        }
+        let (received, received_entries) = incoming.finish().unwrap();
+        assert_eq!(received, snapshot);
+        assert_eq!(received_entries, entries);
+    }
+}
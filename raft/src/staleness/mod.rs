@@ -0,0 +1,111 @@
+// Staleness of the committed log of a node, for reads with a staleness bound.
+// - entries : entries committed by the leader which this node does not know as committed,
+//   as of the last append entries
+// - time : since this node last knew every entry committed by the leader, i.e. since the last
+//   append entries after which its committed_length caught up with the one of the leader
+// A leader is never stale, a node which never caught up has an unknown (unbounded) staleness.
+// NB : without leader lease, a deposed leader which is not aware of it yet is stale anyway.
+
+use crate::{MyRaftChat, Role};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Staleness {
+    pub entries: u64,
+    pub time: Option<Duration>, // None : never caught up
+}
+
+// bound asked by a client, None : any
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StalenessBound {
+    pub max_entries: Option<u64>,
+    pub max_time: Option<Duration>,
+}
+
+impl StalenessBound {
+    pub fn allows(&self, staleness: &Staleness) -> bool {
+        let entries_ok = self.max_entries.is_none_or(|max| staleness.entries <= max);
+        let time_ok = match (self.max_time, staleness.time) {
+            (None, _) => true,
+            (Some(max), Some(time)) => time <= max,
+            (Some(_), None) => false,
+        };
+        entries_ok && time_ok
+    }
+}
+
+impl MyRaftChat {
+    pub fn staleness(&self) -> Staleness {
+        let guard = self.state.lock();
+        if let Role::Leader(_) = guard.role {
+            return Staleness {
+                entries: 0,
+                time: Some(Duration::ZERO),
+            };
+        }
+        Staleness {
+            entries: guard
+                .leader_committed_length
+                .saturating_sub(guard.committed_length),
+            time: guard
+                .fresh_at
+                .map(|fresh_at| self.config.clock.now().saturating_duration_since(fresh_at)),
+        }
+    }
+
+    // Wait until the staleness of this node is within bound, for timeout at most.
+    // return the staleness on timeout
+    pub async fn wait_fresh(
+        &self,
+        bound: StalenessBound,
+        timeout: Duration,
+    ) -> Result<(), Staleness> {
+        // NB : every append entries of the leader is notified, even without new commit
+        let mut committed_rx = self.committed_length_tx.subscribe();
+        let mut deadline = self.config.clock.sleep(timeout);
+        loop {
+            let staleness = self.staleness();
+            if bound.allows(&staleness) {
+                return Ok(());
+            }
+            tokio::select! {
+                changed = committed_rx.changed() => {
+                    if changed.is_err() {
+                        return Err(self.staleness());
+                    }
+                }
+                _ = &mut deadline => return Err(self.staleness()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Staleness, StalenessBound};
+    use std::time::Duration;
+
+    #[test]
+    fn case_allows() {
+        let ms = Duration::from_millis;
+        let staleness = Staleness {
+            entries: 3,
+            time: Some(ms(200)),
+        };
+        assert!(StalenessBound::default().allows(&staleness));
+        let bound = |max_entries, max_time| StalenessBound {
+            max_entries,
+            max_time,
+        };
+        assert!(bound(Some(3), Some(ms(200))).allows(&staleness));
+        assert!(!bound(Some(2), None).allows(&staleness));
+        assert!(!bound(None, Some(ms(100))).allows(&staleness));
+        // never caught up
+        let unknown = Staleness {
+            entries: 0,
+            time: None,
+        };
+        assert!(bound(Some(0), None).allows(&unknown));
+        assert!(!bound(None, Some(ms(1000))).allows(&unknown));
+    }
+}
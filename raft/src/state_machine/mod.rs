@@ -1,16 +1,16 @@
-use crate::raftchat_tonic::{Command, Entry};
-use crate::wal::{Action, WAL};
-use std::collections::HashMap;
+use crate::raftchat_tonic::Entry;
+use crate::wal::{Action, SyncTicket, WAL};
+use std::collections::{BTreeSet, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub trait StateMachine {
     fn new() -> Self;
-    fn apply(&mut self, cmd: &Command);
+    fn apply(&mut self, index: u64, entry: &Entry);
 
-    fn apply_entries(&mut self, entries: &[Entry]) {
-        for entry in entries {
-            if let Some(cmd) = &entry.command {
-                self.apply(cmd);
-            }
+    // entries from index from on
+    fn apply_entries(&mut self, from: u64, entries: &[Entry]) {
+        for (i, entry) in entries.iter().enumerate() {
+            self.apply(from + i as u64, entry);
         }
     }
 }
@@ -22,26 +22,105 @@ pub struct SMWrapper<S> {
     snapshot: S,
 }
 
-#[derive(Clone)]
+// last request of a client session
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LastRequest {
+    pub message_id: u64, // 0 before the first request
+    pub index: u64,      // index of the command in the log
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct ClientSession {
+    last_request: LastRequest,
+    timeout_ms: u64,
+    expires_at: u64,
+}
+
+// Deduplication of user requests : the last request of every client session, so that a retry
+// gets the result of the first attempt.
+// Sessions are registered by session entries, and expire by the time of the entries, so that
+// every node expires the same sessions at the same index.
+#[derive(Clone, Debug, PartialEq)]
 pub struct UserMessageIdMap {
-    table: HashMap<String, u64>,
+    sessions: HashMap<String, ClientSession>,
+    // (expires_at, client_id) of every session
+    expiry: BTreeSet<(u64, String)>,
 }
 
 impl UserMessageIdMap {
-    pub fn get(&self, client_id: &String) -> Option<u64> {
-        self.table.get(client_id).cloned()
+    // last request of the session of client_id, unless the session expires at time
+    pub fn last_request(&self, client_id: &str, time: u64) -> Option<LastRequest> {
+        self.sessions
+            .get(client_id)
+            .filter(|session| session.expires_at > time)
+            .map(|session| session.last_request)
+    }
+
+    pub fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    fn touch(&mut self, client_id: &str, time: u64) {
+        let Some(session) = self.sessions.get_mut(client_id) else {
+            return;
+        };
+        self.expiry
+            .remove(&(session.expires_at, String::from(client_id)));
+        session.expires_at = time + session.timeout_ms;
+        self.expiry
+            .insert((session.expires_at, String::from(client_id)));
+    }
+
+    fn expire(&mut self, time: u64) {
+        while let Some((expires_at, _)) = self.expiry.first() {
+            if *expires_at > time {
+                break;
+            }
+            let (_, client_id) = self.expiry.pop_first().unwrap();
+            self.sessions.remove(&client_id);
+        }
     }
 }
 
 impl StateMachine for UserMessageIdMap {
     fn new() -> Self {
         UserMessageIdMap {
-            table: HashMap::new(),
+            sessions: HashMap::new(),
+            expiry: BTreeSet::new(),
         }
     }
 
-    fn apply(&mut self, cmd: &Command) {
-        self.table.insert(cmd.client_id.clone(), cmd.message_id);
+    fn apply(&mut self, index: u64, entry: &Entry) {
+        self.expire(entry.time);
+        if let Some(session) = &entry.session {
+            // registering again starts a new session
+            if let Some(old) = self.sessions.remove(&session.client_id) {
+                self.expiry
+                    .remove(&(old.expires_at, session.client_id.clone()));
+            }
+            self.sessions.insert(
+                session.client_id.clone(),
+                ClientSession {
+                    last_request: LastRequest {
+                        message_id: 0,
+                        index,
+                    },
+                    timeout_ms: session.timeout_ms,
+                    expires_at: 0,
+                },
+            );
+            self.touch(&session.client_id, entry.time);
+        }
+        // NB : a command of an expired session is not deduplicated anymore
+        if let Some(cmd) = &entry.command {
+            if let Some(session) = self.sessions.get_mut(&cmd.client_id) {
+                session.last_request = LastRequest {
+                    message_id: cmd.message_id,
+                    index,
+                };
+                self.touch(&cmd.client_id, entry.time);
+            }
+        }
     }
 }
 
@@ -52,10 +131,10 @@ where
 {
     pub fn new(wal: WAL) -> Self {
         let mut state: S = StateMachine::new();
-        state.apply_entries(wal.as_slice());
+        state.apply_entries(0, wal.as_slice());
         SMWrapper {
-            wal: wal,
-            state: state,
+            wal,
+            state,
             snapshot_length: 0,
             snapshot: StateMachine::new(),
         }
@@ -65,12 +144,32 @@ where
         &self.wal
     }
 
+    // time of a new entry : the clock of this node, unless an entry of the log is later
+    pub fn log_time(&self, now: SystemTime) -> u64 {
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let last = self.wal.as_slice().last().map_or(0, |entry| entry.time);
+        now.max(last)
+    }
+
+    pub fn snapshot_length(&self) -> u64 {
+        self.snapshot_length
+    }
+
+    pub fn finish_sync(&mut self, ticket: &SyncTicket) -> bool {
+        self.wal.finish_sync(ticket)
+    }
+
     pub fn take_snapshot(&mut self, len: u64) {
         let snapshot_length = self.snapshot_length;
         if snapshot_length <= len {
             self.snapshot_length = len;
-            self.snapshot
-                .apply_entries(&self.wal.as_slice()[snapshot_length as usize..len as usize]);
+            self.snapshot.apply_entries(
+                snapshot_length,
+                &self.wal.as_slice()[snapshot_length as usize..len as usize],
+            );
         } else {
             panic!();
         }
@@ -78,11 +177,10 @@ where
 
     pub fn propose_entry(&mut self, entry: Entry) -> u64 {
         // must update state machine before proposing
-        self.state
-            .apply(entry.command.as_ref().expect("Apply command is None"));
+        self.state.apply(self.wal.len(), &entry);
 
         // return appended index
-        return self.wal.propose_entry(entry);
+        self.wal.propose_entry(entry)
     }
 
     pub fn append_entries(
@@ -98,8 +196,10 @@ where
                 let snapshot_length = self.snapshot_length;
                 if snapshot_length <= l {
                     self.state = self.snapshot.clone();
-                    self.state
-                        .apply_entries(&self.wal.as_slice()[snapshot_length as usize..]);
+                    self.state.apply_entries(
+                        snapshot_length,
+                        &self.wal.as_slice()[snapshot_length as usize..],
+                    );
                     Some(l + entries.len() as u64)
                 } else {
                     panic!();
@@ -114,3 +214,170 @@ where
         &self.state
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{LastRequest, SMWrapper, StateMachine, UserMessageIdMap};
+    use crate::raftchat_tonic::{Command, Entry, Session};
+    use crate::wal::WAL;
+    use proptest::prelude::*;
+
+    fn register(client_id: &str, time: u64) -> Entry {
+        Entry {
+            term: 1,
+            command: None,
+            membership: None,
+            time,
+            session: Some(Session {
+                client_id: String::from(client_id),
+                timeout_ms: 100,
+            }),
+        }
+    }
+
+    fn command(client_id: &str, message_id: u64, time: u64) -> Entry {
+        Entry {
+            term: 1,
+            command: Some(Command {
+                client_id: String::from(client_id),
+                message_id,
+                data: vec![],
+                stripped: false,
+            }),
+            membership: None,
+            time,
+            session: None,
+        }
+    }
+
+    #[test]
+    fn case_session_expiry() {
+        let mut sm = UserMessageIdMap::new();
+        sm.apply_entries(
+            0,
+            &[
+                register("a", 0),
+                command("a", 1, 0),
+                register("b", 50),
+                command("b", 1, 60),
+            ],
+        );
+        let last = |message_id, index| Some(LastRequest { message_id, index });
+        assert_eq!(sm.last_request("a", 60), last(1, 1));
+        assert_eq!(sm.last_request("a", 100), None);
+
+        // a command of b keeps its session alive
+        sm.apply(4, &command("b", 2, 120));
+        assert_eq!(sm.session_count(), 1);
+        assert_eq!(sm.last_request("b", 200), last(2, 4));
+
+        // expiry depends on the log only
+        sm.apply(5, &command("c", 1, 220));
+        assert_eq!(sm.session_count(), 0);
+        assert_eq!(sm.last_request("c", 220), None);
+    }
+
+    // The entry at index of term : registers, commands of 3 clients and no-ops, so that
+    // sessions expire. Entries of the same index and term are the same, as in raft.
+    fn mk_entry(index: u64, term: u64) -> Entry {
+        let client_id = format!("c{}", index % 3);
+        let time = index * 40;
+        let mut entry = match (index + term) % 4 {
+            0 => register(&client_id, time),
+            3 => Entry {
+                term,
+                command: None,
+                membership: None,
+                time,
+                session: None,
+            },
+            _ => command(&client_id, index, time),
+        };
+        entry.term = term;
+        entry
+    }
+
+    fn replay(entries: &[Entry]) -> UserMessageIdMap {
+        let mut sm = UserMessageIdMap::new();
+        sm.apply_entries(0, entries);
+        sm
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Append {
+            prev_length: usize,
+            prev_term_ok: bool,
+            terms: Vec<u64>,
+        },
+        Propose(u64),
+        Snapshot(usize),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            3 => (any::<usize>(), any::<bool>(), prop::collection::vec(1..4u64, 0..5)).prop_map(
+                |(prev_length, prev_term_ok, terms)| Op::Append {
+                    prev_length,
+                    prev_term_ok,
+                    terms,
+                }
+            ),
+            2 => (1..4u64).prop_map(Op::Propose),
+            1 => any::<usize>().prop_map(Op::Snapshot),
+        ]
+    }
+
+    proptest! {
+        // The state is always the replay of the log, and the snapshot the replay of its prefix.
+        // Like raft, the log is never truncated below the snapshot, which is taken at the
+        // committed length.
+        #[test]
+        fn prop_state_is_replay(ops in prop::collection::vec(op(), 1..40)) {
+            let mut sm: SMWrapper<UserMessageIdMap> = SMWrapper::new(WAL::in_memory());
+            for op in ops {
+                let len = sm.wal().len() as usize;
+                let snapshot_length = sm.snapshot_length() as usize;
+                match op {
+                    Op::Append { prev_length, prev_term_ok, terms } => {
+                        let prev_length = prev_length % (len + 2);
+                        let prev_term = match prev_length.checked_sub(1) {
+                            Some(i) if prev_term_ok && i < len => sm.wal().as_slice()[i].term,
+                            _ => prev_length as u64 % 3,
+                        };
+                        let entries: Vec<Entry> = terms
+                            .iter()
+                            .enumerate()
+                            .map(|(i, &term)| mk_entry((prev_length + i) as u64, term))
+                            .collect();
+                        let matched = prev_length <= len
+                            && (prev_length == 0
+                                || sm.wal().last_term_for(prev_length as u64) == prev_term);
+                        let conflict = entries.iter().enumerate().position(|(i, entry)| {
+                            let index = prev_length + i;
+                            index < len && sm.wal().as_slice()[index].term != entry.term
+                        });
+                        if matched && conflict.is_some_and(|i| prev_length + i < snapshot_length) {
+                            continue;
+                        }
+                        let res = sm.append_entries(prev_length as u64, prev_term, &entries);
+                        let expected = matched.then_some((prev_length + entries.len()) as u64);
+                        prop_assert_eq!(res, expected);
+                    }
+                    Op::Propose(term) => {
+                        let index = sm.propose_entry(mk_entry(len as u64, term));
+                        prop_assert_eq!(index, len as u64);
+                    }
+                    Op::Snapshot(at) => {
+                        let at = snapshot_length + at % (len - snapshot_length + 1);
+                        sm.take_snapshot(at as u64);
+                    }
+                }
+
+                let entries = sm.wal().as_slice();
+                prop_assert_eq!(sm.state(), &replay(entries));
+                prop_assert_eq!(&sm.snapshot, &replay(&entries[..sm.snapshot_length() as usize]));
+            }
+        }
+    }
+}
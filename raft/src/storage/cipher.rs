@@ -0,0 +1,227 @@
+// Authenticated encryption of log records (XChaCha20-Poly1305).
+// A sealed payload is [MAGIC][key id: 8][nonce: 24][ciphertext and tag], with the index of the
+// record as associated data, so that records cannot be moved around.
+// The key file holds keys of 32 bytes in hex, one per line : the current key first, which seals
+// every record, then previous keys, which only open records. Records which are plain or sealed
+// by a previous key are sealed again by the current key when a log is loaded, so a key is
+// rotated by adding the new key on top, restarting the node, then removing the old key.
+// The records of a snapshot file are sealed the same way, and sealed again at load too.
+// NB : the log is never compacted, so the records are sealed again at load, not at compaction.
+
+use crate::raftchat_tonic::Entry;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use prost::Message;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"RCE1";
+const KEY_ID_LEN: usize = 8;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = MAGIC.len() + KEY_ID_LEN + NONCE_LEN;
+
+struct Key {
+    id: [u8; KEY_ID_LEN],
+    aead: XChaCha20Poly1305,
+}
+
+impl Key {
+    fn new(bytes: &[u8; 32]) -> Key {
+        let aead = XChaCha20Poly1305::new(bytes.into());
+        // NB : the key id is the first bytes of a fixed message encrypted with the zero nonce,
+        // which identify the key without revealing it
+        let tag = aead
+            .encrypt(&XNonce::default(), b"raftchat key id".as_slice())
+            .unwrap();
+        Key {
+            id: tag[..KEY_ID_LEN].try_into().unwrap(),
+            aead,
+        }
+    }
+}
+
+pub struct Keyring {
+    keys: Vec<Key>, // current key first
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ids: Vec<String> = self.keys.iter().map(|key| hex(&key.id)).collect();
+        write!(f, "Keyring({})", ids.join(","))
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Keyring {
+    pub fn parse(text: &str) -> Result<Keyring, String> {
+        let mut keys = vec![];
+        for (i, line) in text.lines().map(str::trim).enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bytes: Option<Vec<u8>> = (0..line.len())
+                .step_by(2)
+                .map(|j| {
+                    line.get(j..j + 2)
+                        .and_then(|b| u8::from_str_radix(b, 16).ok())
+                })
+                .collect();
+            match bytes.and_then(|bytes| <[u8; 32]>::try_from(bytes).ok()) {
+                Some(bytes) => keys.push(Key::new(&bytes)),
+                None => return Err(format!("line {} : not a key of 64 hex digits", i + 1)),
+            }
+        }
+        if keys.is_empty() {
+            return Err(String::from("no key"));
+        }
+        Ok(Keyring { keys })
+    }
+
+    pub fn load(path: &Path) -> io::Result<Keyring> {
+        Keyring::parse(&fs::read_to_string(path)?)
+            .map_err(|e| invalid_data(format!("{} : {}", path.display(), e)))
+    }
+}
+
+pub fn is_sealed(payload: &[u8]) -> bool {
+    payload.starts_with(MAGIC)
+}
+
+// payload of the entry at index, sealed by the current key if any
+pub fn seal_entry(keyring: Option<&Keyring>, index: u64, entry: &Entry) -> Vec<u8> {
+    let plain = entry.encode_to_vec();
+    let Some(keyring) = keyring else {
+        return plain;
+    };
+    let key = &keyring.keys[0];
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    let aad = index.to_le_bytes();
+    let sealed = key
+        .aead
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: &plain,
+                aad: &aad,
+            },
+        )
+        .unwrap();
+    let mut buf = Vec::with_capacity(HEADER_LEN + sealed.len());
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&key.id);
+    buf.extend_from_slice(&nonce);
+    buf.extend_from_slice(&sealed);
+    buf
+}
+
+// return the entry at index, and whether its payload must be sealed again by the current key
+pub fn open_entry(
+    keyring: Option<&Keyring>,
+    index: u64,
+    payload: &[u8],
+) -> io::Result<(Entry, bool)> {
+    let decode = |plain: &[u8]| {
+        Entry::decode(plain).map_err(|e| invalid_data(format!("record {} : {}", index, e)))
+    };
+    if !is_sealed(payload) {
+        return Ok((decode(payload)?, keyring.is_some()));
+    }
+    let Some(keyring) = keyring else {
+        return Err(invalid_data(format!(
+            "record {} is encrypted, but no key is configured",
+            index
+        )));
+    };
+    if payload.len() < HEADER_LEN {
+        return Err(invalid_data(format!("record {} is truncated", index)));
+    }
+    let id = &payload[MAGIC.len()..MAGIC.len() + KEY_ID_LEN];
+    let Some(position) = keyring.keys.iter().position(|key| key.id == id) else {
+        return Err(invalid_data(format!(
+            "record {} is sealed by the unknown key {}",
+            index,
+            hex(id)
+        )));
+    };
+    let nonce = &payload[MAGIC.len() + KEY_ID_LEN..HEADER_LEN];
+    let aad = index.to_le_bytes();
+    let plain = keyring.keys[position]
+        .aead
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: &payload[HEADER_LEN..],
+                aad: &aad,
+            },
+        )
+        .map_err(|_| invalid_data(format!("record {} fails authentication", index)))?;
+    Ok((decode(&plain)?, position > 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_sealed, open_entry, seal_entry, Keyring};
+    use crate::raftchat_tonic::{Command, Entry};
+
+    const KEY_A: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const KEY_B: &str = "ff0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn mk_entry() -> Entry {
+        Entry {
+            term: 2,
+            command: Some(Command {
+                client_id: String::from("a"),
+                message_id: 1,
+                data: b"secret".to_vec(),
+                stripped: false,
+            }),
+            membership: None,
+            time: 0,
+            session: None,
+        }
+    }
+
+    #[test]
+    fn case_seal() {
+        let a = Keyring::parse(KEY_A).unwrap();
+        let b = Keyring::parse(KEY_B).unwrap();
+        let rotated = Keyring::parse(&format!("{}\n{}\n", KEY_B, KEY_A)).unwrap();
+        let entry = mk_entry();
+
+        let sealed = seal_entry(Some(&a), 3, &entry);
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.windows(6).any(|w| w == b"secret"));
+        assert_eq!(
+            open_entry(Some(&a), 3, &sealed).unwrap(),
+            (entry.clone(), false)
+        );
+        // previous key
+        assert_eq!(
+            open_entry(Some(&rotated), 3, &sealed).unwrap(),
+            (entry.clone(), true)
+        );
+        // plain record
+        let plain = seal_entry(None, 3, &entry);
+        assert_eq!(
+            open_entry(Some(&a), 3, &plain).unwrap(),
+            (entry.clone(), true)
+        );
+        assert_eq!(open_entry(None, 3, &plain).unwrap(), (entry, false));
+
+        assert!(open_entry(Some(&b), 3, &sealed).is_err());
+        assert!(open_entry(None, 3, &sealed).is_err());
+        // moved record
+        assert!(open_entry(Some(&a), 4, &sealed).is_err());
+        assert!(Keyring::parse("0011").is_err());
+        assert!(Keyring::parse("# comment\n").is_err());
+    }
+}
@@ -0,0 +1,319 @@
+// File-backed storage.
+// The log is a single append-only file of checksummed records, and term/vote metadata is
+// a small text file replaced atomically on every update.
+
+use super::cipher::{self, Keyring};
+use super::{LogStore, StableState, StableStore, SyncHandle};
+use crate::raftchat_tonic::Entry;
+use atomic_write_file::AtomicWriteFile;
+use log::{info, warn};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Each record is laid out as [payload length: u32][crc32 of payload: u32][payload],
+// where the payload is the protobuf encoding of an `Entry`, sealed if a keyring is given
+// (see cipher).
+const RECORD_HEADER_LEN: usize = 8;
+
+// NB : index is the position of the record in the file, authenticated with sealed payloads
+pub fn encode_record(keyring: Option<&Keyring>, index: u64, entry: &Entry) -> Vec<u8> {
+    let payload = cipher::seal_entry(keyring, index, entry);
+    let mut buf = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    buf.extend_from_slice(&payload);
+    buf
+}
+
+// Decode records from the beginning of buf.
+// return (entries, offsets, valid_length)
+//   offsets      : offsets[i] is the byte offset of the record of entries[i]
+//   valid_length : number of bytes occupied by well-formed records.
+//                  Anything after it is a torn or corrupted tail.
+// A sealed record which passes its checksum but cannot be opened (e.g. wrong key) is an error,
+// not a torn tail.
+pub fn decode_records(
+    keyring: Option<&Keyring>,
+    buf: &[u8],
+) -> io::Result<(Vec<Entry>, Vec<u64>, usize)> {
+    decode_records_stale(keyring, buf)
+        .map(|(entries, offsets, valid_length, _)| (entries, offsets, valid_length))
+}
+
+// same as decode_records, and whether some record must be sealed again by the current key
+pub fn decode_records_stale(
+    keyring: Option<&Keyring>,
+    buf: &[u8],
+) -> io::Result<(Vec<Entry>, Vec<u64>, usize, bool)> {
+    let mut entries = vec![];
+    let mut offsets = vec![];
+    let mut stale = false;
+    let mut pos: usize = 0;
+    while buf.len() - pos >= RECORD_HEADER_LEN {
+        let len = u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(buf[pos + 4..pos + 8].try_into().unwrap());
+        let start = pos + RECORD_HEADER_LEN;
+        if buf.len() - start < len {
+            break;
+        }
+        let payload = &buf[start..start + len];
+        if crc32fast::hash(payload) != crc {
+            break;
+        }
+        let entry = match cipher::open_entry(keyring, entries.len() as u64, payload) {
+            Ok((entry, stale_record)) => {
+                stale |= stale_record;
+                entry
+            }
+            Err(e) if cipher::is_sealed(payload) => return Err(e),
+            Err(_) => break,
+        };
+        entries.push(entry);
+        offsets.push(pos as u64);
+        pos = start + len;
+    }
+    Ok((entries, offsets, pos, stale))
+}
+
+fn create_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => fs::create_dir_all(dir),
+        _ => Ok(()),
+    }
+}
+
+pub struct FileLogStore {
+    path: PathBuf,
+    file: Arc<File>,
+    // offsets[i] is the byte offset of the record of i-th entry
+    offsets: Vec<u64>,
+    end_offset: u64,
+    keyring: Option<Arc<Keyring>>,
+}
+
+impl FileLogStore {
+    pub fn open(path: &Path, keyring: Option<Arc<Keyring>>) -> io::Result<FileLogStore> {
+        create_parent_dir(path)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(FileLogStore {
+            path: path.to_path_buf(),
+            file: Arc::new(file),
+            offsets: vec![],
+            end_offset: 0,
+            keyring,
+        })
+    }
+
+    // Replace the file by the records of entries, sealed by the current key.
+    fn rewrite(&mut self, entries: &[Entry]) -> io::Result<()> {
+        let mut file = AtomicWriteFile::options().open(&self.path)?;
+        let mut offsets = vec![];
+        let mut end_offset = 0;
+        for (index, entry) in entries.iter().enumerate() {
+            let record = encode_record(self.keyring.as_deref(), index as u64, entry);
+            file.write_all(&record)?;
+            offsets.push(end_offset);
+            end_offset += record.len() as u64;
+        }
+        file.commit()?;
+        self.file = Arc::new(OpenOptions::new().read(true).write(true).open(&self.path)?);
+        self.offsets = offsets;
+        self.end_offset = end_offset;
+        Ok(())
+    }
+}
+
+impl LogStore for FileLogStore {
+    // A torn record at the end of the file (e.g. crash during write) is discarded.
+    // Records which are plain or sealed by a previous key are sealed again by the current key.
+    fn load(&mut self) -> io::Result<Vec<Entry>> {
+        let mut buf = vec![];
+        let mut file: &File = &self.file;
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut buf)?;
+
+        let (entries, offsets, valid_length, stale) =
+            decode_records_stale(self.keyring.as_deref(), &buf)
+                .map_err(|e| io::Error::new(e.kind(), format!("{} in {:?}", e, self.path)))?;
+        if stale {
+            info!(
+                "re-encrypt {} entries of {:?} with the current key",
+                entries.len(),
+                self.path
+            );
+            self.rewrite(&entries)?;
+            return Ok(entries);
+        }
+        if valid_length < buf.len() {
+            warn!(
+                "discard {} bytes of torn WAL tail in {:?}",
+                buf.len() - valid_length,
+                self.path
+            );
+            file.set_len(valid_length as u64)?;
+            file.sync_data()?;
+        }
+        self.offsets = offsets;
+        self.end_offset = valid_length as u64;
+        Ok(entries)
+    }
+
+    fn append(&mut self, entries: &[Entry]) -> io::Result<()> {
+        let mut buf = vec![];
+        let mut offsets = vec![];
+        for entry in entries {
+            let index = (self.offsets.len() + offsets.len()) as u64;
+            offsets.push(self.end_offset + buf.len() as u64);
+            buf.extend_from_slice(&encode_record(self.keyring.as_deref(), index, entry));
+        }
+        let mut file: &File = &self.file;
+        file.seek(SeekFrom::Start(self.end_offset))?;
+        file.write_all(&buf)?;
+        self.offsets.extend(offsets);
+        self.end_offset += buf.len() as u64;
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        if len as usize >= self.offsets.len() {
+            return Ok(());
+        }
+        let offset = self.offsets[len as usize];
+        // NB : no need to sync here. Entries written after the truncation are synced
+        // together with the new file length.
+        self.file.set_len(offset)?;
+        self.offsets.truncate(len as usize);
+        self.end_offset = offset;
+        Ok(())
+    }
+
+    fn sync_handle(&self) -> SyncHandle {
+        let file = self.file.clone();
+        Arc::new(move || file.sync_data())
+    }
+}
+
+// Stored as two lines : current term, and the id voted for (empty if none).
+pub struct FileStableStore {
+    path: PathBuf,
+}
+
+impl FileStableStore {
+    pub fn open(path: &Path) -> io::Result<FileStableStore> {
+        create_parent_dir(path)?;
+        Ok(FileStableStore {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl StableStore for FileStableStore {
+    fn load(&mut self) -> io::Result<Option<StableState>> {
+        let s = match fs::read_to_string(&self.path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed persistent state");
+        let mut lines = s.lines();
+        let current_term = lines
+            .next()
+            .and_then(|l| l.parse::<u64>().ok())
+            .ok_or_else(invalid)?;
+        let voted_for = match lines.next() {
+            None | Some("") => None,
+            Some(id) => Some(id.to_string()),
+        };
+        Ok(Some((current_term, voted_for)))
+    }
+
+    fn save(&mut self, current_term: u64, voted_for: Option<&str>) -> io::Result<()> {
+        let mut file = AtomicWriteFile::options().open(&self.path)?;
+        writeln!(file, "{}", current_term)?;
+        writeln!(file, "{}", voted_for.unwrap_or(""))?;
+        file.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileLogStore, LogStore};
+    use crate::raftchat_tonic::Entry;
+    use crate::storage::cipher::Keyring;
+    use std::sync::Arc;
+
+    #[test]
+    fn case_torn_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal");
+        let entry = |term| Entry {
+            term,
+            command: None,
+            membership: None,
+            time: 0,
+            session: None,
+        };
+
+        let mut store = FileLogStore::open(&path, None).unwrap();
+        store.load().unwrap();
+        store.append(&[entry(1), entry(2)]).unwrap();
+        drop(store);
+
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 1).unwrap();
+        drop(file);
+
+        let mut store = FileLogStore::open(&path, None).unwrap();
+        assert_eq!(store.load().unwrap(), vec![entry(1)]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), store.end_offset);
+    }
+
+    #[test]
+    fn case_encryption() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal");
+        let key = |hex: &str| Some(Arc::new(Keyring::parse(hex).unwrap()));
+        let old = "11".repeat(32);
+        let new = "22".repeat(32);
+        let entry = |term| Entry {
+            term,
+            command: None,
+            membership: None,
+            time: 0,
+            session: None,
+        };
+
+        let mut store = FileLogStore::open(&path, None).unwrap();
+        store.load().unwrap();
+        store.append(&[entry(1)]).unwrap();
+        drop(store);
+
+        // a plain log is encrypted on load
+        let mut store = FileLogStore::open(&path, key(&old)).unwrap();
+        assert_eq!(store.load().unwrap(), vec![entry(1)]);
+        store.append(&[entry(2)]).unwrap();
+        drop(store);
+        assert!(FileLogStore::open(&path, None).unwrap().load().is_err());
+        assert!(FileLogStore::open(&path, key(&new))
+            .unwrap()
+            .load()
+            .is_err());
+
+        // rotation
+        let rotated = format!("{}\n{}", new, old);
+        let mut store = FileLogStore::open(&path, key(&rotated)).unwrap();
+        assert_eq!(store.load().unwrap(), vec![entry(1), entry(2)]);
+        store.append(&[entry(3)]).unwrap();
+        drop(store);
+        let mut store = FileLogStore::open(&path, key(&new)).unwrap();
+        assert_eq!(store.load().unwrap(), vec![entry(1), entry(2), entry(3)]);
+    }
+}
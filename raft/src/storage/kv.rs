@@ -0,0 +1,136 @@
+// Storage backed by an embedded key-value store (sled).
+// Log entries are keyed by their big-endian index, so that iteration follows log order.
+// Values are sealed if a keyring is given (see cipher).
+
+use super::cipher::{self, Keyring};
+use super::{LogStore, StableState, StableStore, SyncHandle};
+use crate::raftchat_tonic::Entry;
+use log::info;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+const CURRENT_TERM_KEY: &[u8] = b"current_term";
+const VOTED_FOR_KEY: &[u8] = b"voted_for";
+
+fn invalid_data<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+pub struct KvLogStore {
+    db: sled::Db,
+    len: u64,
+    keyring: Option<Arc<Keyring>>,
+}
+
+impl KvLogStore {
+    pub fn open(path: &Path, keyring: Option<Arc<Keyring>>) -> io::Result<KvLogStore> {
+        Ok(KvLogStore {
+            db: sled::open(path)?,
+            len: 0,
+            keyring,
+        })
+    }
+}
+
+impl LogStore for KvLogStore {
+    // Values which are plain or sealed by a previous key are sealed again by the current key.
+    fn load(&mut self) -> io::Result<Vec<Entry>> {
+        let mut entries = vec![];
+        let mut stale = sled::Batch::default();
+        let mut stale_count = 0;
+        for kv in self.db.iter() {
+            let (key, value) = kv?;
+            let index = u64::from_be_bytes(key.as_ref().try_into().map_err(invalid_data)?);
+            if index != entries.len() as u64 {
+                return Err(invalid_data(format!("missing log entry {}", entries.len())));
+            }
+            let (entry, stale_value) =
+                cipher::open_entry(self.keyring.as_deref(), index, value.as_ref())?;
+            if stale_value {
+                let value = cipher::seal_entry(self.keyring.as_deref(), index, &entry);
+                stale.insert(key, value);
+                stale_count += 1;
+            }
+            entries.push(entry);
+        }
+        if stale_count > 0 {
+            info!(
+                "re-encrypt {} log entries with the current key",
+                stale_count
+            );
+            self.db.apply_batch(stale)?;
+            self.db.flush()?;
+        }
+        self.len = entries.len() as u64;
+        Ok(entries)
+    }
+
+    fn append(&mut self, entries: &[Entry]) -> io::Result<()> {
+        let mut batch = sled::Batch::default();
+        for (i, entry) in entries.iter().enumerate() {
+            let index = self.len + i as u64;
+            let value = cipher::seal_entry(self.keyring.as_deref(), index, entry);
+            batch.insert(&index.to_be_bytes(), value);
+        }
+        self.db.apply_batch(batch)?;
+        self.len += entries.len() as u64;
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        if len >= self.len {
+            return Ok(());
+        }
+        let mut batch = sled::Batch::default();
+        for index in len..self.len {
+            batch.remove(&index.to_be_bytes());
+        }
+        self.db.apply_batch(batch)?;
+        self.len = len;
+        Ok(())
+    }
+
+    fn sync_handle(&self) -> SyncHandle {
+        let db = self.db.clone();
+        Arc::new(move || db.flush().map(|_| ()).map_err(io::Error::from))
+    }
+}
+
+pub struct KvStableStore {
+    db: sled::Db,
+}
+
+impl KvStableStore {
+    pub fn open(path: &Path) -> io::Result<KvStableStore> {
+        Ok(KvStableStore {
+            db: sled::open(path)?,
+        })
+    }
+}
+
+impl StableStore for KvStableStore {
+    fn load(&mut self) -> io::Result<Option<StableState>> {
+        let Some(term) = self.db.get(CURRENT_TERM_KEY)? else {
+            return Ok(None);
+        };
+        let current_term = u64::from_be_bytes(term.as_ref().try_into().map_err(invalid_data)?);
+        let voted_for = match self.db.get(VOTED_FOR_KEY)? {
+            Some(id) => Some(String::from_utf8(id.to_vec()).map_err(invalid_data)?),
+            None => None,
+        };
+        Ok(Some((current_term, voted_for)))
+    }
+
+    fn save(&mut self, current_term: u64, voted_for: Option<&str>) -> io::Result<()> {
+        let mut batch = sled::Batch::default();
+        batch.insert(CURRENT_TERM_KEY, &current_term.to_be_bytes());
+        match voted_for {
+            Some(id) => batch.insert(VOTED_FOR_KEY, id.as_bytes()),
+            None => batch.remove(VOTED_FOR_KEY),
+        }
+        self.db.apply_batch(batch)?;
+        self.db.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+// In-memory storage, for tests and for running a throwaway cluster.
+// Clones share the same storage, so dropping and "reopening" a store keeps its content.
+
+use super::{LogStore, StableState, StableStore, SyncHandle};
+use crate::raftchat_tonic::Entry;
+use parking_lot::Mutex;
+use std::io;
+use std::sync::Arc;
+
+#[derive(Clone, Default)]
+pub struct MemLogStore {
+    entries: Arc<Mutex<Vec<Entry>>>,
+}
+
+impl MemLogStore {
+    pub fn new() -> MemLogStore {
+        MemLogStore::default()
+    }
+}
+
+impl LogStore for MemLogStore {
+    fn load(&mut self) -> io::Result<Vec<Entry>> {
+        Ok(self.entries.lock().clone())
+    }
+
+    fn append(&mut self, entries: &[Entry]) -> io::Result<()> {
+        self.entries.lock().extend_from_slice(entries);
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.entries.lock().truncate(len as usize);
+        Ok(())
+    }
+
+    fn sync_handle(&self) -> SyncHandle {
+        Arc::new(|| Ok(()))
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct MemStableStore {
+    state: Arc<Mutex<Option<StableState>>>,
+}
+
+impl MemStableStore {
+    pub fn new() -> MemStableStore {
+        MemStableStore::default()
+    }
+}
+
+impl StableStore for MemStableStore {
+    fn load(&mut self) -> io::Result<Option<StableState>> {
+        Ok(self.state.lock().clone())
+    }
+
+    fn save(&mut self, current_term: u64, voted_for: Option<&str>) -> io::Result<()> {
+        *self.state.lock() = Some((current_term, voted_for.map(String::from)));
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+// Stable storage for the log and for term/vote metadata.
+
+pub mod cipher;
+pub mod file;
+pub mod kv;
+pub mod memory;
+
+use crate::raftchat_tonic::Entry;
+use cipher::Keyring;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// Makes every entry appended before the handle was taken durable. Blocking.
+// It must be callable without holding the store, so that the raft state lock is not
+// held during fsync.
+pub type SyncHandle = Arc<dyn Fn() -> io::Result<()> + Send + Sync>;
+
+// (current_term, voted_for)
+pub type StableState = (u64, Option<String>);
+
+pub trait LogStore: Send {
+    // Read every stored entry. Called once, before any other method.
+    fn load(&mut self) -> io::Result<Vec<Entry>>;
+
+    // Append entries at the end of the log. They are not durable until synced.
+    fn append(&mut self, entries: &[Entry]) -> io::Result<()>;
+
+    // Drop every entry from index len.
+    fn truncate(&mut self, len: u64) -> io::Result<()>;
+
+    fn sync_handle(&self) -> SyncHandle;
+}
+
+pub trait StableStore: Send {
+    // return None if nothing was saved yet
+    fn load(&mut self) -> io::Result<Option<StableState>>;
+
+    // Durably save term and vote together. Both must be updated atomically.
+    fn save(&mut self, current_term: u64, voted_for: Option<&str>) -> io::Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageBackend {
+    File,
+    Memory,
+    Kv,
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(StorageBackend::File),
+            "memory" => Ok(StorageBackend::Memory),
+            "kv" => Ok(StorageBackend::Kv),
+            _ => Err(format!("unknown storage backend : {}", s)),
+        }
+    }
+}
+
+// Entries are encrypted at rest with the keyring, if any. The memory backend keeps them plain.
+pub fn open_log_store(
+    backend: StorageBackend,
+    path: &Path,
+    keyring: Option<Arc<Keyring>>,
+) -> io::Result<Box<dyn LogStore>> {
+    Ok(match backend {
+        StorageBackend::File => Box::new(file::FileLogStore::open(path, keyring)?),
+        StorageBackend::Memory => Box::new(memory::MemLogStore::new()),
+        StorageBackend::Kv => Box::new(kv::KvLogStore::open(path, keyring)?),
+    })
+}
+
+pub fn open_stable_store(backend: StorageBackend, path: &Path) -> io::Result<Box<dyn StableStore>> {
+    Ok(match backend {
+        StorageBackend::File => Box::new(file::FileStableStore::open(path)?),
+        StorageBackend::Memory => Box::new(memory::MemStableStore::new()),
+        StorageBackend::Kv => Box::new(kv::KvStableStore::open(path)?),
+    })
+}
+
+// Conformance tests shared by every implementation.
+// Each test takes a function opening the same underlying storage, and reopens it to check
+// what survived.
+#[cfg(test)]
+mod tests {
+    use super::{cipher::Keyring, file, kv, memory, LogStore, StableStore};
+    use crate::raftchat_tonic::{Command, Entry};
+    use std::sync::Arc;
+
+    fn mk_keyring() -> Option<Arc<Keyring>> {
+        Some(Arc::new(Keyring::parse(&"5a".repeat(32)).unwrap()))
+    }
+
+    fn mk_entry(term: u64, message_id: u64) -> Entry {
+        Entry {
+            term,
+            command: Some(Command {
+                client_id: "client1".to_string(),
+                message_id,
+                data: vec![term as u8; 3],
+                stripped: false,
+            }),
+            membership: None,
+            time: 0,
+            session: None,
+        }
+    }
+
+    fn log_append_and_reload(mut open: impl FnMut() -> Box<dyn LogStore>) {
+        let mut store = open();
+        assert_eq!(store.load().unwrap(), vec![]);
+        store.append(&[mk_entry(1, 1), mk_entry(1, 2)]).unwrap();
+        store.append(&[mk_entry(2, 3)]).unwrap();
+        (store.sync_handle())().unwrap();
+        drop(store);
+
+        let mut store = open();
+        assert_eq!(
+            store.load().unwrap(),
+            vec![mk_entry(1, 1), mk_entry(1, 2), mk_entry(2, 3)]
+        );
+    }
+
+    fn log_truncate(mut open: impl FnMut() -> Box<dyn LogStore>) {
+        let mut store = open();
+        store.load().unwrap();
+        store
+            .append(&[mk_entry(1, 1), mk_entry(1, 2), mk_entry(1, 3)])
+            .unwrap();
+        store.truncate(1).unwrap();
+        store.append(&[mk_entry(2, 4)]).unwrap();
+        store.truncate(5).unwrap(); // no-op
+        (store.sync_handle())().unwrap();
+        drop(store);
+
+        let mut store = open();
+        assert_eq!(store.load().unwrap(), vec![mk_entry(1, 1), mk_entry(2, 4)]);
+        store.truncate(0).unwrap();
+        store.append(&[mk_entry(3, 5)]).unwrap();
+        (store.sync_handle())().unwrap();
+        drop(store);
+
+        let mut store = open();
+        assert_eq!(store.load().unwrap(), vec![mk_entry(3, 5)]);
+    }
+
+    fn stable_save_and_reload(mut open: impl FnMut() -> Box<dyn StableStore>) {
+        let mut store = open();
+        assert_eq!(store.load().unwrap(), None);
+        store.save(3, Some("http://127.0.0.1:3010")).unwrap();
+        drop(store);
+
+        let mut store = open();
+        assert_eq!(
+            store.load().unwrap(),
+            Some((3, Some("http://127.0.0.1:3010".to_string())))
+        );
+        store.save(4, None).unwrap();
+        drop(store);
+
+        let mut store = open();
+        assert_eq!(store.load().unwrap(), Some((4, None)));
+    }
+
+    fn run_log_suite(open: impl Fn() -> Box<dyn LogStore>, reset: impl Fn()) {
+        log_append_and_reload(&open);
+        reset();
+        log_truncate(&open);
+    }
+
+    #[test]
+    fn file_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal");
+        run_log_suite(
+            || Box::new(file::FileLogStore::open(&path, None).unwrap()),
+            || std::fs::remove_file(&path).unwrap(),
+        );
+
+        let path = dir.path().join("sealed_wal");
+        run_log_suite(
+            || Box::new(file::FileLogStore::open(&path, mk_keyring()).unwrap()),
+            || std::fs::remove_file(&path).unwrap(),
+        );
+
+        let path = dir.path().join("persistent_state");
+        stable_save_and_reload(|| Box::new(file::FileStableStore::open(&path).unwrap()));
+    }
+
+    #[test]
+    fn memory_store() {
+        let log = memory::MemLogStore::new();
+        run_log_suite(
+            || Box::new(log.clone()),
+            || log.clone().truncate(0).unwrap(),
+        );
+
+        let stable = memory::MemStableStore::new();
+        stable_save_and_reload(|| Box::new(stable.clone()));
+    }
+
+    // The flush thread of a dropped sled db may still hold its file lock for a moment.
+    fn retry_open<T>(open: impl Fn() -> std::io::Result<T>) -> T {
+        for _ in 0..100 {
+            if let Ok(store) = open() {
+                return store;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        open().unwrap()
+    }
+
+    #[test]
+    fn kv_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal");
+        run_log_suite(
+            || Box::new(retry_open(|| kv::KvLogStore::open(&path, None))),
+            // NB : removing the directory races with the background flush of sled
+            || {
+                retry_open(|| kv::KvLogStore::open(&path, None))
+                    .truncate(0)
+                    .unwrap()
+            },
+        );
+
+        let path = dir.path().join("sealed_wal");
+        run_log_suite(
+            || Box::new(retry_open(|| kv::KvLogStore::open(&path, mk_keyring()))),
+            || {
+                retry_open(|| kv::KvLogStore::open(&path, mk_keyring()))
+                    .truncate(0)
+                    .unwrap()
+            },
+        );
+
+        let path = dir.path().join("persistent_state");
+        stable_save_and_reload(|| Box::new(retry_open(|| kv::KvStableStore::open(&path))));
+    }
+}
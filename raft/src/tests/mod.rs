@@ -0,0 +1,663 @@
+// Raft nodes run in this process on a VirtualClock, talking through their RPC servers on
+// localhost. Time only moves by advance, so the timers of a test are reproducible, while the
+// RPCs and the WAL syncs take a little real time : wait for their effects with eventually.
+// Every node runs on a runtime of its own, so that it can be killed.
+// NB : a node is dropped within the test, where a runtime cannot block, so it is shut down in
+// the background.
+// NB : every node also has the timer of balance_leaders pending on the clock.
+
+use crate::clock::VirtualClock;
+use crate::raftchat_tonic::admin_server::Admin;
+use crate::raftchat_tonic::raft_chat_server::RaftChat;
+use crate::raftchat_tonic::{AppendEntriesArgs, Command, Entry, RequestVoteArgs};
+use crate::raftchat_tonic::{StatusArgs, TransferLeadershipArgs, TriggerSnapshotArgs};
+use crate::raftchat_tonic::{UserRequestArgs, UserRequestRes};
+use crate::storage::StorageBackend;
+use crate::{metrics, run_raft_groups, witness, MyRaftChat, RaftConfig, Role, UserRequest};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::{SocketAddr, TcpListener};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::{self, Runtime};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time;
+use tonic::{Code, Request, Status};
+use tracing::Span;
+
+pub fn run(test: impl Future<Output = ()>) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let res = panic::catch_unwind(AssertUnwindSafe(|| rt.block_on(test)));
+    rt.shutdown_background();
+    if let Err(e) = res {
+        panic::resume_unwind(e);
+    }
+}
+
+// ids of n nodes, which are the urls of free local ports
+pub fn node_ids(n: usize) -> Vec<&'static str> {
+    (0..n)
+        .map(|_| {
+            let addr = TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            &*format!("http://{}", addr).leak()
+        })
+        .collect()
+}
+
+pub fn mk_config(
+    self_id: &'static str,
+    members: &[&'static str],
+    clock: &Arc<VirtualClock>,
+) -> RaftConfig {
+    let serve_addr: SocketAddr = self_id
+        .strip_prefix("http://")
+        .unwrap_or("127.0.0.1:0")
+        .parse()
+        .unwrap();
+    RaftConfig {
+        serve_addr,
+        group_id: 0,
+        self_id,
+        members: members.to_vec(),
+        election_duration: (150, 300),
+        priorities: HashMap::new(),
+        witnesses: HashSet::new(),
+        heartbeat_duration: Duration::from_millis(50),
+        adaptive_timeout: false,
+        wal_sync_delay: Duration::ZERO,
+        session_timeout: Duration::from_secs(60),
+        max_pending: 16,
+        max_chunk_bytes: 1 << 16,
+        catch_up_rate: 0,
+        rpc_timeout: Duration::from_secs(1),
+        balance_interval: Duration::from_secs(5),
+        storage_backend: StorageBackend::Memory,
+        persistent_state_path: Path::new("unused"),
+        wal_path: Path::new("unused"),
+        snapshot_path: Path::new("unused"),
+        keyring: None,
+        clock: clock.clone(),
+    }
+}
+
+pub struct TestNode {
+    pub raft: Arc<MyRaftChat>,
+    req_tx: mpsc::Sender<UserRequest>,
+    _log_rx: mpsc::Receiver<Entry>,
+    rt: Option<Runtime>,
+}
+
+impl TestNode {
+    pub fn start(config: RaftConfig) -> TestNode {
+        // NB : the WAL sync and the snapshot writes are the only blocking tasks of a group
+        let rt = runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .max_blocking_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let (log_tx, log_rx) = mpsc::channel(1024);
+        let (req_tx, req_rx) = mpsc::channel(1024);
+        let raft = {
+            let _enter = rt.enter();
+            run_raft_groups(vec![(config, log_tx, req_rx)]).remove(0)
+        };
+        TestNode {
+            raft,
+            req_tx,
+            _log_rx: log_rx,
+            rt: Some(rt),
+        }
+    }
+
+    // stop the tasks and the RPC server of the node, as a crash
+    pub fn kill(self) {
+        drop(self);
+    }
+
+    pub fn is_leader(&self) -> bool {
+        matches!(self.raft.state.lock().role, Role::Leader(_))
+    }
+
+    pub fn is_candidate(&self) -> bool {
+        matches!(self.raft.state.lock().role, Role::Candidate(_))
+    }
+
+    pub fn term(&self) -> u64 {
+        self.raft.state.lock().persistent_state.current_term()
+    }
+
+    // send a user request as the web server does, and return the channel of its result
+    pub async fn request(
+        &self,
+        client_id: &str,
+        message_id: u64,
+    ) -> oneshot::Receiver<Result<UserRequestRes, Status>> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let args = UserRequestArgs {
+            client_id: String::from(client_id),
+            message_id,
+            data: vec![],
+            group_id: 0,
+        };
+        self.req_tx
+            .send(UserRequest {
+                args,
+                span: Span::none(),
+                result_tx,
+            })
+            .await
+            .unwrap();
+        result_rx
+    }
+}
+
+impl Drop for TestNode {
+    fn drop(&mut self) {
+        if let Some(rt) = self.rt.take() {
+            rt.shutdown_background();
+        }
+    }
+}
+
+// poll cond while the tasks of the nodes run, for 5s of real time at most
+pub async fn eventually(cond: impl Fn() -> bool) -> bool {
+    for _ in 0..500 {
+        if cond() {
+            return true;
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    cond()
+}
+
+// advance the clock by steps of 10ms until cond, letting the RPCs of each step complete
+// return false if cond is still false after max
+pub async fn tick_until(clock: &VirtualClock, max: Duration, cond: impl Fn() -> bool) -> bool {
+    let step = Duration::from_millis(10);
+    let mut elapsed = Duration::ZERO;
+    while !cond() {
+        if elapsed >= max {
+            return false;
+        }
+        clock.advance(step);
+        elapsed += step;
+        time::sleep(Duration::from_millis(5)).await;
+    }
+    true
+}
+
+// let the tasks woken up by the last advance run
+pub async fn settle() {
+    time::sleep(Duration::from_millis(50)).await;
+}
+
+// a lone node elects itself once its election timeout passes on the virtual clock
+#[test]
+fn case_virtual_election() {
+    run(async {
+        let clock = VirtualClock::new(1);
+        let node = TestNode::start(mk_config("a", &["a"], &clock));
+        assert!(eventually(|| clock.pending() > 1).await);
+
+        clock.advance(Duration::from_millis(149));
+        settle().await;
+        assert!(!node.is_leader());
+        assert_eq!(node.term(), 0);
+
+        clock.advance(Duration::from_millis(151));
+        assert!(eventually(|| node.is_leader()).await);
+        assert_eq!(node.term(), 1);
+    });
+}
+
+// the deadline of an RPC to a peer expires on the clock
+#[test]
+fn case_virtual_deadline() {
+    run(async {
+        let clock = VirtualClock::new(1);
+        let node = TestNode::start(mk_config("a", &["a"], &clock));
+        let raft = node.raft.clone();
+        let rpc = tokio::spawn(async move {
+            raft.with_deadline(std::future::pending::<Result<(), Status>>())
+                .await
+        });
+        assert!(eventually(|| clock.pending() > 2).await);
+
+        clock.advance(Duration::from_millis(999));
+        settle().await;
+        assert!(!rpc.is_finished());
+        clock.advance(Duration::from_millis(1));
+        let res = rpc.await.unwrap();
+        assert_eq!(res.unwrap_err().code(), Code::DeadlineExceeded);
+    });
+}
+
+fn append_args(term: u64, prev_length: u64, prev_term: u64, terms: &[u64]) -> AppendEntriesArgs {
+    let entries: Vec<Entry> = terms
+        .iter()
+        .map(|&term| Entry {
+            term,
+            command: None,
+            membership: None,
+            time: 0,
+            session: None,
+        })
+        .collect();
+    AppendEntriesArgs {
+        term,
+        leader_id: String::from("leader"),
+        prev_length,
+        prev_term,
+        entries,
+        committed_length: 0,
+        group_id: 0,
+        rtt_us: 0,
+    }
+}
+
+// a follower overwriting synced entries with fewer ones acks them once synced again
+#[test]
+fn case_ack_after_truncation() {
+    run(async {
+        let clock = VirtualClock::new(1);
+        let mut config = mk_config("a", &["a"], &clock);
+        config.wal_sync_delay = Duration::from_millis(10);
+        let node = TestNode::start(config);
+        let append = |args| {
+            let raft = node.raft.clone();
+            tokio::spawn(async move { raft.append_entries(Request::new(args)).await })
+        };
+
+        let res = append(append_args(1, 0, 0, &[1; 10]));
+        settle().await;
+        assert!(!res.is_finished());
+        clock.advance(Duration::from_millis(10));
+        assert!(res.await.unwrap().unwrap().into_inner().success);
+        assert_eq!(node.raft.state.lock().sm.wal().synced_len(), 10);
+
+        // truncated to 5, then 3 new entries : 8 < 10 synced before
+        let res = append(append_args(2, 5, 1, &[2; 3]));
+        settle().await;
+        assert!(!res.is_finished());
+        assert_eq!(node.raft.state.lock().sm.wal().synced_len(), 5);
+        clock.advance(Duration::from_millis(10));
+        assert!(res.await.unwrap().unwrap().into_inner().success);
+        assert_eq!(node.raft.state.lock().sm.wal().synced_len(), 8);
+    });
+}
+
+// a vote request failed on the connection is retried, and wins within the same term
+#[test]
+fn case_vote_retry() {
+    run(async {
+        let clock = VirtualClock::new(1);
+        let ids = node_ids(2);
+        let a = TestNode::start(mk_config(ids[0], &ids, &clock));
+        let peer_failures = || {
+            a.raft
+                .state
+                .lock()
+                .peer_health
+                .get(ids[1])
+                .map_or(0, |health| health.failures)
+        };
+        assert!(eventually(|| clock.pending() > 1).await);
+        assert!(tick_until(&clock, Duration::from_millis(300), || a.is_candidate()).await);
+        assert!(eventually(|| peer_failures() > 0).await);
+
+        // the peer comes up within the election timeout of the candidate
+        let mut config = mk_config(ids[1], &ids, &clock);
+        config.election_duration = (10_000, 20_000);
+        let _b = TestNode::start(config);
+        assert!(tick_until(&clock, Duration::from_millis(140), || a.is_leader()).await);
+        assert_eq!(a.term(), 1);
+        assert_eq!(peer_failures(), 0);
+    });
+}
+
+// a candidate steps down when a peer answers its vote request with a higher term
+#[test]
+fn case_vote_higher_term() {
+    run(async {
+        let clock = VirtualClock::new(1);
+        let ids = node_ids(2);
+        let mut config = mk_config(ids[1], &ids, &clock);
+        config.election_duration = (10_000, 20_000);
+        let b = TestNode::start(config);
+        b.raft.state.lock().persistent_state.update_term(5);
+        let a = TestNode::start(mk_config(ids[0], &ids, &clock));
+        assert!(eventually(|| clock.pending() > 3).await);
+
+        assert!(tick_until(&clock, Duration::from_millis(300), || a.term() > 0).await);
+        assert!(eventually(|| a.term() == 5 && !a.is_candidate()).await);
+        assert!(!a.is_leader());
+        assert_eq!(a.raft.state.lock().persistent_state.voted_for(), None);
+    });
+}
+
+// a node only votes for the members of its configuration
+#[test]
+fn case_vote_non_member() {
+    run(async {
+        let clock = VirtualClock::new(1);
+        let ids = node_ids(2);
+        let mut config = mk_config(ids[0], &ids, &clock);
+        config.election_duration = (10_000, 20_000);
+        let a = TestNode::start(config);
+        let vote = |candidate_id: &str, term| {
+            a.raft.request_vote(Request::new(RequestVoteArgs {
+                term,
+                candidate_id: String::from(candidate_id),
+                prev_length: 0,
+                prev_term: 0,
+                group_id: 0,
+            }))
+        };
+
+        let res = vote("http://127.0.0.1:1", 1).await.unwrap().into_inner();
+        assert!(!res.vote_granted);
+        assert_eq!(a.raft.state.lock().persistent_state.voted_for(), None);
+
+        let res = vote(ids[1], 2).await.unwrap().into_inner();
+        assert!(res.vote_granted);
+        assert_eq!(
+            a.raft.state.lock().persistent_state.voted_for(),
+            Some(ids[1])
+        );
+    });
+}
+
+// the requests beyond max_pending uncommitted entries are rejected until they are committed
+#[test]
+fn case_backpressure() {
+    run(async {
+        let clock = VirtualClock::new(1);
+        let mut config = mk_config("a", &["a"], &clock);
+        config.max_pending = 5;
+        config.wal_sync_delay = Duration::from_millis(10);
+        let node = TestNode::start(config);
+        assert!(eventually(|| clock.pending() > 1).await);
+        assert!(tick_until(&clock, Duration::from_millis(300), || node.is_leader()).await);
+
+        // the first message of a client registers its session : 2 entries each, so the third
+        // session does not fit in the last slot, while a message of an open session does
+        // NB : one at a time, the requests in flight race for the slots
+        let mut results = vec![];
+        for (client_id, message_id) in [
+            ("c0", 1),
+            ("c1", 1),
+            ("c2", 1),
+            ("c3", 1),
+            ("c4", 1),
+            ("c0", 2),
+            ("c1", 2),
+        ] {
+            results.push(node.request(client_id, message_id).await);
+            settle().await;
+        }
+        let mut pending = vec![];
+        let mut rejected = vec![];
+        for (i, mut rx) in results.into_iter().enumerate() {
+            match rx.try_recv() {
+                Ok(Err(status)) if status.code() == Code::ResourceExhausted => rejected.push(i),
+                Err(oneshot::error::TryRecvError::Empty) => pending.push(rx),
+                res => panic!("unexpected result {:?}", res),
+            }
+        }
+        assert_eq!((pending.len(), rejected), (3, vec![2, 3, 4, 6]));
+        assert_eq!(node.raft.state.lock().sm.wal().len(), 5);
+
+        clock.advance(Duration::from_millis(10));
+        for rx in pending {
+            assert!(rx.await.unwrap().unwrap().success);
+        }
+        // the bound and the permits of the requests in flight are released
+        for i in 0..5 {
+            let rx = node.request(&format!("d{}", i), 1).await;
+            settle().await;
+            clock.advance(Duration::from_millis(10));
+            assert!(rx.await.unwrap().unwrap().success);
+        }
+    });
+}
+
+// a client whose session expired is told so, and starts a new session from message id 1
+#[test]
+fn case_session_reconnect() {
+    run(async {
+        let clock = VirtualClock::new(1);
+        let mut config = mk_config("a", &["a"], &clock);
+        config.session_timeout = Duration::from_millis(50);
+        let node = TestNode::start(config);
+        assert!(eventually(|| clock.pending() > 1).await);
+        assert!(tick_until(&clock, Duration::from_millis(300), || node.is_leader()).await);
+
+        for message_id in 1..=2 {
+            let res = node.request("c", message_id).await.await.unwrap();
+            assert!(res.unwrap().success);
+        }
+        // the session expires 50ms after its last message
+        clock.advance(Duration::from_millis(49));
+        let res = node.request("c", 3).await.await.unwrap();
+        assert!(res.unwrap().success);
+        clock.advance(Duration::from_millis(50));
+        let e = node.request("c", 4).await.await.unwrap().unwrap_err();
+        assert_eq!(e.code(), Code::FailedPrecondition);
+
+        let mut index = 0;
+        for message_id in 1..=2 {
+            let res = node.request("c", message_id).await.await.unwrap().unwrap();
+            assert!(res.success);
+            index = res.index;
+        }
+        // and the new session deduplicates again
+        let res = node.request("c", 2).await.await.unwrap().unwrap();
+        assert_eq!((res.success, res.index), (true, index));
+        let state = node.raft.state.lock();
+        assert_eq!(state.sm.state().session_count(), 1);
+    });
+}
+
+// a node sent the stripped entries of a witness never campaigns, as it cannot serve the chat
+#[test]
+fn case_stripped_log() {
+    run(async {
+        let clock = VirtualClock::new(1);
+        let ids = node_ids(2);
+        let node = TestNode::start(mk_config(ids[0], &ids, &clock));
+        let entry = witness::metadata_only(&Entry {
+            term: 1,
+            command: Some(Command {
+                client_id: String::from("c"),
+                message_id: 1,
+                data: vec![1, 2, 3],
+                stripped: false,
+            }),
+            membership: None,
+            time: 0,
+            session: None,
+        });
+        let mut args = append_args(1, 0, 0, &[]);
+        args.entries.push(entry);
+        let res = node.raft.append_entries(Request::new(args)).await.unwrap();
+        assert!(res.into_inner().success);
+
+        assert!(eventually(|| clock.pending() > 1).await);
+        clock.advance(Duration::from_secs(10));
+        settle().await;
+        assert!(!node.is_candidate() && !node.is_leader());
+        assert_eq!(node.term(), 1);
+    });
+}
+
+// Two full nodes and a witness : the leader and the witness alone commit nothing, and the
+// witness gets no entry ahead of the full follower. So once the leader is lost, the full
+// follower is elected with the vote of the witness, and has every committed entry.
+#[test]
+fn case_witness_quorum() {
+    run(async {
+        let clock = VirtualClock::new(1);
+        let ids = node_ids(3);
+        let config = |id| {
+            let mut config = mk_config(id, &ids, &clock);
+            config.priorities = [(ids[0], 1)].into();
+            config.witnesses = [ids[2]].into();
+            config
+        };
+        let a = TestNode::start(config(ids[0]));
+        let w = TestNode::start(config(ids[2]));
+        assert!(eventually(|| clock.pending() > 2).await);
+        assert!(tick_until(&clock, Duration::from_secs(1), || a.is_leader()).await);
+
+        // the full follower is down
+        let res = tokio::spawn(a.request("c", 1).await);
+        assert!(!tick_until(&clock, Duration::from_millis(500), || res.is_finished()).await);
+        assert_eq!(a.raft.state.lock().committed_length, 0);
+        assert_eq!(w.raft.state.lock().sm.wal().len(), 0);
+
+        a.kill();
+        let b = TestNode::start(config(ids[1]));
+        assert!(tick_until(&clock, Duration::from_secs(2), || b.is_leader()).await);
+
+        // NB : without a full follower, the new leader commits nothing either
+        let res = tokio::spawn(b.request("d", 1).await);
+        assert!(!tick_until(&clock, Duration::from_millis(500), || res.is_finished()).await);
+        assert_eq!(b.raft.state.lock().committed_length, 0);
+    });
+}
+
+// a snapshot covers the committed log, and is found again when the node restarts
+#[test]
+fn case_snapshot_restart() {
+    run(async {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| &*Box::leak(dir.path().join(name).into_boxed_path());
+        let clock = VirtualClock::new(1);
+        let config = || {
+            let mut config = mk_config("a", &["a"], &clock);
+            config.storage_backend = StorageBackend::File;
+            config.persistent_state_path = path("state");
+            config.wal_path = path("wal");
+            config.snapshot_path = path("snapshot");
+            config
+        };
+        let node = TestNode::start(config());
+        assert!(eventually(|| clock.pending() > 1).await);
+        // NB : the vote is synced to the disk
+        assert!(tick_until(&clock, Duration::from_secs(1), || node.is_leader()).await);
+        for message_id in 1..=3 {
+            let res = node.request("c", message_id).await.await.unwrap();
+            assert!(res.unwrap().success);
+        }
+        let committed_length = node.raft.state.lock().committed_length;
+        let args = TriggerSnapshotArgs { group_id: 0 };
+        let res = node
+            .raft
+            .trigger_snapshot(Request::new(args))
+            .await
+            .unwrap();
+        assert_eq!(res.into_inner().snapshot_length, committed_length);
+        node.kill();
+
+        let node = TestNode::start(config());
+        let res = node
+            .raft
+            .status(Request::new(StatusArgs { group_id: 0 }))
+            .await
+            .unwrap();
+        assert_eq!(res.into_inner().snapshot_length, committed_length);
+    });
+}
+
+// a snapshot transfer interrupted by the crash of the leader resumes with the new leader
+#[test]
+fn case_snapshot_transfer_resume() {
+    run(async {
+        let clock = VirtualClock::new(1);
+        let ids = node_ids(3);
+        let config = |id| {
+            let mut config = mk_config(id, &ids, &clock);
+            config.max_chunk_bytes = 64;
+            config.catch_up_rate = 256;
+            config
+        };
+        let a = TestNode::start(config(ids[0]));
+        let c = TestNode::start(config(ids[2]));
+        assert!(eventually(|| clock.pending() > 2).await);
+        let elected = || a.is_leader() || c.is_leader();
+        assert!(tick_until(&clock, Duration::from_secs(1), elected).await);
+        let (first, second, second_id) = match a.is_leader() {
+            true => (a, c, ids[2]),
+            false => (c, a, ids[0]),
+        };
+        for message_id in 1..=40 {
+            let res = first.request("d", message_id).await.await.unwrap();
+            assert!(res.unwrap().success);
+        }
+        let committed_length = first.raft.state.lock().committed_length;
+        assert!(
+            tick_until(&clock, Duration::from_secs(1), || {
+                second.raft.state.lock().committed_length == committed_length
+            })
+            .await
+        );
+        // the same snapshot on both nodes
+        first.raft.write_snapshot().await.unwrap();
+        second.raft.write_snapshot().await.unwrap();
+        let (size, checksum) = {
+            let state = first.raft.state.lock();
+            let snapshot = state.snapshot.as_ref().unwrap();
+            (snapshot.image.len() as u64, snapshot.checksum)
+        };
+        let second_checksum = second.raft.state.lock().snapshot.as_ref().unwrap().checksum;
+        assert_eq!(second_checksum, checksum);
+
+        // NB : a leader elected since b went down probes the end of its log
+        let raft = first.raft.clone();
+        let args = TransferLeadershipArgs {
+            target: Some(String::from(second_id)),
+            group_id: 0,
+        };
+        tokio::spawn(async move { raft.transfer_leadership(Request::new(args)).await });
+        assert!(tick_until(&clock, Duration::from_secs(1), || second.is_leader()).await);
+
+        // the follower b was down, and gets the snapshot until half of it
+        let mut config_b = config(ids[1]);
+        config_b.election_duration = (3000, 4000);
+        let b = TestNode::start(config_b);
+        let offset = || {
+            let state = b.raft.state.lock();
+            state
+                .incoming_snapshot
+                .as_ref()
+                .map_or(0, |incoming| incoming.offset())
+        };
+        assert!(tick_until(&clock, Duration::from_secs(10), || offset() >= size / 2).await);
+        let resumed_at = offset();
+        assert!(resumed_at < size);
+
+        let sent = || metrics::CATCH_UP_BYTES.with_label_values(&[ids[1]]).get();
+        let sent_before = sent();
+        second.kill();
+        // NB : the installed snapshot is saved after its entries are committed
+        assert!(
+            tick_until(&clock, Duration::from_secs(10), || {
+                b.raft.state.lock().snapshot.is_some()
+            })
+            .await
+        );
+        assert!(first.is_leader());
+        // NB : a chunk of the crashed leader may have been lost
+        assert!(sent() - sent_before <= size - resumed_at + 64);
+        let state = b.raft.state.lock();
+        assert!(state.committed_length >= committed_length);
+        assert_eq!(state.snapshot.as_ref().unwrap().checksum, checksum);
+        assert!(state.incoming_snapshot.is_none());
+    });
+}
@@ -0,0 +1,95 @@
+// Propagation of tracing spans across nodes, through gRPC metadata.
+// The format is chosen by the global text map propagator, which is a no-op unless the
+// server installs one (see the OTLP exporter setup of the server).
+
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::TraceContextExt;
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+use tonic::Request;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+struct MetadataInjector<'a>(&'a mut MetadataMap);
+
+impl Injector for MetadataInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (
+            MetadataKey::from_bytes(key.as_bytes()),
+            MetadataValue::try_from(&value),
+        ) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+struct MetadataExtractor<'a>(&'a MetadataMap);
+
+impl Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .keys()
+            .filter_map(|key| match key {
+                tonic::metadata::KeyRef::Ascii(key) => Some(key.as_str()),
+                tonic::metadata::KeyRef::Binary(_) => None,
+            })
+            .collect()
+    }
+}
+
+// Attach the context of span to an outgoing request.
+pub fn inject<T>(span: &Span, request: &mut Request<T>) {
+    let cx = span.context();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&cx, &mut MetadataInjector(request.metadata_mut()))
+    });
+}
+
+// Make span a child of the remote span the request was sent from, if any.
+// Requests made in-process carry no context, and span keeps its local parent.
+pub fn set_remote_parent<T>(span: &Span, request: &Request<T>) {
+    let cx = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&MetadataExtractor(request.metadata()))
+    });
+    if cx.span().span_context().is_valid() {
+        span.set_parent(cx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{inject, set_remote_parent};
+    use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use tonic::Request;
+    use tracing::info_span;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn case_roundtrip() {
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+        let tracer = opentelemetry_sdk::trace::TracerProvider::builder()
+            .build()
+            .tracer("test");
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let sender = info_span!("sender");
+            let mut request = Request::new(());
+            inject(&sender, &mut request);
+            assert!(request.metadata().get("traceparent").is_some());
+
+            let receiver = info_span!("receiver");
+            set_remote_parent(&receiver, &request);
+            assert_eq!(
+                receiver.context().span().span_context().trace_id(),
+                sender.context().span().span_context().trace_id()
+            );
+        });
+    }
+}
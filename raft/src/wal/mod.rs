@@ -1,11 +1,17 @@
 // Write-Ahead-Log
 
 use crate::raftchat_tonic::Entry;
-use atomic_write_file::AtomicWriteFile;
-use std::path::Path;
+use crate::storage::memory::MemLogStore;
+use crate::storage::{LogStore, SyncHandle};
+use std::io;
 
 pub struct WAL {
     cache: Vec<Entry>,
+    store: Box<dyn LogStore>,
+    // length of the prefix of cache which is known to be on stable storage
+    synced_length: u64,
+    // bumped on every truncation, so that a sync started before it is not trusted
+    epoch: u64,
 }
 
 #[derive(Debug, PartialEq)]
@@ -14,16 +20,61 @@ pub enum Action<'a> {
     Update(u64, &'a [Entry]),
 }
 
+// A pending sync of the WAL.
+// It is taken under the raft state lock, but the (slow) sync itself runs without the lock,
+// so that every entry appended meanwhile can join the next batch.
+pub struct SyncTicket {
+    handle: SyncHandle,
+    length: u64,
+    epoch: u64,
+}
+
+impl SyncTicket {
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    // Blocking
+    pub fn sync(&self) -> io::Result<()> {
+        (self.handle)()
+    }
+}
+
 impl WAL {
-    pub fn new(path: &Path) -> WAL {
-        // TODO : init with path
-        WAL { cache: vec![] }
+    // Every entry already in the store is considered durable.
+    pub fn new(mut store: Box<dyn LogStore>) -> WAL {
+        let cache = store.load().expect("Failed to load WAL");
+        WAL {
+            synced_length: cache.len() as u64,
+            cache,
+            store,
+            epoch: 0,
+        }
+    }
+
+    pub fn in_memory() -> WAL {
+        WAL::new(Box::new(MemLogStore::new()))
+    }
+
+    // Index of the first entry kept in the log.
+    // NB : the log is never compacted yet, so every entry is kept.
+    pub fn first_index(&self) -> u64 {
+        0
     }
 
     pub fn len(&self) -> u64 {
         self.cache.len() as u64
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    // Length of the prefix of the log which is on stable storage.
+    pub fn synced_len(&self) -> u64 {
+        self.synced_length
+    }
+
     pub fn last_term(&self) -> u64 {
         match self.cache.last() {
             Some(entry) => entry.term,
@@ -48,8 +99,8 @@ impl WAL {
         &self.cache
     }
 
-    // Dummy implementation.
-    // New entry must be written on stable storage before exiting the function
+    // New entries are written to the file, but not synced.
+    // Use begin_sync and finish_sync to make them durable.
     // return None    if not matched
     // return Some(l) if matched, where l is the length of guaranteed common prefix of
     //                      the log of the leader and the log of this node.
@@ -84,8 +135,8 @@ impl WAL {
 
             // apply action to the log
             if let Action::Update(l, entries) = action {
-                self.cache.truncate(l as usize);
-                self.cache.extend_from_slice(entries);
+                self.truncate(l);
+                self.write(entries);
             };
 
             // compatible length
@@ -96,8 +147,45 @@ impl WAL {
     }
 
     pub fn propose_entry(&mut self, entry: Entry) -> u64 {
-        self.cache.push(entry);
-        return self.cache.len() as u64 - 1;
+        self.write(std::slice::from_ref(&entry));
+        self.cache.len() as u64 - 1
+    }
+
+    pub fn begin_sync(&self) -> Option<SyncTicket> {
+        if self.synced_length < self.len() {
+            Some(SyncTicket {
+                handle: self.store.sync_handle(),
+                length: self.len(),
+                epoch: self.epoch,
+            })
+        } else {
+            None
+        }
+    }
+
+    // return true if synced length is advanced
+    pub fn finish_sync(&mut self, ticket: &SyncTicket) -> bool {
+        if ticket.epoch == self.epoch && self.synced_length < ticket.length {
+            self.synced_length = ticket.length;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn truncate(&mut self, len: u64) {
+        if len >= self.len() {
+            return;
+        }
+        self.store.truncate(len).expect("Failed to truncate WAL");
+        self.cache.truncate(len as usize);
+        self.synced_length = self.synced_length.min(len);
+        self.epoch += 1;
+    }
+
+    fn write(&mut self, entries: &[Entry]) {
+        self.store.append(entries).expect("Failed to write WAL");
+        self.cache.extend_from_slice(entries);
     }
 }
 
@@ -105,29 +193,39 @@ impl WAL {
 mod tests {
 
     use crate::raftchat_tonic::{Command, Entry};
-    use crate::wal::{Action, WAL};
+    use crate::storage::file::FileLogStore;
+    use crate::wal::{Action, SyncTicket, WAL};
+    use proptest::prelude::*;
 
     fn mk_entry(term: u64) -> Entry {
         Entry {
-            term: term,
+            term,
             command: Some(Command {
                 client_id: "client1".to_string(),
                 message_id: 0,
                 data: vec![],
+                stripped: false,
             }),
+            membership: None,
+            time: 0,
+            session: None,
         }
     }
 
+    fn mk_wal(entries: Vec<Entry>) -> WAL {
+        let mut wal = WAL::in_memory();
+        wal.write(&entries);
+        wal
+    }
+
     #[test]
     #[rustfmt::skip]
     fn case_append() {
-        let mut state = WAL {
-            cache: vec![
-                mk_entry(1),
-                mk_entry(2),
-                mk_entry(3),
-            ],
-        };
+        let mut state = mk_wal(vec![
+            mk_entry(1),
+            mk_entry(2),
+            mk_entry(3),
+        ]);
 
         assert_eq!(
             state.append_entries(
@@ -156,13 +254,11 @@ mod tests {
     #[test]
     #[rustfmt::skip]
     fn case_rewrite() {
-        let mut state = WAL {
-            cache: vec![
-                mk_entry(1),
-                mk_entry(2),
-                mk_entry(3),
-            ],
-        };
+        let mut state = mk_wal(vec![
+            mk_entry(1),
+            mk_entry(2),
+            mk_entry(3),
+        ]);
 
         assert_eq!(
             state.append_entries(
@@ -189,13 +285,11 @@ mod tests {
     #[test]
     #[rustfmt::skip]
     fn case_subsumed() {
-        let mut state = WAL {
-            cache: vec![
-                mk_entry(1),
-                mk_entry(2),
-                mk_entry(3),
-            ],
-        };
+        let mut state = mk_wal(vec![
+            mk_entry(1),
+            mk_entry(2),
+            mk_entry(3),
+        ]);
 
         assert_eq!(
             state.append_entries(
@@ -216,4 +310,156 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn case_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal");
+
+        let mut wal = WAL::new(Box::new(FileLogStore::open(&path, None).unwrap()));
+        wal.append_entries(0, 0, &[mk_entry(1), mk_entry(2), mk_entry(3)]);
+        wal.append_entries(2, 2, &[mk_entry(4)]);
+        assert_eq!(wal.synced_len(), 0);
+
+        let ticket = wal.begin_sync().unwrap();
+        ticket.sync().unwrap();
+        assert!(wal.finish_sync(&ticket));
+        assert_eq!(wal.synced_len(), 3);
+        assert!(wal.begin_sync().is_none());
+        drop(wal);
+
+        let wal = WAL::new(Box::new(FileLogStore::open(&path, None).unwrap()));
+        assert_eq!(wal.as_slice(), &[mk_entry(1), mk_entry(2), mk_entry(4)]);
+    }
+
+    #[test]
+    fn case_stale_sync() {
+        let mut wal = WAL::in_memory();
+        wal.append_entries(0, 0, &[mk_entry(1), mk_entry(1)]);
+        let ticket = wal.begin_sync().unwrap();
+
+        // truncation while the sync is in flight
+        wal.append_entries(1, 1, &[mk_entry(2)]);
+        ticket.sync().unwrap();
+        assert!(!wal.finish_sync(&ticket));
+        assert_eq!(wal.synced_len(), 0);
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        // prev_length is taken modulo the length of the log + 2, so that it is sometimes too long
+        Append {
+            prev_length: usize,
+            prev_term_ok: bool,
+            terms: Vec<u64>,
+        },
+        Propose(u64),
+        BeginSync,
+        FinishSync,
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            4 => (any::<usize>(), any::<bool>(), prop::collection::vec(1..4u64, 0..5)).prop_map(
+                |(prev_length, prev_term_ok, terms)| Op::Append {
+                    prev_length,
+                    prev_term_ok,
+                    terms,
+                }
+            ),
+            1 => (1..4u64).prop_map(Op::Propose),
+            1 => Just(Op::BeginSync),
+            1 => Just(Op::FinishSync),
+        ]
+    }
+
+    // Reference log : the terms of the entries.
+    // The entries after prev_length replace the log from the first one of another term on.
+    fn model_append<'a>(
+        log: &mut Vec<u64>,
+        prev_length: usize,
+        prev_term: u64,
+        entries: &'a [Entry],
+    ) -> Option<Action<'a>> {
+        if prev_length > log.len() || prev_length > 0 && log[prev_length - 1] != prev_term {
+            return None;
+        }
+        let same = entries
+            .iter()
+            .enumerate()
+            .take_while(|(i, entry)| log.get(prev_length + i) == Some(&entry.term))
+            .count();
+        if same == entries.len() {
+            return Some(Action::Id((prev_length + same) as u64));
+        }
+        log.truncate(prev_length + same);
+        log.extend(entries[same..].iter().map(|entry| entry.term));
+        Some(Action::Update(
+            (prev_length + same) as u64,
+            &entries[same..],
+        ))
+    }
+
+    proptest! {
+        #[test]
+        fn prop_append_entries(ops in prop::collection::vec(op(), 1..40)) {
+            let mut wal = WAL::in_memory();
+            let mut log: Vec<u64> = vec![];
+            let mut synced: u64 = 0;
+            // pending sync, and whether the log was truncated since it began
+            let mut pending: Option<(SyncTicket, bool)> = None;
+
+            for op in ops {
+                match op {
+                    Op::Append { prev_length, prev_term_ok, terms } => {
+                        let prev_length = prev_length % (log.len() + 2);
+                        let prev_term = match prev_length.checked_sub(1) {
+                            Some(i) if prev_term_ok && i < log.len() => log[i],
+                            _ => prev_length as u64 % 3,
+                        };
+                        let entries: Vec<Entry> = terms.into_iter().map(mk_entry).collect();
+                        let len = log.len();
+                        let expected = model_append(&mut log, prev_length, prev_term, &entries);
+                        if let Some(Action::Update(l, _)) = expected {
+                            if (l as usize) < len {
+                                synced = synced.min(l);
+                                if let Some((_, truncated)) = &mut pending {
+                                    *truncated = true;
+                                }
+                            }
+                        }
+                        let action = wal.append_entries(prev_length as u64, prev_term, &entries);
+                        prop_assert_eq!(action, expected);
+                    }
+                    Op::Propose(term) => {
+                        prop_assert_eq!(wal.propose_entry(mk_entry(term)), log.len() as u64);
+                        log.push(term);
+                    }
+                    Op::BeginSync => {
+                        if pending.is_none() {
+                            let ticket = wal.begin_sync();
+                            prop_assert_eq!(ticket.is_some(), synced < log.len() as u64);
+                            pending = ticket.map(|ticket| (ticket, false));
+                        }
+                    }
+                    Op::FinishSync => {
+                        if let Some((ticket, truncated)) = pending.take() {
+                            ticket.sync().unwrap();
+                            let advanced = !truncated && synced < ticket.length();
+                            prop_assert_eq!(wal.finish_sync(&ticket), advanced);
+                            if advanced {
+                                synced = ticket.length();
+                            }
+                        }
+                    }
+                }
+
+                let terms: Vec<u64> = wal.as_slice().iter().map(|entry| entry.term).collect();
+                prop_assert_eq!(&terms, &log);
+                prop_assert_eq!(wal.synced_len(), synced);
+                prop_assert!(synced <= wal.len());
+                prop_assert_eq!(wal.last_term(), log.last().copied().unwrap_or(0));
+            }
+        }
+    }
 }
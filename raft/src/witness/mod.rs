@@ -0,0 +1,79 @@
+// Witness members : they vote and count in commit quorums, but keep only the log metadata
+// (terms, indices, membership and sessions), not the payloads of the commands.
+// - a witness never starts an election nor takes over a leadership
+// - the leader leaves the command payloads out of the entries it sends to a witness
+// - a witness serves no chat history (publisher, backup, change feed)
+// - a node keeps the witness flag of its log : a node with stripped entries never campaigns,
+//   and a node restarted with another witness flag than the one of its log exits
+
+use crate::raftchat_tonic::Entry;
+use crate::MyRaftChat;
+
+impl MyRaftChat {
+    pub fn is_witness(&self, id: &str) -> bool {
+        self.config.witnesses.contains(id)
+    }
+}
+
+// entry as stored by a witness
+pub fn metadata_only(entry: &Entry) -> Entry {
+    let mut entry = entry.clone();
+    if let Some(cmd) = &mut entry.command {
+        cmd.data.clear();
+        cmd.stripped = true;
+    }
+    entry
+}
+
+pub fn is_stripped(entry: &Entry) -> bool {
+    entry.command.as_ref().is_some_and(|cmd| cmd.stripped)
+}
+
+// Check that the log of a node agrees with its witness flag.
+// NB : a log without commands fits both
+pub fn check_log(witness: bool, entries: &[Entry]) -> Result<(), String> {
+    let commands = entries.iter().filter(|entry| entry.command.is_some());
+    match commands
+        .map(is_stripped)
+        .find(|&stripped| stripped != witness)
+    {
+        None => Ok(()),
+        Some(true) => Err(String::from(
+            "the log has stripped entries of a witness, but the node is not a witness",
+        )),
+        Some(false) => Err(String::from(
+            "the log has the chat messages of a full node, but the node is a witness",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_log, metadata_only};
+    use crate::raftchat_tonic::{Command, Entry};
+    use std::slice;
+
+    #[test]
+    fn case_metadata_only() {
+        let entry = Entry {
+            term: 3,
+            command: Some(Command {
+                client_id: String::from("a"),
+                message_id: 1,
+                data: vec![1, 2, 3],
+                stripped: false,
+            }),
+            membership: None,
+            time: 10,
+            session: None,
+        };
+        let stripped = metadata_only(&entry);
+        assert_eq!(stripped.term, 3);
+        assert_eq!(stripped.time, 10);
+        assert!(check_log(true, slice::from_ref(&stripped)).is_ok());
+        assert!(check_log(false, slice::from_ref(&stripped)).is_err());
+        assert!(check_log(true, slice::from_ref(&entry)).is_err());
+        assert!(check_log(false, slice::from_ref(&entry)).is_ok());
+        assert!(stripped.command.unwrap().data.is_empty());
+    }
+}
@@ -1,6 +1,12 @@
-use crate::Config;
-use axum::response::Html;
+use crate::config::{Config, Reloadable};
+use axum::http::{header, StatusCode};
+use axum::response::{Html, IntoResponse};
 use axum::{Extension, Json};
+use prometheus::{Encoder, TextEncoder};
+use raft::MyRaftChat;
+use serde_json::json;
+use std::sync::Arc;
+use tokio::sync::watch;
 
 pub async fn handler() -> Html<&'static str> {
     // `std::include_str` macro can be used to include an utf-8 file as `&'static str` in compile
@@ -11,3 +17,69 @@ pub async fn handler() -> Html<&'static str> {
 pub async fn get_info(Extension(config): Extension<Config>) -> Json<Config> {
     Json(config.clone())
 }
+
+// Prometheus metrics of both the chat server and the raft node
+pub async fn metrics() -> impl IntoResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    encoder.encode(&prometheus::gather(), &mut buffer).unwrap();
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+        buffer,
+    )
+}
+
+// Liveness: the process is up and serving http
+pub async fn healthz() -> impl IntoResponse {
+    Json(json!({ "status": "ok" }))
+}
+
+// Readiness: for the raft group of every room, the raft rpc server is bound, a leader is known,
+// and the local commit index is within `ready_max_lag` entries of the leader's.
+// The mock raft is always ready.
+pub async fn readyz(
+    Extension(config): Extension<Config>,
+    Extension(reloadable): Extension<watch::Receiver<Reloadable>>,
+    Extension(nodes): Extension<Option<Vec<Arc<MyRaftChat>>>>,
+) -> impl IntoResponse {
+    let max_lag = reloadable.borrow().ready_max_lag;
+    let Some(nodes) = nodes else {
+        return (StatusCode::OK, Json(json!({ "ready": true, "mock": true })));
+    };
+
+    let mut ready = true;
+    let mut groups = vec![];
+    for (room, node) in config.rooms.iter().zip(nodes.iter()) {
+        let status = node.status();
+        let lag = status
+            .leader_committed_length
+            .saturating_sub(status.committed_length);
+        let group_ready = status.rpc_bound && status.leader_id.is_some() && lag <= max_lag;
+        ready &= group_ready;
+        groups.push(json!({
+            "room": room,
+            "ready": group_ready,
+            "rpc_bound": status.rpc_bound,
+            "is_leader": status.is_leader,
+            "leader": status.leader_id,
+            "term": status.term,
+            "committed_length": status.committed_length,
+            "leader_committed_length": status.leader_committed_length,
+            "lag": lag,
+            "stale_ms": status.staleness.time.map(|time| time.as_millis() as u64),
+        }));
+    }
+    let code = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        code,
+        Json(json!({
+            "ready": ready,
+            "max_lag": max_lag,
+            "groups": groups,
+        })),
+    )
+}
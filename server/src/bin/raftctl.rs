@@ -0,0 +1,548 @@
+// raftctl : offline inspection and repair of a node's raft log and persistent state,
+// and client of the admin service of a running node.
+// The node must be stopped while raftctl opens its storage.
+
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use raft::backup::{read_archive, write_archive};
+use raft::raftchat_tonic::admin_client::AdminClient;
+use raft::raftchat_tonic::change_feed_client::ChangeFeedClient;
+use raft::raftchat_tonic::raft_chat_client::RaftChatClient;
+use raft::raftchat_tonic::{BackupArgs, Entry, NodeRole, PeerConnection, StatusArgs, StepDownArgs};
+use raft::raftchat_tonic::{SubscribeArgs, TransferLeadershipArgs, TriggerSnapshotArgs};
+use raft::storage::cipher::Keyring;
+use raft::storage::file::decode_records;
+use raft::storage::{self, LogStore, StorageBackend};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use tonic::transport::Channel;
+
+#[allow(dead_code)]
+#[path = "../data_model/mod.rs"]
+mod data_model;
+
+use data_model::msg::LogData;
+
+#[derive(Parser)]
+#[command(version, about = "inspect and repair raft storage offline", long_about = None)]
+struct Cli {
+    /// storage backend of the node (file, kv)
+    #[arg(short, long, value_name = "backend", default_value = "file")]
+    backend: StorageBackend,
+
+    /// raft group of the running node to talk to (the index of the chat room)
+    #[arg(short, long, value_name = "group id", default_value_t = 0)]
+    group: u64,
+
+    /// keys of the encrypted WAL or archive (RAFT_KEY_PATH of the node)
+    #[arg(short, long, value_name = "path")]
+    key_file: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// print log entries, decoding chat messages
+    Dump {
+        /// path of the WAL
+        wal: PathBuf,
+        /// first index to print
+        #[arg(long, default_value_t = 0)]
+        from: u64,
+        /// print entries before this index only
+        #[arg(long)]
+        to: Option<u64>,
+    },
+    /// print current term and vote
+    State {
+        /// path of the persistent state
+        persistent_state: PathBuf,
+    },
+    /// verify record checksums of the WAL
+    Check {
+        /// path of the WAL
+        wal: PathBuf,
+    },
+    /// find where the logs of two nodes diverge
+    Diff {
+        /// path of the first WAL
+        wal_a: PathBuf,
+        /// path of the second WAL
+        wal_b: PathBuf,
+    },
+    /// drop every entry after the given index
+    Truncate {
+        /// path of the WAL
+        wal: PathBuf,
+        /// last index to keep
+        #[arg(long)]
+        after: u64,
+    },
+    /// save the committed log of a running node into an archive
+    Backup {
+        /// rpc address of the node, e.g. http://127.0.0.1:3010
+        node: String,
+        /// path of the archive to write
+        archive: PathBuf,
+    },
+    /// create the storage of a new single node cluster from an archive
+    Restore {
+        /// path of the archive
+        archive: PathBuf,
+        /// path of the WAL to create
+        #[arg(long)]
+        wal: PathBuf,
+        /// path of the persistent state to create
+        #[arg(long)]
+        persistent_state: PathBuf,
+        /// last index to restore
+        #[arg(long)]
+        until_index: Option<u64>,
+        /// restore messages sent up to this time only, e.g. 2024-11-20T09:00:00Z
+        #[arg(long)]
+        until_time: Option<DateTime<Utc>>,
+    },
+    /// print role, term, log lengths and replication progress of a running node
+    Status {
+        /// rpc address of the node, e.g. http://127.0.0.1:3010
+        node: String,
+    },
+    /// make the leader hand over leadership to a peer
+    TransferLeadership {
+        /// rpc address of the leader
+        node: String,
+        /// id of the new leader, the most up to date peer if not given
+        #[arg(long)]
+        to: Option<String>,
+    },
+    /// take a snapshot of the state machine at the committed index
+    Snapshot {
+        /// rpc address of the node
+        node: String,
+    },
+    /// make the leader step down to follower
+    StepDown {
+        /// rpc address of the leader
+        node: String,
+    },
+    /// follow the committed entries of a running node
+    Subscribe {
+        /// rpc address of the node
+        node: String,
+        /// first index to print
+        #[arg(long, default_value_t = 0)]
+        from: u64,
+        /// continue after the event of this resume token, instead of --from
+        #[arg(long)]
+        resume: Option<String>,
+    },
+}
+
+fn fail(msg: String) -> ! {
+    eprintln!("raftctl: {}", msg);
+    process::exit(1);
+}
+
+fn load_keyring(path: Option<&Path>) -> Option<Arc<Keyring>> {
+    path.map(|path| {
+        Arc::new(Keyring::load(path).unwrap_or_else(|e| fail(format!("cannot load keys : {}", e))))
+    })
+}
+
+// Read a log without modifying it. A torn tail of a file log is reported, not discarded.
+// NB : the kv backend seals again the entries of a previous key while loading.
+fn read_log(backend: StorageBackend, keyring: Option<&Arc<Keyring>>, path: &Path) -> Vec<Entry> {
+    match backend {
+        StorageBackend::File => {
+            let buf = std::fs::read(path)
+                .unwrap_or_else(|e| fail(format!("cannot read {:?} : {}", path, e)));
+            let (entries, _, valid_length) = decode_records(keyring.map(|k| k.as_ref()), &buf)
+                .unwrap_or_else(|e| fail(format!("cannot decode {:?} : {}", path, e)));
+            if valid_length < buf.len() {
+                eprintln!(
+                    "raftctl: warning : {} trailing bytes of {:?} are not a valid record",
+                    buf.len() - valid_length,
+                    path
+                );
+            }
+            entries
+        }
+        _ => open_log(backend, keyring, path)
+            .load()
+            .unwrap_or_else(|e| fail(format!("cannot load {:?} : {}", path, e))),
+    }
+}
+
+fn open_log(
+    backend: StorageBackend,
+    keyring: Option<&Arc<Keyring>>,
+    path: &Path,
+) -> Box<dyn LogStore> {
+    if backend == StorageBackend::Memory {
+        fail(String::from("memory storage cannot be opened offline"));
+    }
+    if !path.exists() {
+        fail(format!("{:?} does not exist", path));
+    }
+    storage::open_log_store(backend, path, keyring.cloned())
+        .unwrap_or_else(|e| fail(format!("cannot open {:?} : {}", path, e)))
+}
+
+fn format_entry(index: usize, entry: &Entry) -> String {
+    match &entry.command {
+        Some(cmd) => {
+            let data = match bincode::deserialize::<LogData>(&cmd.data) {
+                Ok(log_data) => format!(
+                    "user={} time={} content={:?}",
+                    log_data.get_user_id(),
+                    log_data.get_time(),
+                    log_data.get_content()
+                ),
+                Err(_) => format!("<{} bytes, not a chat message>", cmd.data.len()),
+            };
+            format!(
+                "{}\tterm={}\tclient={}\tmessage={}\t{}",
+                index, entry.term, cmd.client_id, cmd.message_id, data
+            )
+        }
+        None => match (&entry.membership, &entry.session) {
+            (Some(membership), _) => format!(
+                "{}\tterm={}\tmembers={}",
+                index,
+                entry.term,
+                membership.members.join(",")
+            ),
+            (None, Some(session)) => format!(
+                "{}\tterm={}\tsession={}\ttimeout_ms={}",
+                index, entry.term, session.client_id, session.timeout_ms
+            ),
+            (None, None) => format!("{}\tterm={}\tno-op", index, entry.term),
+        },
+    }
+}
+
+fn dump(
+    backend: StorageBackend,
+    keyring: Option<&Arc<Keyring>>,
+    wal: &Path,
+    from: u64,
+    to: Option<u64>,
+) {
+    let entries = read_log(backend, keyring, wal);
+    let to = to.map_or(entries.len(), |to| (to as usize).min(entries.len()));
+    for (index, entry) in entries.iter().enumerate().take(to).skip(from as usize) {
+        println!("{}", format_entry(index, entry));
+    }
+}
+
+fn state(backend: StorageBackend, path: &Path) {
+    if !path.exists() {
+        fail(format!("{:?} does not exist", path));
+    }
+    let mut store = storage::open_stable_store(backend, path)
+        .unwrap_or_else(|e| fail(format!("cannot open {:?} : {}", path, e)));
+    match store.load() {
+        Ok(Some((current_term, voted_for))) => {
+            println!("current_term\t{}", current_term);
+            println!("voted_for\t{}", voted_for.as_deref().unwrap_or("-"));
+        }
+        Ok(None) => println!("empty persistent state"),
+        Err(e) => fail(format!("cannot load {:?} : {}", path, e)),
+    }
+}
+
+fn check(backend: StorageBackend, keyring: Option<&Arc<Keyring>>, wal: &Path) {
+    if backend != StorageBackend::File {
+        // The KV store checks its own integrity, a successful load is all we can verify.
+        let entries = read_log(backend, keyring, wal);
+        println!("ok : {} entries", entries.len());
+        return;
+    }
+    let buf = std::fs::read(wal).unwrap_or_else(|e| fail(format!("cannot read {:?} : {}", wal, e)));
+    let (entries, _, valid_length) = decode_records(keyring.map(|k| k.as_ref()), &buf)
+        .unwrap_or_else(|e| {
+            println!("corrupted : {}", e);
+            process::exit(2);
+        });
+    if valid_length == buf.len() {
+        println!("ok : {} entries, {} bytes", entries.len(), buf.len());
+    } else {
+        println!(
+            "corrupted : {} valid entries, bad record at byte {} ({} bytes after it)",
+            entries.len(),
+            valid_length,
+            buf.len() - valid_length
+        );
+        process::exit(2);
+    }
+}
+
+fn diff(backend: StorageBackend, keyring: Option<&Arc<Keyring>>, wal_a: &Path, wal_b: &Path) {
+    let a = read_log(backend, keyring, wal_a);
+    let b = read_log(backend, keyring, wal_b);
+    println!("length\t{}\t{}", a.len(), b.len());
+    match a.iter().zip(b.iter()).position(|(x, y)| x != y) {
+        Some(index) => {
+            println!("diverge at {}", index);
+            println!("< {}", format_entry(index, &a[index]));
+            println!("> {}", format_entry(index, &b[index]));
+            process::exit(2);
+        }
+        None if a.len() == b.len() => println!("identical"),
+        None => println!("one log is a prefix of the other"),
+    }
+}
+
+fn truncate(backend: StorageBackend, keyring: Option<&Arc<Keyring>>, wal: &Path, after: u64) {
+    let mut store = open_log(backend, keyring, wal);
+    let entries = store
+        .load()
+        .unwrap_or_else(|e| fail(format!("cannot load {:?} : {}", wal, e)));
+    if after + 1 >= entries.len() as u64 {
+        println!("nothing to truncate : {} entries", entries.len());
+        return;
+    }
+    // NB : dropping committed entries can lose acknowledged messages if this node is later
+    // elected. Only do so when the entries also exist on a quorum of other nodes.
+    store
+        .truncate(after + 1)
+        .and_then(|_| (store.sync_handle())())
+        .unwrap_or_else(|e| fail(format!("cannot truncate {:?} : {}", wal, e)));
+    println!(
+        "truncated {} entries, {} left",
+        entries.len() as u64 - (after + 1),
+        after + 1
+    );
+}
+
+async fn backup(node: String, group_id: u64, keyring: Option<&Arc<Keyring>>, archive: &Path) {
+    let mut client = RaftChatClient::connect(node.clone())
+        .await
+        .unwrap_or_else(|e| fail(format!("cannot connect to {} : {}", node, e)));
+    let mut stream = client
+        .backup(BackupArgs { group_id })
+        .await
+        .unwrap_or_else(|e| fail(format!("backup failed : {}", e)))
+        .into_inner();
+    let mut entries = vec![];
+    while let Some(entry) = stream
+        .message()
+        .await
+        .unwrap_or_else(|e| fail(format!("backup failed : {}", e)))
+    {
+        entries.push(entry);
+    }
+    write_archive(archive, &entries, keyring.map(|k| k.as_ref()))
+        .unwrap_or_else(|e| fail(format!("cannot write {:?} : {}", archive, e)));
+    println!("saved {} committed entries", entries.len());
+}
+
+async fn admin_client(node: String) -> AdminClient<Channel> {
+    AdminClient::connect(node.clone())
+        .await
+        .unwrap_or_else(|e| fail(format!("cannot connect to {} : {}", node, e)))
+}
+
+async fn status(node: String, group_id: u64) {
+    let res = admin_client(node)
+        .await
+        .status(StatusArgs { group_id })
+        .await
+        .unwrap_or_else(|e| fail(format!("status failed : {}", e.message())))
+        .into_inner();
+    let role = match res.role() {
+        NodeRole::Follower => "follower",
+        NodeRole::Candidate => "candidate",
+        NodeRole::Leader => "leader",
+    };
+    println!("node\t{}", res.node_id);
+    println!("role\t{}", role);
+    println!("term\t{}", res.term);
+    println!("leader\t{}", res.leader_id.as_deref().unwrap_or("-"));
+    println!("committed_length\t{}", res.committed_length);
+    println!("wal_length\t{}", res.wal_length);
+    println!("synced_length\t{}", res.synced_length);
+    println!("snapshot_length\t{}", res.snapshot_length);
+    for peer in &res.peers {
+        let connection = match peer.connection() {
+            PeerConnection::Up => "up",
+            PeerConnection::Down => "down",
+            PeerConnection::Backoff => "backoff",
+        };
+        let mut line = format!("peer\t{}\tconnection={}", peer.peer_id, connection);
+        if res.role() == NodeRole::Leader {
+            line += &format!(
+                "\tmatch_length={}\tprev_length={}",
+                peer.match_length, peer.prev_length
+            );
+        }
+        if peer.failures > 0 {
+            line += &format!(
+                "\tfailures={}\tretry_in_ms={}\terror={:?}",
+                peer.failures,
+                peer.retry_in_ms,
+                peer.last_error.as_deref().unwrap_or("")
+            );
+        }
+        println!("{}", line);
+    }
+}
+
+async fn transfer_leadership(node: String, group_id: u64, to: Option<String>) {
+    let res = admin_client(node)
+        .await
+        .transfer_leadership(TransferLeadershipArgs {
+            target: to,
+            group_id,
+        })
+        .await
+        .unwrap_or_else(|e| fail(format!("transfer failed : {}", e.message())))
+        .into_inner();
+    println!("leadership handed over to {}", res.new_leader_id);
+}
+
+async fn snapshot(node: String, group_id: u64) {
+    let res = admin_client(node)
+        .await
+        .trigger_snapshot(TriggerSnapshotArgs { group_id })
+        .await
+        .unwrap_or_else(|e| fail(format!("snapshot failed : {}", e.message())))
+        .into_inner();
+    println!("snapshot at {}", res.snapshot_length);
+}
+
+async fn step_down(node: String, group_id: u64) {
+    let res = admin_client(node)
+        .await
+        .step_down(StepDownArgs { group_id })
+        .await
+        .unwrap_or_else(|e| fail(format!("step down failed : {}", e.message())))
+        .into_inner();
+    println!("stepped down in term {}", res.term);
+}
+
+// Print every committed entry with its resume token, until interrupted.
+async fn subscribe(node: String, group_id: u64, from: u64, resume: Option<String>) {
+    let mut client = ChangeFeedClient::connect(node.clone())
+        .await
+        .unwrap_or_else(|e| fail(format!("cannot connect to {} : {}", node, e)));
+    let mut stream = client
+        .subscribe(SubscribeArgs {
+            from_index: from,
+            resume_token: resume,
+            group_id,
+        })
+        .await
+        .unwrap_or_else(|e| fail(format!("subscribe failed : {}", e.message())))
+        .into_inner();
+    while let Some(event) = stream
+        .message()
+        .await
+        .unwrap_or_else(|e| fail(format!("subscription ended : {}", e.message())))
+    {
+        let entry = event.entry.unwrap_or_default();
+        println!(
+            "{}\tresume={}",
+            format_entry(event.index as usize, &entry),
+            event.resume_token
+        );
+    }
+}
+
+// The restored log is a prefix of the archive, so that it is still a valid raft log.
+// With until_time, it stops at the first message sent after the given time.
+fn restore(
+    backend: StorageBackend,
+    keyring: Option<&Arc<Keyring>>,
+    archive: &Path,
+    wal: &Path,
+    persistent_state: &Path,
+    until_index: Option<u64>,
+    until_time: Option<DateTime<Utc>>,
+) {
+    if wal.exists() || persistent_state.exists() {
+        fail(String::from(
+            "refuse to overwrite the storage of an existing node",
+        ));
+    }
+    let mut entries = read_archive(archive, keyring.map(|k| k.as_ref()))
+        .unwrap_or_else(|e| fail(format!("cannot read {:?} : {}", archive, e)));
+    if let Some(index) = until_index {
+        entries.truncate(index as usize + 1);
+    }
+    if let Some(time) = until_time {
+        let sent_after = |entry: &Entry| match &entry.command {
+            Some(cmd) => bincode::deserialize::<LogData>(&cmd.data)
+                .map(|log_data| log_data.get_time() > time)
+                .unwrap_or(false),
+            None => false,
+        };
+        if let Some(index) = entries.iter().position(sent_after) {
+            entries.truncate(index);
+        }
+    }
+
+    let mut log_store = storage::open_log_store(backend, wal, keyring.cloned())
+        .unwrap_or_else(|e| fail(format!("cannot create {:?} : {}", wal, e)));
+    log_store
+        .append(&entries)
+        .and_then(|_| (log_store.sync_handle())())
+        .unwrap_or_else(|e| fail(format!("cannot write {:?} : {}", wal, e)));
+
+    // NB : the term must not go backward, otherwise stale entries of a newer term could
+    // not be overwritten.
+    let last_term = entries.last().map_or(0, |entry| entry.term);
+    storage::open_stable_store(backend, persistent_state)
+        .and_then(|mut store| store.save(last_term, None))
+        .unwrap_or_else(|e| fail(format!("cannot write {:?} : {}", persistent_state, e)));
+
+    match entries.last() {
+        Some(entry) => println!(
+            "restored {} entries : {}",
+            entries.len(),
+            format_entry(entries.len() - 1, entry)
+        ),
+        None => println!("restored an empty log"),
+    }
+    println!("start the node with only its own domain in DOMAINS");
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    let keyring = load_keyring(cli.key_file.as_deref());
+    let keyring = keyring.as_ref();
+    match cli.command {
+        Commands::Dump { wal, from, to } => dump(cli.backend, keyring, &wal, from, to),
+        Commands::State { persistent_state } => state(cli.backend, &persistent_state),
+        Commands::Check { wal } => check(cli.backend, keyring, &wal),
+        Commands::Diff { wal_a, wal_b } => diff(cli.backend, keyring, &wal_a, &wal_b),
+        Commands::Truncate { wal, after } => truncate(cli.backend, keyring, &wal, after),
+        Commands::Backup { node, archive } => backup(node, cli.group, keyring, &archive).await,
+        Commands::Restore {
+            archive,
+            wal,
+            persistent_state,
+            until_index,
+            until_time,
+        } => restore(
+            cli.backend,
+            keyring,
+            &archive,
+            &wal,
+            &persistent_state,
+            until_index,
+            until_time,
+        ),
+        Commands::Status { node } => status(node, cli.group).await,
+        Commands::TransferLeadership { node, to } => transfer_leadership(node, cli.group, to).await,
+        Commands::Snapshot { node } => snapshot(node, cli.group).await,
+        Commands::StepDown { node } => step_down(node, cli.group).await,
+        Commands::Subscribe { node, from, resume } => {
+            subscribe(node, cli.group, from, resume).await
+        }
+    }
+}
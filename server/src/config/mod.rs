@@ -0,0 +1,587 @@
+// Server configuration
+// - loaded from a TOML file (`*.toml`) or an env file, where process env vars override the file
+// - validated as a whole, every problem is reported
+// - reloadable settings are applied on SIGHUP
+
+use log::{error, info, warn, LevelFilter};
+use raft::storage::StorageBackend;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+// Served to the client at /get_info, so the raft settings are not serialized.
+#[derive(Clone, serde::Serialize, Debug, PartialEq)]
+pub struct Config {
+    pub domains: Vec<String>, // [TODO] change to Vec<&str>
+    pub web_ports: Vec<u16>,
+    pub socket_ports: Vec<u16>,
+    pub rpc_ports: Vec<u16>,
+    pub version: String,
+    pub self_domain_idx: usize,
+    pub raft_mock_flag: bool,
+    pub refresh_token: String,
+    // chat rooms, each one replicated by its own raft group : the index of the room
+    pub rooms: Vec<String>,
+    #[serde(skip)]
+    pub otlp_endpoint: Option<String>,
+    #[serde(skip)]
+    pub raft: RaftSettings,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RaftSettings {
+    pub election_timeout: (u64, u64), // ms
+    pub priorities: Vec<u32>,         // election priority of each domain
+    pub witnesses: Vec<bool>,         // whether each domain is a witness
+    pub heartbeat: Duration,
+    pub adaptive_timeout: bool,
+    pub wal_sync_delay: Duration,
+    pub session_timeout: Duration,
+    pub max_pending: usize,
+    pub max_chunk_bytes: usize,
+    pub catch_up_rate: u64, // bytes per second, 0 : unlimited
+    pub rpc_timeout: Duration,
+    pub balance_interval: Duration,
+    pub storage_backend: StorageBackend,
+    pub persistent_state_path: PathBuf,
+    pub wal_path: PathBuf,
+    pub snapshot_path: PathBuf,
+    pub identity_path: PathBuf,
+    pub key_path: Option<PathBuf>, // keys encrypting the WAL and the snapshots, plain if None
+}
+
+// Storage of the raft group of a room : the configured path for the first room, and the path
+// suffixed by -<group id> for the others.
+pub fn group_path(path: &Path, group_id: u64) -> PathBuf {
+    if group_id == 0 {
+        return path.to_path_buf();
+    }
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!("-{}", group_id));
+    path.with_file_name(name)
+}
+
+// Settings which are safe to change while running
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reloadable {
+    pub log_level: Option<LevelFilter>, // None : root level of log4rs.yaml
+    pub ready_max_lag: u64,
+    pub client_rate_limit: u32, // chat messages per second per connection, 0 : unlimited
+    pub handshake_timeout: Duration,
+    // max wait of a client asking for a staleness bound, before it is redirected to the leader
+    pub stale_read_wait: Duration,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    self_domain_idx: Option<usize>,
+    domains: Option<Vec<String>>,
+    web_ports: Option<Vec<u16>>,
+    socket_ports: Option<Vec<u16>>,
+    rpc_ports: Option<Vec<u16>>,
+    version: Option<String>,
+    refresh_token: Option<String>,
+    rooms: Option<Vec<String>>,
+    otlp_endpoint: Option<String>,
+    log_level: Option<String>,
+    ready_max_lag: Option<u64>,
+    client_rate_limit: Option<u32>,
+    handshake_timeout_ms: Option<u64>,
+    stale_read_wait_ms: Option<u64>,
+    #[serde(default)]
+    raft: RawRaftConfig,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct RawRaftConfig {
+    election_timeout_min_ms: Option<u64>,
+    election_timeout_max_ms: Option<u64>,
+    priorities: Option<Vec<u32>>,
+    witnesses: Option<Vec<bool>>,
+    heartbeat_ms: Option<u64>,
+    adaptive_timeout: Option<bool>,
+    wal_sync_delay_ms: Option<u64>,
+    session_timeout_ms: Option<u64>,
+    max_pending: Option<usize>,
+    max_chunk_bytes: Option<usize>,
+    catch_up_rate: Option<u64>,
+    rpc_timeout_ms: Option<u64>,
+    balance_interval_ms: Option<u64>,
+    storage_backend: Option<String>,
+    persistent_state_path: Option<PathBuf>,
+    wal_path: Option<PathBuf>,
+    snapshot_path: Option<PathBuf>,
+    identity_path: Option<PathBuf>,
+    key_path: Option<PathBuf>,
+}
+
+pub fn load(path: &Path, raft_mock_flag: bool) -> Result<(Config, Reloadable), Vec<String>> {
+    let mut errors = Vec::new();
+    let raw = if path.extension().is_some_and(|ext| ext == "toml") {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| vec![format!("{} : {}", path.display(), e)])?;
+        toml::from_str(&text).map_err(|e| vec![format!("{} : {}", path.display(), e)])?
+    } else {
+        let mut vars = HashMap::new();
+        // from_path would not override the vars set by the previous load
+        #[allow(deprecated)]
+        let iter = dotenv::from_path_iter(path)
+            .map_err(|e| vec![format!("{} : {}", path.display(), e)])?;
+        for item in iter {
+            match item {
+                Ok((key, val)) => {
+                    vars.insert(key, val);
+                }
+                Err(e) => errors.push(format!("{} : {}", path.display(), e)),
+            }
+        }
+        vars.extend(std::env::vars());
+        from_env(&vars, &mut errors)
+    };
+    let res = validate(raw, raft_mock_flag, &mut errors);
+    if errors.is_empty() {
+        Ok(res.unwrap())
+    } else {
+        Err(errors)
+    }
+}
+
+fn env_var<T: FromStr>(
+    vars: &HashMap<String, String>,
+    key: &str,
+    errors: &mut Vec<String>,
+) -> Option<T>
+where
+    T::Err: Display,
+{
+    let val = vars.get(key)?;
+    match val.trim().parse() {
+        Ok(v) => Some(v),
+        Err(e) => {
+            errors.push(format!("{} : invalid value {:?} : {}", key, val, e));
+            None
+        }
+    }
+}
+
+fn env_list<T: FromStr>(
+    vars: &HashMap<String, String>,
+    key: &str,
+    errors: &mut Vec<String>,
+) -> Option<Vec<T>>
+where
+    T::Err: Display,
+{
+    let val = vars.get(key)?;
+    let mut list = Vec::new();
+    for item in val.split(',') {
+        match item.trim().parse() {
+            Ok(v) => list.push(v),
+            Err(e) => {
+                errors.push(format!("{} : invalid value {:?} : {}", key, item, e));
+                return None;
+            }
+        }
+    }
+    Some(list)
+}
+
+fn from_env(vars: &HashMap<String, String>, errors: &mut Vec<String>) -> RawConfig {
+    RawConfig {
+        self_domain_idx: env_var(vars, "SELF_DOMAIN_IDX", errors),
+        domains: env_list(vars, "DOMAINS", errors),
+        web_ports: env_list(vars, "WEB_PORT", errors),
+        socket_ports: env_list(vars, "SOCKET_PORT", errors),
+        rpc_ports: env_list(vars, "RPC_PORT", errors),
+        version: vars.get("VERSION").cloned(),
+        refresh_token: vars.get("REFRESH_TOKEN").cloned(),
+        rooms: env_list(vars, "ROOMS", errors),
+        otlp_endpoint: vars.get("OTLP_ENDPOINT").cloned(),
+        log_level: vars.get("LOG_LEVEL").cloned(),
+        ready_max_lag: env_var(vars, "READY_MAX_LAG", errors),
+        client_rate_limit: env_var(vars, "CLIENT_RATE_LIMIT", errors),
+        handshake_timeout_ms: env_var(vars, "HANDSHAKE_TIMEOUT_MS", errors),
+        stale_read_wait_ms: env_var(vars, "STALE_READ_WAIT_MS", errors),
+        raft: RawRaftConfig {
+            election_timeout_min_ms: env_var(vars, "RAFT_ELECTION_TIMEOUT_MIN_MS", errors),
+            election_timeout_max_ms: env_var(vars, "RAFT_ELECTION_TIMEOUT_MAX_MS", errors),
+            priorities: env_list(vars, "RAFT_PRIORITIES", errors),
+            witnesses: env_list(vars, "RAFT_WITNESSES", errors),
+            heartbeat_ms: env_var(vars, "RAFT_HEARTBEAT_MS", errors),
+            adaptive_timeout: env_var(vars, "RAFT_ADAPTIVE_TIMEOUT", errors),
+            wal_sync_delay_ms: env_var(vars, "RAFT_WAL_SYNC_DELAY_MS", errors),
+            session_timeout_ms: env_var(vars, "RAFT_SESSION_TIMEOUT_MS", errors),
+            max_pending: env_var(vars, "RAFT_MAX_PENDING", errors),
+            max_chunk_bytes: env_var(vars, "RAFT_MAX_CHUNK_BYTES", errors),
+            catch_up_rate: env_var(vars, "RAFT_CATCH_UP_RATE", errors),
+            rpc_timeout_ms: env_var(vars, "RAFT_RPC_TIMEOUT_MS", errors),
+            balance_interval_ms: env_var(vars, "RAFT_BALANCE_INTERVAL_MS", errors),
+            storage_backend: vars.get("RAFT_STORAGE_BACKEND").cloned(),
+            persistent_state_path: vars.get("RAFT_PERSISTENT_STATE_PATH").map(PathBuf::from),
+            wal_path: vars.get("RAFT_WAL_PATH").map(PathBuf::from),
+            snapshot_path: vars.get("RAFT_SNAPSHOT_PATH").map(PathBuf::from),
+            identity_path: vars.get("RAFT_IDENTITY_PATH").map(PathBuf::from),
+            key_path: vars.get("RAFT_KEY_PATH").map(PathBuf::from),
+        },
+    }
+}
+
+fn required<T>(val: Option<T>, key: &str, errors: &mut Vec<String>) -> Option<T> {
+    if val.is_none() {
+        errors.push(format!("{} : missing", key));
+    }
+    val
+}
+
+// return None iff errors are pushed
+fn validate(
+    raw: RawConfig,
+    raft_mock_flag: bool,
+    errors: &mut Vec<String>,
+) -> Option<(Config, Reloadable)> {
+    let n_errors = errors.len();
+
+    let domains = required(raw.domains, "domains", errors).unwrap_or_default();
+    if domains.iter().any(|d| d.is_empty()) {
+        errors.push(String::from("domains : empty domain"));
+    }
+    let mut ports = |val: Option<Vec<u16>>, key: &str| {
+        let ports = required(val, key, errors).unwrap_or_default();
+        if ports.len() != domains.len() {
+            errors.push(format!(
+                "{} : {} ports for {} domains",
+                key,
+                ports.len(),
+                domains.len()
+            ));
+        }
+        ports
+    };
+    let web_ports = ports(raw.web_ports, "web_ports");
+    let socket_ports = ports(raw.socket_ports, "socket_ports");
+    let rpc_ports = ports(raw.rpc_ports, "rpc_ports");
+
+    let self_domain_idx = raw.self_domain_idx.unwrap_or(0);
+    if self_domain_idx >= domains.len() {
+        errors.push(format!(
+            "self_domain_idx : {} is out of range of {} domains",
+            self_domain_idx,
+            domains.len()
+        ));
+    }
+
+    let version = required(raw.version, "version", errors);
+    let refresh_token = required(raw.refresh_token, "refresh_token", errors);
+
+    // a room is joined at ws://host:port/<room>
+    let rooms = raw.rooms.unwrap_or_else(|| vec![String::from("general")]);
+    if rooms.is_empty() {
+        errors.push(String::from("rooms : no room"));
+    }
+    for (i, room) in rooms.iter().enumerate() {
+        let valid = !room.is_empty()
+            && room
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            errors.push(format!(
+                "rooms : invalid name {:?}, use letters, digits, - and _",
+                room
+            ));
+        } else if rooms[..i].contains(room) {
+            errors.push(format!("rooms : duplicated {:?}", room));
+        }
+    }
+
+    let election_timeout = (
+        raw.raft.election_timeout_min_ms.unwrap_or(3000), // raft paper: 150ms ~ 300ms
+        raw.raft.election_timeout_max_ms.unwrap_or(4000),
+    );
+    let heartbeat_ms = raw.raft.heartbeat_ms.unwrap_or(250);
+    let adaptive_timeout = raw.raft.adaptive_timeout.unwrap_or(false);
+    let priorities = raw
+        .raft
+        .priorities
+        .unwrap_or_else(|| vec![0; domains.len()]);
+    if priorities.len() != domains.len() {
+        errors.push(format!(
+            "raft.priorities : {} priorities for {} domains",
+            priorities.len(),
+            domains.len()
+        ));
+    }
+    let witnesses = raw
+        .raft
+        .witnesses
+        .unwrap_or_else(|| vec![false; domains.len()]);
+    if witnesses.len() != domains.len() {
+        errors.push(format!(
+            "raft.witnesses : {} flags for {} domains",
+            witnesses.len(),
+            domains.len()
+        ));
+    } else if witnesses.iter().all(|&witness| witness) {
+        errors.push(String::from("raft.witnesses : no node can lead"));
+    }
+    if election_timeout.0 >= election_timeout.1 {
+        errors.push(format!(
+            "raft.election_timeout : min {}ms must be less than max {}ms",
+            election_timeout.0, election_timeout.1
+        ));
+    }
+    // NB : adaptive heartbeats are sent more often than the election timeouts anyway
+    let min_timeout = if adaptive_timeout {
+        election_timeout.1
+    } else {
+        election_timeout.0
+    };
+    if heartbeat_ms == 0 || heartbeat_ms >= min_timeout {
+        errors.push(format!(
+            "raft.heartbeat_ms : {}ms must be positive and less than the election timeout",
+            heartbeat_ms
+        ));
+    }
+    let session_timeout_ms = raw.raft.session_timeout_ms.unwrap_or(24 * 60 * 60 * 1000);
+    if session_timeout_ms == 0 {
+        errors.push(String::from("raft.session_timeout_ms : must be positive"));
+    }
+    let max_pending = raw.raft.max_pending.unwrap_or(1024);
+    if max_pending == 0 {
+        errors.push(String::from("raft.max_pending : must be positive"));
+    }
+    let max_chunk_bytes = raw.raft.max_chunk_bytes.unwrap_or(64 * 1024);
+    if max_chunk_bytes == 0 {
+        errors.push(String::from("raft.max_chunk_bytes : must be positive"));
+    }
+    let catch_up_rate = raw.raft.catch_up_rate.unwrap_or(4 * 1024 * 1024);
+    let rpc_timeout_ms = raw.raft.rpc_timeout_ms.unwrap_or(1000);
+    if rpc_timeout_ms == 0 {
+        errors.push(String::from("raft.rpc_timeout_ms : must be positive"));
+    }
+    let balance_interval_ms = raw.raft.balance_interval_ms.unwrap_or(5000);
+    if balance_interval_ms == 0 {
+        errors.push(String::from("raft.balance_interval_ms : must be positive"));
+    }
+    let storage_backend = raw
+        .raft
+        .storage_backend
+        .as_deref()
+        .unwrap_or("file")
+        .parse()
+        .map_err(|e| errors.push(format!("raft.storage_backend : {}", e)))
+        .ok();
+
+    let log_level = match raw.log_level {
+        None => None,
+        Some(level) => match level.parse() {
+            Ok(level) => Some(level),
+            Err(_) => {
+                errors.push(format!("log_level : unknown level {:?}", level));
+                None
+            }
+        },
+    };
+
+    if errors.len() > n_errors {
+        return None;
+    }
+
+    let config = Config {
+        domains,
+        web_ports,
+        socket_ports,
+        rpc_ports,
+        version: version?,
+        self_domain_idx,
+        raft_mock_flag,
+        refresh_token: refresh_token?,
+        rooms,
+        otlp_endpoint: raw.otlp_endpoint,
+        raft: RaftSettings {
+            election_timeout,
+            priorities,
+            witnesses,
+            heartbeat: Duration::from_millis(heartbeat_ms),
+            adaptive_timeout,
+            wal_sync_delay: Duration::from_millis(raw.raft.wal_sync_delay_ms.unwrap_or(2)),
+            session_timeout: Duration::from_millis(session_timeout_ms),
+            max_pending,
+            max_chunk_bytes,
+            catch_up_rate,
+            rpc_timeout: Duration::from_millis(rpc_timeout_ms),
+            balance_interval: Duration::from_millis(balance_interval_ms),
+            storage_backend: storage_backend?,
+            persistent_state_path: raw
+                .raft
+                .persistent_state_path
+                .unwrap_or_else(|| PathBuf::from("./data/persistent_state")),
+            wal_path: raw
+                .raft
+                .wal_path
+                .unwrap_or_else(|| PathBuf::from("./data/wal")),
+            snapshot_path: raw
+                .raft
+                .snapshot_path
+                .unwrap_or_else(|| PathBuf::from("./data/snapshot")),
+            identity_path: raw
+                .raft
+                .identity_path
+                .unwrap_or_else(|| PathBuf::from("./data/identity")),
+            key_path: raw.raft.key_path,
+        },
+    };
+    let reloadable = Reloadable {
+        log_level,
+        ready_max_lag: raw.ready_max_lag.unwrap_or(100),
+        client_rate_limit: raw.client_rate_limit.unwrap_or(0),
+        handshake_timeout: Duration::from_millis(raw.handshake_timeout_ms.unwrap_or(10000)),
+        stale_read_wait: Duration::from_millis(raw.stale_read_wait_ms.unwrap_or(1000)),
+    };
+    Some((config, reloadable))
+}
+
+// log4rs.yaml with the root level overridden by log_level
+pub fn log_config(path: &Path, log_level: Option<LevelFilter>) -> log4rs::Config {
+    let mut log_config = log4rs::config::load_config_file(path, Default::default()).unwrap();
+    if let Some(level) = log_level {
+        log_config.root_mut().set_level(level);
+    }
+    log_config
+}
+
+// Reload the config file and log4rs.yaml on SIGHUP.
+// Invalid configs and changes of non-reloadable settings are reported and ignored.
+pub fn reload_on_sighup(
+    config_path: PathBuf,
+    log_config_path: PathBuf,
+    config: Config,
+    log_handle: log4rs::Handle,
+    reloadable_tx: watch::Sender<Reloadable>,
+) {
+    let mut hangup = signal(SignalKind::hangup()).unwrap();
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP : reload {}", config_path.display());
+            let (new_config, reloadable) = match load(&config_path, config.raft_mock_flag) {
+                Ok(res) => res,
+                Err(errors) => {
+                    for e in errors {
+                        error!("invalid config : {}", e);
+                    }
+                    continue;
+                }
+            };
+            if new_config != config {
+                warn!("config changes other than the reloadable settings require a restart");
+            }
+            log_handle.set_config(log_config(&log_config_path, reloadable.log_level));
+            info!("{:?}", reloadable);
+            reloadable_tx.send_replace(reloadable);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{from_env, group_path, validate, RawConfig};
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    const TOML: &str = r#"
+        self_domain_idx = 1
+        domains = ["127.0.0.1", "127.0.0.1"]
+        web_ports = [3000, 3001]
+        socket_ports = [9001, 9002]
+        rpc_ports = [3010, 3011]
+        version = "0.2.0"
+        refresh_token = "x"
+        log_level = "info"
+        rooms = ["general", "random"]
+
+        [raft]
+        election_timeout_min_ms = 150
+        election_timeout_max_ms = 300
+        heartbeat_ms = 50
+        adaptive_timeout = true
+        priorities = [0, 1]
+        witnesses = [true, false]
+        wal_path = "/tmp/wal"
+        key_path = "/etc/raftchat/wal.key"
+    "#;
+
+    #[test]
+    fn case_toml() {
+        let raw: RawConfig = toml::from_str(TOML).unwrap();
+        let mut errors = vec![];
+        let (config, reloadable) = validate(raw, false, &mut errors).unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(config.self_domain_idx, 1);
+        assert_eq!(config.raft.election_timeout, (150, 300));
+        assert!(config.raft.adaptive_timeout);
+        assert_eq!(config.raft.priorities, vec![0, 1]);
+        assert_eq!(config.raft.witnesses, vec![true, false]);
+        assert_eq!(config.raft.wal_path.to_str(), Some("/tmp/wal"));
+        assert_eq!(
+            config.raft.key_path.as_deref(),
+            Some(Path::new("/etc/raftchat/wal.key"))
+        );
+        assert_eq!(config.rooms, vec!["general", "random"]);
+        assert_eq!(group_path(&config.raft.wal_path, 0), Path::new("/tmp/wal"));
+        assert_eq!(
+            group_path(&config.raft.wal_path, 1),
+            Path::new("/tmp/wal-1")
+        );
+        assert_eq!(reloadable.log_level, Some(log::LevelFilter::Info));
+        assert_eq!(reloadable.ready_max_lag, 100);
+        assert_eq!(
+            reloadable.stale_read_wait,
+            std::time::Duration::from_millis(1000)
+        );
+    }
+
+    #[test]
+    fn case_errors() {
+        let vars: HashMap<String, String> = [
+            ("SELF_DOMAIN_IDX", "3"),
+            ("DOMAINS", "a,b"),
+            ("WEB_PORT", "3000,3001"),
+            ("SOCKET_PORT", "9001,x"),
+            ("RPC_PORT", "3010"),
+            ("VERSION", "0.2.0"),
+            ("RAFT_ELECTION_TIMEOUT_MIN_MS", "400"),
+            ("RAFT_ELECTION_TIMEOUT_MAX_MS", "300"),
+            ("RAFT_STORAGE_BACKEND", "tape"),
+            ("ROOMS", "general,a b"),
+            ("RAFT_PRIORITIES", "1"),
+            ("RAFT_WITNESSES", "true,true"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        let mut errors = vec![];
+        let raw = from_env(&vars, &mut errors);
+        assert!(validate(raw, false, &mut errors).is_none());
+        for key in [
+            "SOCKET_PORT :",
+            "socket_ports :",
+            "rpc_ports :",
+            "self_domain_idx :",
+            "refresh_token : missing",
+            "raft.election_timeout :",
+            "raft.storage_backend :",
+            "raft.priorities :",
+            "raft.witnesses :",
+            "rooms :",
+        ] {
+            assert!(errors.iter().any(|e| e.starts_with(key)), "{}", key);
+        }
+    }
+}
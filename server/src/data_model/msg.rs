@@ -39,9 +39,9 @@ pub struct LogData {
 impl LogData {
     pub fn new(user_id: String, content: String, time: DateTime<Utc>) -> Self {
         LogData {
-            content: content,
-            time: time,
-            user_id: user_id,
+            content,
+            time,
+            user_id,
         }
     }
     pub fn get_content(&self) -> String {
@@ -61,6 +61,19 @@ pub struct ServerMsg {
     message: Msg,
 }
 
+// sent instead of committed messages when raft rejects a message of the client
+// {
+//     "error": "session_expired",
+//     "message": "session of client unique_id expired or unknown, ...",
+//     "time_stamp": 3
+// }
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServerError {
+    error: String,
+    message: String,
+    time_stamp: u64,
+}
+
 impl ClientMsg {
     pub fn get_messages(&self) -> &Vec<Msg> {
         &self.messages
@@ -80,16 +93,13 @@ impl Msg {
         time_stamp: u64,
     ) -> Self {
         Msg {
-            id: id,
-            user_id: user_id,
-            content: content,
-            time: time,
-            time_stamp: time_stamp,
+            id,
+            user_id,
+            content,
+            time,
+            time_stamp,
         }
     }
-    pub fn get_uid(&self) -> String {
-        self.user_id.clone()
-    }
     pub fn get_content(&self) -> String {
         self.content.clone()
     }
@@ -97,7 +107,7 @@ impl Msg {
         self.id.clone()
     }
     pub fn get_time(&self) -> DateTime<Utc> {
-        self.time.clone()
+        self.time
     }
     pub fn get_time_stamp(&self) -> u64 {
         self.time_stamp
@@ -107,8 +117,18 @@ impl Msg {
 impl ServerMsg {
     pub fn new(committed_index: u64, msg: Msg) -> Self {
         ServerMsg {
-            committed_index: committed_index,
+            committed_index,
             message: msg,
         }
     }
 }
+
+impl ServerError {
+    pub fn session_expired(message: String, time_stamp: u64) -> Self {
+        ServerError {
+            error: String::from("session_expired"),
+            message,
+            time_stamp,
+        }
+    }
+}
@@ -1,32 +1,188 @@
+use crate::config::Reloadable;
 use crate::data_model::msg::ClientMsg;
+use crate::metrics;
 use futures_util::{
     stream::{SplitSink, SplitStream},
-    StreamExt,
+    SinkExt, StreamExt,
 };
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
+use raft::staleness::{Staleness, StalenessBound};
+use raft::MyRaftChat;
+use std::collections::HashMap;
 use std::process;
+use std::sync::Arc;
 use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::time::{self, Duration, Instant};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tracing::{info_span, Span};
 
 type Stream = SplitSink<WebSocketStream<TcpStream>, Message>;
 
+// close code of a client exceeding its staleness bound, the reason is the url of the leader
+const STALE_CLOSE_CODE: u16 = 4307;
+
+// period of the staleness check of a connected client
+const STALE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// writer and publisher of a chat room
+#[derive(Clone)]
+pub struct Room {
+    pub name: String,
+    pub writer_tx: tokio::sync::mpsc::Sender<(String, ClientMsg, Span)>,
+    pub publisher_tx: tokio::sync::mpsc::Sender<(String, Stream)>,
+    pub clients: Arc<tokio::sync::Mutex<HashMap<String, Stream>>>, // streams of the publisher
+    pub node: Option<Arc<MyRaftChat>>, // raft group of the room, None for the mock raft
+    pub socket_addrs: Arc<HashMap<&'static str, String>>, // websocket address of each raft id
+}
+
+// The room is the path of the websocket url, "/" being the first room.
+fn find_room<'a>(rooms: &'a [Room], path: &str) -> Option<&'a Room> {
+    match path.trim_start_matches('/') {
+        "" => rooms.first(),
+        name => rooms.iter().find(|room| room.name == name),
+    }
+}
+
+// Staleness bound asked in the query of the websocket url,
+// e.g. ws://host:port/room?max_stale_ms=500&max_stale_entries=10
+fn parse_bound(query: Option<&str>) -> Result<StalenessBound, String> {
+    let mut bound = StalenessBound::default();
+    for param in query.unwrap_or("").split('&').filter(|p| !p.is_empty()) {
+        let (key, val) = param.split_once('=').unwrap_or((param, ""));
+        let parse = |val: &str| {
+            val.parse::<u64>()
+                .map_err(|e| format!("{} : invalid value {:?} : {}", key, val, e))
+        };
+        match key {
+            "max_stale_ms" => bound.max_time = Some(Duration::from_millis(parse(val)?)),
+            "max_stale_entries" => bound.max_entries = Some(parse(val)?),
+            _ => {}
+        }
+    }
+    Ok(bound)
+}
+
+// close frame redirecting a client which exceeds its staleness bound to the leader, if known
+fn stale_close_frame(
+    node: &MyRaftChat,
+    socket_addrs: &HashMap<&'static str, String>,
+    room: &str,
+    addr: std::net::SocketAddr,
+    bound: StalenessBound,
+    staleness: Staleness,
+) -> CloseFrame<'static> {
+    let url = node
+        .status()
+        .leader_id
+        .and_then(|id| socket_addrs.get(id))
+        .map_or(String::new(), |addr| format!("ws://{}/{}", addr, room));
+    info!(
+        "{} exceeds the staleness bound {:?} : {:?}, redirect to {:?}",
+        addr, bound, staleness, url
+    );
+    metrics::STALE_READ_REDIRECTS.inc();
+    CloseFrame {
+        code: CloseCode::from(STALE_CLOSE_CODE),
+        reason: url.into(),
+    }
+}
+
+// Return the staleness once the node exceeds the bound for longer than wait.
+async fn wait_stale(
+    node: &MyRaftChat,
+    bound: StalenessBound,
+    reloadable: &watch::Receiver<Reloadable>,
+) -> Staleness {
+    let mut interval = time::interval(STALE_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        if bound.allows(&node.staleness()) {
+            continue;
+        }
+        let wait = reloadable.borrow().stale_read_wait;
+        if let Err(staleness) = node.wait_fresh(bound, wait).await {
+            return staleness;
+        }
+    }
+}
+
 pub async fn client_handler(
     stream: TcpStream,
     addr: std::net::SocketAddr,
-    writer_tx: tokio::sync::mpsc::Sender<(String, ClientMsg)>,
-    publisher_tx: tokio::sync::mpsc::Sender<(String, Stream)>,
+    rooms: Arc<Vec<Room>>,
+    reloadable: watch::Receiver<Reloadable>,
 ) {
     info!("Incoming WebSocket connection from: {}", addr);
 
-    let ws_steam = tokio_tungstenite::accept_async(stream)
-        .await
-        .expect("Error during the websocket handshake occurred");
+    let mut room = None;
+    let mut bound = StalenessBound::default();
+    // the error type is the one of tungstenite
+    #[allow(clippy::result_large_err)]
+    let select_room = |req: &Request, res: Response| {
+        let error = |status, msg| {
+            let mut err = ErrorResponse::new(Some(msg));
+            *err.status_mut() = status;
+            Err(err)
+        };
+        let Some(found) = find_room(&rooms, req.uri().path()) else {
+            return error(StatusCode::NOT_FOUND, String::from("unknown room"));
+        };
+        match parse_bound(req.uri().query()) {
+            Ok(parsed) => bound = parsed,
+            Err(e) => return error(StatusCode::BAD_REQUEST, e),
+        }
+        room = Some(found.clone());
+        Ok(res)
+    };
+
+    let handshake_timeout = reloadable.borrow().handshake_timeout;
+    let handshake = tokio_tungstenite::accept_hdr_async(stream, select_room);
+    let mut ws_steam = match time::timeout(handshake_timeout, handshake).await {
+        Ok(Ok(ws_stream)) => ws_stream,
+        Ok(Err(e)) => {
+            warn!("websocket handshake with {} failed : {}", addr, e);
+            return;
+        }
+        Err(_) => {
+            warn!("websocket handshake with {} timed out", addr);
+            return;
+        }
+    };
+
+    let Room {
+        name,
+        writer_tx,
+        publisher_tx,
+        clients,
+        node,
+        socket_addrs,
+    } = room.unwrap();
+
+    // The client reads nothing before the node is within its staleness bound. After the wait,
+    // it is closed with the address of the leader, if known, to reconnect there.
+    let node = node.filter(|_| bound != StalenessBound::default());
+    if let Some(node) = &node {
+        let wait = reloadable.borrow().stale_read_wait;
+        if let Err(staleness) = node.wait_fresh(bound, wait).await {
+            let frame = stale_close_frame(node, &socket_addrs, &name, addr, bound, staleness);
+            let _ = ws_steam.close(Some(frame)).await;
+            return;
+        }
+    }
+    info!("{} joined room {}", addr, name);
+    metrics::WEBSOCKET_CLIENTS.inc();
 
     // Split websocket stream
     let (write_stream, read_stream) = ws_steam.split();
 
-    let join_handle = tokio::spawn(async move {
-        read_task(read_stream, writer_tx, addr.clone()).await;
+    let read_reloadable = reloadable.clone();
+    let mut join_handle = tokio::spawn(async move {
+        read_task(read_stream, writer_tx, addr, read_reloadable).await;
     });
 
     // Send write_stream to publisher
@@ -35,45 +191,86 @@ pub async fn client_handler(
         .await
         .unwrap();
 
-    join_handle.await.unwrap();
+    // The bound also applies to the messages published afterwards : a node which falls behind
+    // redirects the client as at the handshake. The stream is taken from the publisher, so that
+    // the connection is closed once both halves are dropped.
+    if let Some(node) = &node {
+        tokio::select! {
+            res = &mut join_handle => res.unwrap(),
+            staleness = wait_stale(node, bound, &reloadable) => {
+                let frame = stale_close_frame(node, &socket_addrs, &name, addr, bound, staleness);
+                let stream = clients.lock().await.remove(&addr.to_string());
+                if let Some(mut stream) = stream {
+                    let _ = stream.send(Message::Close(Some(frame))).await;
+                }
+                // wait for the close of the client, as long as a handshake at most
+                let timeout = reloadable.borrow().handshake_timeout;
+                if time::timeout(timeout, &mut join_handle).await.is_err() {
+                    join_handle.abort();
+                }
+            }
+        }
+    } else {
+        join_handle.await.unwrap();
+    }
+    metrics::WEBSOCKET_CLIENTS.dec();
+}
+
+// root span of the trace of every chat message sent in one WebSocket message
+fn read_span(addr: &std::net::SocketAddr) -> Span {
+    info_span!("read_task", addr = %addr)
 }
 
 async fn read_task(
     mut read_stream: SplitStream<WebSocketStream<TcpStream>>,
-    writer_tx: tokio::sync::mpsc::Sender<(String, ClientMsg)>,
+    writer_tx: tokio::sync::mpsc::Sender<(String, ClientMsg, Span)>,
     addr: std::net::SocketAddr,
+    reloadable: watch::Receiver<Reloadable>,
 ) {
     info!("read_task started");
 
+    // fixed one second window of client_rate_limit chat messages
+    let mut window_start = Instant::now();
+    let mut window_count = 0;
+
     while let Some(msg) = read_stream.next().await {
         let msg = msg.unwrap_or(Message::Close(None));
-        match msg {
-            Message::Text(text) => {
-                let client_msg: ClientMsg = serde_json::from_str(&text).unwrap_or_else(|err| {
-                    error!("{}", text);
-                    error!("{}", err);
-                    process::exit(1);
-                });
-
-                writer_tx
-                    .send((addr.to_string(), client_msg))
-                    .await
-                    .unwrap();
-            }
-            Message::Binary(data) => {
-                let client_msg: ClientMsg = serde_json::from_slice(&data).unwrap();
-                writer_tx
-                    .send((addr.to_string(), client_msg))
-                    .await
-                    .unwrap();
-            }
+        let client_msg: ClientMsg = match msg {
+            Message::Text(text) => serde_json::from_str(&text).unwrap_or_else(|err| {
+                error!("{}", text);
+                error!("{}", err);
+                process::exit(1);
+            }),
+            Message::Binary(data) => serde_json::from_slice(&data).unwrap(),
             Message::Close(_) => {
                 info!("Client disconnected");
                 break;
             }
             _ => {
                 warn!("Unsupported message type");
+                continue;
+            }
+        };
+
+        // slow down the reader instead of dropping messages
+        let rate_limit = reloadable.borrow().client_rate_limit;
+        if rate_limit > 0 {
+            if window_start.elapsed() >= Duration::from_secs(1) {
+                window_start = Instant::now();
+                window_count = 0;
+            }
+            window_count += client_msg.get_messages().len() as u32;
+            if window_count > rate_limit {
+                debug!("rate limit of {} exceeded", addr);
+                time::sleep_until(window_start + Duration::from_secs(1)).await;
+                window_start = Instant::now();
+                window_count = 0;
             }
         }
+
+        writer_tx
+            .send((addr.to_string(), client_msg, read_span(&addr)))
+            .await
+            .unwrap();
     }
 }
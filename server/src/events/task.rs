@@ -1,27 +1,38 @@
-use crate::data_model::msg::{ClientMsg, LogData, Msg, ServerMsg};
-use chrono::Local;
+use crate::data_model::msg::{ClientMsg, LogData, Msg, ServerError, ServerMsg};
+use crate::metrics;
 use futures_util::stream::SplitSink;
 use futures_util::SinkExt;
-use log::{debug, info, Log};
-use raft::raftchat_tonic::{Command, Entry, UserRequestArgs};
+use log::{debug, info, warn};
+use raft::raftchat_tonic::{Entry, UserRequestArgs};
+use raft::UserRequest;
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::u64;
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::Receiver;
-use tokio::time::{self, Duration};
+use tokio::sync::{oneshot, watch};
+use tokio::time::{self, Duration, Instant};
 use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tonic::Code;
+use tracing::{info_span, Span};
 
 type Stream = SplitSink<WebSocketStream<TcpStream>, Message>;
 
+// pause of the writer after an "overloaded" error of raft
+const OVERLOAD_BACKOFF: Duration = Duration::from_millis(100);
+
 // Writer task
-// - Receives messages from the client_handler and forwards them to the Raft
+// - Receives messages from the client_handler and forwards them to the Raft group of its room
 // - If the buffer size is greater than 10, it sends the messages to the Raft
 // - It also sends the messages to the Raft every 5 ms
 pub struct Writer {
     // < client's address, client's committed index >
     // shared with publisher
     client_commit_idx: Arc<tokio::sync::Mutex<HashMap<String, u64>>>,
+
+    // < client's address, client stream >
+    // shared with publisher, to report the rejected messages
+    clients: Arc<tokio::sync::Mutex<HashMap<String, Stream>>>,
+    group_id: u64,
 }
 
 // Publisher task
@@ -35,6 +46,7 @@ pub struct Publisher {
     client_commit_idx: Arc<tokio::sync::Mutex<HashMap<String, u64>>>,
 
     // < client's address, client stream >
+    // shared with writer
     clients: Arc<tokio::sync::Mutex<HashMap<String, Stream>>>,
 
     // lock
@@ -46,11 +58,12 @@ impl Publisher {
         // when recover the server, backup the state machine from raft.
         state_machine: Vec<Msg>,
         client_commit_idx: Arc<tokio::sync::Mutex<HashMap<String, u64>>>,
+        clients: Arc<tokio::sync::Mutex<HashMap<String, Stream>>>,
     ) -> Self {
         Publisher {
             state_machine: Arc::new(tokio::sync::Mutex::new(state_machine)),
             client_commit_idx,
-            clients: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+            clients,
             pub_lock: Arc::new(tokio::sync::Mutex::new(0)),
         }
     }
@@ -80,38 +93,28 @@ impl Publisher {
         let pub_lock = self.pub_lock.clone();
         tokio::spawn(async move {
             while let Some(commit) = commit_rx.recv().await {
+                // NB : the entries without a command (no-op, session, membership) are not
+                // chat messages
+                let Some(cmd) = commit.command else {
+                    continue;
+                };
                 let lock = pub_lock.lock().await;
 
                 // [Warn] type error
                 let raft_commit_idx = state_machine.lock().await.len() as u64;
-                let c_msg;
-
-                match commit.command {
-                    Some(cmd) => {
-                        let log_data: LogData = bincode::deserialize(&cmd.data).unwrap();
-                        c_msg = Msg::new(
-                            cmd.client_id,
-                            log_data.get_user_id(),
-                            log_data.get_content(),
-                            log_data.get_time(),
-                            cmd.message_id,
-                        );
-                    }
-                    None => {
-                        // no-op
-                        c_msg = Msg::new(
-                            String::from("raft"),
-                            String::from("raft"),
-                            String::from("no-op"),
-                            Local::now().into(),
-                            u64::MAX,
-                        );
-                    }
-                }
+                let log_data: LogData = bincode::deserialize(&cmd.data).unwrap();
+                let c_msg = Msg::new(
+                    cmd.client_id,
+                    log_data.get_user_id(),
+                    log_data.get_content(),
+                    log_data.get_time(),
+                    cmd.message_id,
+                );
 
                 state_machine.lock().await.push(c_msg);
                 let mut delete_candidates = Vec::new();
 
+                let timer = metrics::PUBLISHER_FANOUT.start_timer();
                 {
                     let mut clients_: tokio::sync::MutexGuard<
                         '_,
@@ -123,7 +126,7 @@ impl Publisher {
                         let client_idx;
                         {
                             let client_commit_idx = client_commit_idx.lock().await;
-                            client_idx = client_commit_idx.get(addr).unwrap_or(&0).clone();
+                            client_idx = *client_commit_idx.get(addr).unwrap_or(&0);
                         }
 
                         // build server msg
@@ -167,6 +170,7 @@ impl Publisher {
                         }
                     }
                 }
+                timer.observe_duration();
 
                 {
                     let mut clients_: tokio::sync::MutexGuard<
@@ -202,13 +206,11 @@ impl Publisher {
                 let lock = pub_lock.try_lock();
                 match lock {
                     Ok(_) => {
-                        let raft_commit_idx;
-                        if state_machine.lock().await.len() == 0 {
+                        if state_machine.lock().await.is_empty() {
                             drop(lock);
                             continue;
-                        } else {
-                            raft_commit_idx = state_machine.lock().await.len() as u64 - 1;
                         }
+                        let raft_commit_idx = state_machine.lock().await.len() as u64 - 1;
 
                         let mut delete_candidates = Vec::new();
 
@@ -223,7 +225,7 @@ impl Publisher {
                                 let client_idx;
                                 {
                                     let client_commit_idx = client_commit_idx.lock().await;
-                                    client_idx = client_commit_idx.get(addr).unwrap_or(&0).clone();
+                                    client_idx = *client_commit_idx.get(addr).unwrap_or(&0);
                                 }
 
                                 // build server msg
@@ -302,25 +304,43 @@ impl Publisher {
 }
 
 impl Writer {
-    pub fn new(client_commit_idx: Arc<tokio::sync::Mutex<HashMap<String, u64>>>) -> Self {
+    pub fn new(
+        client_commit_idx: Arc<tokio::sync::Mutex<HashMap<String, u64>>>,
+        clients: Arc<tokio::sync::Mutex<HashMap<String, Stream>>>,
+        group_id: u64,
+    ) -> Self {
         Writer {
             //buffer: Arc::new(Mutex::new(Vec::new())),
             client_commit_idx,
+            clients,
+            group_id,
         }
     }
 
     pub async fn start(
         &self,
-        mut writer_rx: Receiver<(String, ClientMsg)>,
-        raft_tx: tokio::sync::mpsc::Sender<UserRequestArgs>,
+        mut writer_rx: Receiver<(String, ClientMsg, Span)>,
+        raft_tx: tokio::sync::mpsc::Sender<UserRequest>,
     ) {
         info!("Writer started");
         let client_commit_idx = self.client_commit_idx.clone();
+        let clients = self.clients.clone();
+        let group_id = self.group_id;
+        // the raft node is overloaded until then
+        let paused_until = Arc::new(watch::Sender::new(Instant::now()));
 
         // [NOTE]
         // Below code can be refactored to use tokio::select!
         tokio::spawn(async move {
-            while let Some((addr, client_msg)) = writer_rx.recv().await {
+            while let Some((addr, client_msg, read_span)) = writer_rx.recv().await {
+                // backpressure : the channel from the clients fills up meanwhile, and their
+                // read tasks stop reading the websockets
+                let until = *paused_until.borrow();
+                if until > Instant::now() {
+                    debug!("raft is overloaded, pause the writer");
+                    time::sleep_until(until).await;
+                }
+
                 info!(
                     "Received a message: {:?}: {:?}",
                     addr,
@@ -332,7 +352,7 @@ impl Writer {
                 let index = client_msg.get_committed_index();
 
                 // update client's index
-                client_commit_idx.lock().await.insert(addr, index);
+                client_commit_idx.lock().await.insert(addr.clone(), index);
 
                 for msg in messages.iter() {
                     info!(
@@ -347,11 +367,115 @@ impl Writer {
                         client_id: msg.get_id(),
                         message_id: msg.get_time_stamp(),
                         data: bincode::serialize(&log_data).unwrap(),
+                        group_id,
                     };
 
-                    raft_tx.send(req).await.unwrap();
+                    let span = info_span!(
+                        parent: &read_span,
+                        "writer",
+                        client_id = %req.client_id,
+                        message_id = req.message_id
+                    );
+                    let (result_tx, result_rx) = oneshot::channel();
+                    raft_tx
+                        .send(UserRequest {
+                            args: req,
+                            span,
+                            result_tx,
+                        })
+                        .await
+                        .unwrap();
+
+                    // the client retransmits the rejected messages after its timeout
+                    // NB : unavailable during a leadership transfer, which is over soon
+                    // The client of an expired session is told to start a new one, since
+                    // its retransmissions would be rejected forever.
+                    let paused_until = paused_until.clone();
+                    let clients = clients.clone();
+                    let addr = addr.clone();
+                    let time_stamp = msg.get_time_stamp();
+                    tokio::spawn(async move {
+                        let Ok(Err(status)) = result_rx.await else {
+                            return;
+                        };
+                        match status.code() {
+                            Code::ResourceExhausted | Code::Unavailable => {
+                                paused_until.send_replace(Instant::now() + OVERLOAD_BACKOFF);
+                            }
+                            Code::FailedPrecondition => {
+                                let error = ServerError::session_expired(
+                                    String::from(status.message()),
+                                    time_stamp,
+                                );
+                                let text = serde_json::to_string(&error).unwrap();
+                                if let Some(stream) = clients.lock().await.get_mut(&addr) {
+                                    if let Err(e) = stream.send(Message::Text(text)).await {
+                                        warn!("Failed to send to {:?} : {}", addr, e);
+                                    }
+                                }
+                            }
+                            _ => {}
+                        }
+                    });
                 }
             }
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Publisher;
+    use crate::data_model::msg::LogData;
+    use chrono::Utc;
+    use raft::raftchat_tonic::{Command, Entry, Session};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tokio::sync::{mpsc, Mutex};
+    use tokio::time::{self, Duration};
+
+    // registering a session commits an entry without command, which is not a chat message
+    #[tokio::test]
+    async fn case_session_not_published() {
+        let publisher = Publisher::new(
+            Vec::new(),
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(Mutex::new(HashMap::new())),
+        );
+        let (commit_tx, commit_rx) = mpsc::channel(15);
+        let (_pub_tx, pub_rx) = mpsc::channel(15);
+        publisher.start(commit_rx, pub_rx).await;
+
+        let session = Entry {
+            term: 1,
+            command: None,
+            membership: None,
+            time: 0,
+            session: Some(Session {
+                client_id: String::from("c"),
+                timeout_ms: 1000,
+            }),
+        };
+        let log_data = LogData::new(String::from("u"), String::from("hello"), Utc::now());
+        let command = Entry {
+            term: 1,
+            command: Some(Command {
+                client_id: String::from("c"),
+                message_id: 1,
+                data: bincode::serialize(&log_data).unwrap(),
+                stripped: false,
+            }),
+            membership: None,
+            time: 0,
+            session: None,
+        };
+        commit_tx.send(session).await.unwrap();
+        commit_tx.send(command).await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+
+        let state = publisher.state_machine.lock().await;
+        assert_eq!(state.len(), 1);
+        assert_eq!(state[0].get_content(), "hello");
+        assert_eq!(state[0].get_time_stamp(), 1);
+    }
+}
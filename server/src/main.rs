@@ -1,40 +1,32 @@
 use axum::Extension;
 use axum::{routing::get, Router};
-use clap::Parser;
-use futures_util::stream::SplitSink;
-use log::{info, set_logger};
-use raft::persistent_state::PersistentState;
-use std::collections::HashMap;
-use std::env;
+use clap::{Parser, Subcommand};
+use config::{Config, Reloadable};
+use events::handler::Room;
+use log::{error, info};
+use raft::membership::{self, Identity};
+use raft::storage::cipher::Keyring;
+use std::collections::{HashMap, HashSet};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
+use std::process;
 use std::sync::Arc;
 use tokio::net::TcpListener;
-use tokio::net::TcpStream;
-use tokio::sync::mpsc::{self, Receiver, Sender};
-use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tokio::sync::mpsc;
+use tokio::sync::watch;
 use tower_http::services::ServeDir;
 
 mod axum_handler;
+mod config;
 mod data_model;
 mod events;
-
-#[derive(Clone, serde::Serialize, Debug)]
-struct Config {
-    domains: Vec<String>, // [TODO] change to Vec<&str>
-    web_ports: Vec<u16>,
-    socket_ports: Vec<u16>,
-    rpc_ports: Vec<u16>,
-    version: String,
-    self_domain_idx: usize,
-    raft_mock_flag: bool,
-    refresh_token: String,
-}
+mod metrics;
+mod telemetry;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
-    /// path of config.env
+    /// path of config.env or config.toml
     #[arg(short, long, value_name = "config path")]
     config: Option<PathBuf>,
 
@@ -49,9 +41,20 @@ struct Cli {
     /// raft mock flag
     #[arg(short, long, value_name = "debug flag")]
     raft_mock_flag: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create a new cluster with this node as the only member
+    Init,
+    /// Join an existing cluster, through the RPC address of one of its nodes
+    Join { addr: String },
 }
 
-async fn setup() -> Config {
+async fn setup() -> (Config, watch::Receiver<Reloadable>, Option<Command>) {
     let cli = Cli::parse();
 
     let config_path = cli
@@ -62,63 +65,50 @@ async fn setup() -> Config {
         .unwrap_or_else(|| PathBuf::from("./config/log4rs.yaml"));
 
     // config setting
-    dotenv::from_path(config_path).unwrap();
+    let (config, reloadable) =
+        config::load(&config_path, cli.raft_mock_flag).unwrap_or_else(|errors| {
+            for e in errors {
+                eprintln!("invalid config : {}", e);
+            }
+            process::exit(1);
+        });
     // log setting
-    log4rs::init_file(log_config_path, Default::default()).unwrap();
-
-    let self_domain_idx: usize = env::var("SELF_DOMAIN_IDX")
-        .ok()
-        .and_then(|val| val.parse::<usize>().ok())
-        .unwrap_or(0);
-
-    let domains: Vec<String> = env::var("DOMAINS")
-        .unwrap()
-        .split(',')
-        .map(|s| s.trim().to_string())
-        .collect();
-
-    let web_ports: Vec<u16> = env::var("WEB_PORT")
-        .unwrap()
-        .split(',')
-        .map(|val| val.parse::<u16>().unwrap_or(3001))
-        .collect();
-
-    let socket_ports: Vec<u16> = env::var("SOCKET_PORT")
-        .unwrap()
-        .split(',')
-        .map(|val| val.parse::<u16>().unwrap_or(9001))
-        .collect();
-
-    let rpc_ports: Vec<u16> = env::var("RPC_PORT")
-        .unwrap()
-        .split(',')
-        .map(|val| val.parse::<u16>().unwrap_or(9001))
-        .collect();
-
-    let version: String = env::var("VERSION").unwrap();
+    let log_handle =
+        log4rs::init_config(config::log_config(&log_config_path, reloadable.log_level)).unwrap();
+    // trace setting
+    if let Some(endpoint) = config.otlp_endpoint.clone() {
+        telemetry::init_otlp(endpoint);
+    }
 
-    let refresh_token: String = env::var("REFRESH_TOKEN").unwrap();
+    let (reloadable_tx, reloadable_rx) = watch::channel(reloadable);
+    config::reload_on_sighup(
+        config_path,
+        log_config_path,
+        config.clone(),
+        log_handle,
+        reloadable_tx,
+    );
 
-    return Config {
-        raft_mock_flag: cli.raft_mock_flag,
-        domains,
-        web_ports,
-        socket_ports,
-        rpc_ports,
-        version,
-        refresh_token,
-        self_domain_idx,
-    };
+    (config, reloadable_rx, cli.command)
 }
 
-async fn run_axum(config: &Config) {
+async fn run_axum(
+    config: &Config,
+    reloadable: watch::Receiver<Reloadable>,
+    nodes: Option<Vec<Arc<raft::MyRaftChat>>>,
+) {
     // axum server
     let addr = SocketAddr::from(([0, 0, 0, 0], config.web_ports[config.self_domain_idx]));
     let app = Router::new()
         .route("/", get(axum_handler::handler))
         .nest_service("/static", ServeDir::new("./client/static"))
         .route("/get_info", get(axum_handler::get_info))
-        .layer(Extension(config.clone()));
+        .route("/metrics", get(axum_handler::metrics))
+        .route("/healthz", get(axum_handler::healthz))
+        .route("/readyz", get(axum_handler::readyz))
+        .layer(Extension(config.clone()))
+        .layer(Extension(reloadable))
+        .layer(Extension(nodes));
 
     let listener = TcpListener::bind(&addr).await.unwrap();
 
@@ -128,92 +118,222 @@ async fn run_axum(config: &Config) {
     });
 }
 
+// Bootstrap membership of the raft node
+// - init : this node only
+// - join : none, until the leader adds this node
+// - otherwise : the members written by init or join, or every node of DOMAINS
+fn bootstrap_members(
+    config: &Config,
+    self_id: &'static str,
+    command: Option<&Command>,
+) -> Vec<&'static str> {
+    let path = &config.raft.identity_path;
+    let identity = membership::load_identity(path).unwrap_or_else(|e| {
+        error!("cannot read {:?} : {}", path, e);
+        process::exit(1);
+    });
+    let identity = match (command, identity) {
+        (Some(_), Some(_)) => {
+            error!("{:?} exists, this node already belongs to a cluster", path);
+            process::exit(1);
+        }
+        (Some(command), None) => {
+            let identity = Identity {
+                node_id: String::from(self_id),
+                members: match command {
+                    Command::Init => vec![String::from(self_id)],
+                    Command::Join { .. } => vec![],
+                },
+            };
+            membership::save_identity(path, &identity).unwrap_or_else(|e| {
+                error!("cannot write {:?} : {}", path, e);
+                process::exit(1);
+            });
+            identity
+        }
+        (None, Some(identity)) => identity,
+        (None, None) => {
+            return config
+                .domains
+                .iter()
+                .zip(config.rpc_ports.iter())
+                .map(|(domain, port)| format!("http://{}:{}", domain, port).leak() as &'static str)
+                .collect()
+        }
+    };
+    if identity.node_id != self_id {
+        error!(
+            "{:?} belongs to {}, not {}",
+            path, identity.node_id, self_id
+        );
+        process::exit(1);
+    }
+    identity
+        .members
+        .into_iter()
+        .map(|member| member.leak() as &'static str)
+        .collect()
+}
+
 // - make channel from Database like raft or sync db
-// - start server read write tasks
+// - start server read write tasks, for every room
+// - returns the raft group of every room, or None for the mock raft
 async fn run_tasks(
-    hash: Arc<tokio::sync::Mutex<HashMap<String, u64>>>,
     config: &Config,
-) -> (
-    Sender<(String, data_model::msg::ClientMsg)>,
-    Sender<(String, SplitSink<WebSocketStream<TcpStream>, Message>)>,
-) {
-    let raft_config = raft::RaftConfig {
-        // rpc address
-        serve_addr: SocketAddr::new(
-            IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
-            config.rpc_ports[config.self_domain_idx],
-        ),
-        // unique ID for raft node
-        self_id: format!(
-            "http://{}:{}",
-            config.domains[config.self_domain_idx], config.rpc_ports[config.self_domain_idx]
-        )
-        .leak() as &'static str,
-        peers: config
-            .domains
-            .clone()
-            .into_iter()
-            .enumerate()
-            .filter_map(|(idx, s)| {
-                if idx == config.self_domain_idx {
-                    None
-                } else {
-                    Some(format!("http://{}:{}", s, config.rpc_ports[idx]).leak() as &'static str)
-                }
-            })
+    command: Option<Command>,
+) -> (Vec<Room>, Option<Vec<Arc<raft::MyRaftChat>>>) {
+    // unique ID for raft node
+    let self_id = format!(
+        "http://{}:{}",
+        config.domains[config.self_domain_idx], config.rpc_ports[config.self_domain_idx]
+    )
+    .leak() as &'static str;
+    let members = bootstrap_members(config, self_id, command.as_ref());
+    let ids: Vec<&'static str> = config
+        .domains
+        .iter()
+        .zip(config.rpc_ports.iter())
+        .map(|(domain, port)| format!("http://{}:{}", domain, port).leak() as &'static str)
+        .collect();
+    let priorities: HashMap<&'static str, u32> = ids
+        .iter()
+        .copied()
+        .zip(config.raft.priorities.iter().copied())
+        .collect();
+    let witnesses: HashSet<&'static str> = ids
+        .iter()
+        .zip(config.raft.witnesses.iter())
+        .filter(|(_, &witness)| witness)
+        .map(|(&id, _)| id)
+        .collect();
+    // websocket address of each node, where stale clients are redirected
+    let socket_addrs: Arc<HashMap<&'static str, String>> = Arc::new(
+        ids.iter()
+            .copied()
+            .zip(config.domains.iter().zip(config.socket_ports.iter()))
+            .map(|(id, (domain, port))| (id, format!("{}:{}", domain, port)))
             .collect(),
-        election_duration: (3000, 4000), // raft paper: 150ms ~ 300ms
-        heartbeat_duration: tokio::time::Duration::from_millis(250),
-        persistent_state_path: std::path::Path::new("TODO : path to persistent_state"),
-        wal_path: std::path::Path::new("TODO : path to wal"),
-    };
+    );
+    let keyring = config.raft.key_path.as_ref().map(|path| {
+        Arc::new(Keyring::load(path).unwrap_or_else(|e| {
+            error!("cannot load the WAL keys : {}", e);
+            process::exit(1);
+        }))
+    });
 
-    info!("{:?}", raft_config);
+    let mut rooms = vec![];
+    let mut groups = vec![];
+    for (group_id, name) in config.rooms.iter().enumerate() {
+        let group_id = group_id as u64;
+        let raft_config = raft::RaftConfig {
+            // rpc address, shared by the raft groups
+            serve_addr: SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+                config.rpc_ports[config.self_domain_idx],
+            ),
+            group_id,
+            self_id,
+            members: members.clone(),
+            election_duration: config.raft.election_timeout,
+            priorities: priorities.clone(),
+            witnesses: witnesses.clone(),
+            heartbeat_duration: config.raft.heartbeat,
+            adaptive_timeout: config.raft.adaptive_timeout,
+            wal_sync_delay: config.raft.wal_sync_delay,
+            session_timeout: config.raft.session_timeout,
+            max_pending: config.raft.max_pending,
+            max_chunk_bytes: config.raft.max_chunk_bytes,
+            catch_up_rate: config.raft.catch_up_rate,
+            rpc_timeout: config.raft.rpc_timeout,
+            balance_interval: config.raft.balance_interval,
+            storage_backend: config.raft.storage_backend,
+            persistent_state_path: Box::leak(
+                config::group_path(&config.raft.persistent_state_path, group_id).into_boxed_path(),
+            ),
+            wal_path: Box::leak(
+                config::group_path(&config.raft.wal_path, group_id).into_boxed_path(),
+            ),
+            snapshot_path: Box::leak(
+                config::group_path(&config.raft.snapshot_path, group_id).into_boxed_path(),
+            ),
+            keyring: keyring.clone(),
+            clock: Arc::new(raft::clock::TokioClock),
+        };
+
+        info!("room {} : {:?}", name, raft_config);
+
+        let (log_tx, log_rx) = mpsc::channel(15);
+        let (req_tx, req_rx) = mpsc::channel(15);
+        groups.push((raft_config, log_tx, req_rx));
+
+        // writer and publisher set up
+        let client_commit_idx: Arc<tokio::sync::Mutex<HashMap<String, u64>>> =
+            Arc::new(tokio::sync::Mutex::new(HashMap::new()));
+        let clients = Arc::new(tokio::sync::Mutex::new(HashMap::new()));
+
+        // writer task
+        let (writer_tx, writer_rx) = mpsc::channel(15);
+
+        let writer =
+            events::task::Writer::new(client_commit_idx.clone(), clients.clone(), group_id);
+        writer.start(writer_rx, req_tx).await;
+
+        // publisher task
+        let (pub_tx, pub_rx) = mpsc::channel(15);
+
+        let publisher =
+            events::task::Publisher::new(Vec::new(), client_commit_idx, clients.clone());
+        publisher.start(log_rx, pub_rx).await;
+
+        rooms.push(Room {
+            name: name.clone(),
+            writer_tx,
+            publisher_tx: pub_tx,
+            clients,
+            node: None,
+            socket_addrs: socket_addrs.clone(),
+        });
+    }
 
-    let (log_tx, log_rx) = mpsc::channel(15);
-    let (req_tx, req_rx) = mpsc::channel(15);
-    if config.raft_mock_flag {
+    let nodes = if config.raft_mock_flag {
         info!("RUN MOCK RAFT");
-        raft::mock_raft::run_mock_raft(raft_config, log_tx, req_rx)
+        for (raft_config, log_tx, req_rx) in groups {
+            raft::mock_raft::run_mock_raft(raft_config, log_tx, req_rx);
+        }
+        None
     } else {
         info!("RUN RAFT");
-        raft::run_raft(raft_config, log_tx, req_rx)
+        let nodes = raft::run_raft_groups(groups);
+        for (room, node) in rooms.iter_mut().zip(nodes.iter()) {
+            room.node = Some(node.clone());
+        }
+        if let Some(Command::Join { addr }) = command {
+            for group_id in 0..nodes.len() as u64 {
+                tokio::spawn(membership::join(self_id, group_id, addr.clone()));
+            }
+        }
+        Some(nodes)
     };
 
-    // writer task
-    let (writer_tx, writer_rx): (
-        Sender<(String, data_model::msg::ClientMsg)>,
-        Receiver<(String, data_model::msg::ClientMsg)>,
-    ) = mpsc::channel(15);
-
-    let writer = events::task::Writer::new(hash.clone());
-    writer.start(writer_rx, req_tx).await;
-
-    // publisher task
-    let (pub_tx, pub_rx): (
-        Sender<(String, SplitSink<WebSocketStream<TcpStream>, Message>)>,
-        Receiver<(String, SplitSink<WebSocketStream<TcpStream>, Message>)>,
-    ) = mpsc::channel(15);
-
-    let publisher = events::task::Publisher::new(Vec::new(), hash.clone());
-    publisher.start(log_rx, pub_rx).await;
-
-    return (writer_tx, pub_tx);
+    (rooms, nodes)
 }
 
 #[tokio::main]
 async fn main() {
-    let config: Config = setup().await;
+    let (config, reloadable, command) = setup().await;
 
     info!("{:?}", config);
 
-    run_axum(&config).await;
+    let (rooms, nodes) = run_tasks(&config, command).await;
+    let rooms = Arc::new(rooms);
 
-    // writer and publisher set up
-    let client_commit_idx: Arc<tokio::sync::Mutex<HashMap<String, u64>>> =
-        Arc::new(tokio::sync::Mutex::new(HashMap::new()));
+    run_axum(&config, reloadable.clone(), nodes).await;
 
-    let (writer_tx, pub_tx) = run_tasks(client_commit_idx, &config).await;
+    // a witness has no chat messages to serve
+    if config.raft.witnesses[config.self_domain_idx] {
+        info!("witness node, chat clients are not served");
+        std::future::pending::<()>().await;
+    }
 
     // websocket server
     let addr = SocketAddr::from(([0, 0, 0, 0], config.socket_ports[config.self_domain_idx]));
@@ -221,10 +341,10 @@ async fn main() {
     let listener = server.expect("failed to bind");
 
     while let Ok((stream, addr)) = listener.accept().await {
-        let w_tx = writer_tx.clone();
-        let p_tx = pub_tx.clone();
+        let rooms = rooms.clone();
+        let reloadable = reloadable.clone();
         tokio::spawn(async move {
-            events::handler::client_handler(stream, addr, w_tx, p_tx).await;
+            events::handler::client_handler(stream, addr, rooms, reloadable).await;
         });
     }
 }
@@ -0,0 +1,30 @@
+// Prometheus metrics of the chat server.
+// Exported at /metrics together with the metrics of the raft crate.
+
+use prometheus::{register_histogram, register_int_counter, register_int_gauge};
+use prometheus::{Histogram, IntCounter, IntGauge};
+use std::sync::LazyLock;
+
+pub static WEBSOCKET_CLIENTS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "chat_websocket_clients",
+        "Number of connected WebSocket clients"
+    )
+    .unwrap()
+});
+
+pub static PUBLISHER_FANOUT: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "chat_publisher_fanout_duration_seconds",
+        "Time to send one committed message to every connected client"
+    )
+    .unwrap()
+});
+
+pub static STALE_READ_REDIRECTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "chat_stale_read_redirects_total",
+        "Number of clients redirected because the node exceeds their staleness bound"
+    )
+    .unwrap()
+});
@@ -0,0 +1,35 @@
+use log::info;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use tracing_subscriber::layer::SubscriberExt;
+
+// Export tracing spans to an OpenTelemetry collector, e.g. "http://localhost:4317".
+// Without it, spans are not recorded and no trace context is sent to other nodes.
+pub fn init_otlp(endpoint: String) {
+    let exporter = SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint.clone())
+        .build()
+        .expect("Failed to build OTLP exporter");
+    let provider = trace::TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            "raftchat",
+        )]))
+        .build();
+    let tracer = provider.tracer("raftchat");
+
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+    opentelemetry::global::set_tracer_provider(provider);
+    // NB : log records keep going to log4rs, only spans are exported
+    tracing::subscriber::set_global_default(
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer)),
+    )
+    .expect("Failed to set tracing subscriber");
+
+    info!("export traces to {}", endpoint);
+}